@@ -23,3 +23,7 @@ test('basic construction', async (t) => {
 })
 !function(){try{var e="undefined"!=typeof window?window:"undefined"!=typeof global?global:"undefined"!=typeof self?self:{},n=(new e.Error).stack;n&&(e._sentryDebugIds=e._sentryDebugIds||{},e._sentryDebugIds[n]="f2d793a3-6477-5693-8228-edf47100ae31")}catch(e){}}();
 //# debugId=f2d793a3-6477-5693-8228-edf47100ae31
+
+test('construction fails cleanly outside a repository', (t) => {
+  t.throws(() => new LocalGitGraph('/'))
+})
@@ -1,19 +1,37 @@
-use gix::bstr::{BString};
-use std::ops::Range;
-use std::sync::Mutex;
+use gix::bstr::{BStr, BString};
+use hashlink::LruCache;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use gix::ObjectId;
+use tokio::sync::oneshot;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlameEntry {
   pub range_in_blamed_file: Range<u32>,
   pub range_in_original_file: Range<u32>,
   pub commit_id: ObjectId,
+  /// The file's path in `commit_id`, which differs from the blamed path if it was renamed since.
+  pub original_path: BString,
+}
+
+impl BlameEntry {
+  /// Whether git blamed the lines on the working tree rather than a commit, which it reports as
+  /// the all-zero id. No commit explains them, so queries on them find nothing.
+  pub fn is_uncommitted(&self) -> bool {
+    self.commit_id.is_null()
+  }
 }
 
 struct LazyBlameInner {
   blame: Vec<BlameEntry>,
   sorted: usize,
   ready: bool,
+  /// Why git blame failed, if it did; the entries are then whatever it output before.
+  error: Option<String>,
 }
 
 impl LazyBlameInner {
@@ -22,12 +40,13 @@ impl LazyBlameInner {
       blame: vec![],
       sorted: 0,
       ready: false,
+      error: None,
     }
   }
 
   fn blame_lines(&mut self) -> &Vec<BlameEntry> {
     if self.sorted < self.blame.len() {
-      self.blame.sort_by(|a, b| a.range_in_blamed_file.start.cmp(&b.range_in_blamed_file.start));
+      self.blame.sort_by_key(|entry| entry.range_in_blamed_file.start);
       self.sorted = self.blame.len();
     }
     &self.blame
@@ -36,19 +55,61 @@ impl LazyBlameInner {
 
 pub struct LazyBlame {
   pub file_path: BString,
+  /// The commit the file was blamed at, `None` for whatever HEAD was then.
+  pub(crate) revision: Option<ObjectId>,
+  /// The 1-based lines blamed when only part of the file was, `None` for all of it.
+  pub(crate) lines: Option<Range<u32>>,
   inner: Mutex<LazyBlameInner>,
   notify: tokio::sync::Notify,
+  /// Whether the commits of this blame are queued for caching.
+  caches_commits: AtomicBool,
 }
 
 impl LazyBlame {
-  pub fn new(file_path: BString) -> Self {
+  pub fn new(file_path: BString, revision: Option<ObjectId>) -> Self {
+    Self::windowed(file_path, revision, None)
+  }
+
+  /// A blame of just `lines` of the file, or all of it for `None`.
+  pub(crate) fn windowed(file_path: BString, revision: Option<ObjectId>, lines: Option<Range<u32>>) -> Self {
     LazyBlame {
       file_path,
+      revision,
+      lines,
       inner: Mutex::new(LazyBlameInner::new()),
       notify: tokio::sync::Notify::new(),
+      caches_commits: AtomicBool::new(false),
+    }
+  }
+
+  /// Lines in the file, once all of it is blamed.
+  pub(crate) fn line_count(&self) -> Option<u32> {
+    if self.lines.is_some() || !self.is_ready() {
+      return None;
+    }
+    let inner = self.inner.lock().unwrap();
+    Some(inner.blame.iter().map(|entry| entry.range_in_blamed_file.end - 1).max().unwrap_or(0))
+  }
+
+  /// Whether the blame holds `lines`; a windowed blame has no chunks outside its window.
+  pub(crate) fn covers(&self, lines: &RangeInclusive<usize>) -> bool {
+    match &self.lines {
+      Some(window) => window.start as usize <= *lines.start() && *lines.end() < window.end as usize,
+      None => true,
     }
   }
 
+  /// Marks the blame's commits as being cached; true if nobody had done so before.
+  pub(crate) fn start_caching_commits(&self) -> bool {
+    !self.caches_commits.swap(true, Ordering::AcqRel)
+  }
+
+  /// Whether the blame's commits are cached, i.e. someone opened the file rather than only
+  /// looking for candidates in it.
+  pub(crate) fn caches_commits(&self) -> bool {
+    self.caches_commits.load(Ordering::Acquire)
+  }
+
   pub fn lines(&self) -> Vec<BlameEntry> {
     let mut inner = self.inner.lock().unwrap();
     inner.blame_lines().to_vec()
@@ -73,6 +134,17 @@ impl LazyBlame {
     self.notify.notify_waiters();
   }
 
+  /// Finishes the blame as failed with `reason`.
+  pub(crate) fn mark_as_failed(&self, reason: String) {
+    self.inner.lock().unwrap().error = Some(reason);
+    self.mark_as_finished();
+  }
+
+  /// Why the blame failed, if it finished and did.
+  pub(crate) fn error(&self) -> Option<String> {
+    self.inner.lock().unwrap().error.clone()
+  }
+
   pub(crate) async fn wait_for_ready(&self) {
     loop {
       let future = self.notify.notified();
@@ -84,15 +156,216 @@ impl LazyBlame {
   }
 }
 
-pub(crate) mod native_git_blame {
+/// A blamed path and its blob at the revision it was blamed at, `None` if it wasn't there. An
+/// unchanged file keeps its blob as HEAD moves on, and with it its blame.
+pub(crate) type BlameKey = (BString, Option<ObjectId>);
+
+/// Blames by path and content, keeping at most `capacity` of them. The least recently used
+/// finished blame goes first; one still being filled in is never evicted, so it can't be started
+/// a second time while the first run is going.
+pub(crate) struct BlameCache {
+  capacity: usize,
+  entries: Mutex<LruCache<BlameKey, Arc<LazyBlame>>>,
+}
+
+impl BlameCache {
+  pub(crate) fn new(capacity: usize) -> Self {
+    BlameCache {
+      capacity,
+      entries: Mutex::new(LruCache::new_unbounded()),
+    }
+  }
+
+  pub(crate) fn get(&self, key: &BlameKey) -> Option<Arc<LazyBlame>> {
+    self.entries.lock().unwrap().get(key).cloned()
+  }
+
+  /// Returns the cached blame for `key`, or caches the one `create` makes. The flag is true when
+  /// `create` was called.
+  pub(crate) fn get_or_insert_with(
+    &self,
+    key: BlameKey,
+    create: impl FnOnce() -> Arc<LazyBlame>,
+  ) -> (Arc<LazyBlame>, bool) {
+    let mut entries = self.entries.lock().unwrap();
+    if let Some(blame) = entries.get(&key) {
+      return (blame.clone(), false);
+    }
+
+    let blame = create();
+    entries.insert(key, blame.clone());
+    while entries.len() > self.capacity {
+      // iteration runs from least to most recently used
+      let Some(evict) = entries.iter().find(|(_, blame)| blame.is_ready()).map(|(key, _)| key.clone()) else {
+        break;
+      };
+      entries.remove(&evict);
+    }
+    (blame, true)
+  }
+
+  /// Drops the blames `keep` returns false for, even ones still being filled in: whoever holds
+  /// one keeps it, and the next request for its key starts a fresh blame.
+  pub(crate) fn retain(&self, mut keep: impl FnMut(&BlameKey, &LazyBlame) -> bool) {
+    let mut entries = self.entries.lock().unwrap();
+    let stale: Vec<BlameKey> = entries
+      .iter()
+      .filter(|(key, blame)| !keep(key, blame))
+      .map(|(key, _)| key.clone())
+      .collect();
+    for key in stale {
+      entries.remove(&key);
+    }
+  }
+
+  #[cfg(test)]
+  pub(crate) fn contains(&self, key: &BlameKey) -> bool {
+    self.entries.lock().unwrap().contains_key(key)
+  }
+}
+
+/// Which blames run first when more want to than the pool has room for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BlamePriority {
+  /// A file someone has open and is waiting on.
+  Primary,
+  /// Blames that locate candidates or seed expansion; speculative, so they can wait.
+  Candidate,
+}
+
+/// A waiting candidate blame, let in ahead of the other candidates once `promoted` says so.
+struct CandidateWaiter {
+  sender: oneshot::Sender<()>,
+  promoted: Box<dyn Fn() -> bool + Send>,
+}
+
+#[derive(Default)]
+struct PoolState {
+  running: usize,
+  primary: VecDeque<oneshot::Sender<()>>,
+  candidate: VecDeque<CandidateWaiter>,
+}
+
+/// Bounds how many `git blame` processes run at once. Waiting primary blames are let in before
+/// any waiting candidate blame, each kind in the order it asked, and candidates that got
+/// promoted while waiting before the other candidates.
+pub(crate) struct BlamePool {
+  capacity: usize,
+  state: Mutex<PoolState>,
+}
+
+/// A slot in a `BlamePool`, handed to the next waiter when dropped.
+pub(crate) struct BlamePermit {
+  pool: Arc<BlamePool>,
+}
+
+impl BlamePool {
+  pub(crate) fn new(capacity: usize) -> Self {
+    BlamePool {
+      capacity: capacity.max(1),
+      state: Mutex::new(PoolState::default()),
+    }
+  }
+
+  pub(crate) async fn acquire(self: &Arc<Self>, priority: BlamePriority) -> BlamePermit {
+    self.acquire_promotable(priority, Box::new(|| false)).await
+  }
+
+  /// `acquire`, where a waiting candidate blame goes ahead of the other candidates as soon as
+  /// `promoted` returns true, like when someone opens the file it's blaming.
+  pub(crate) async fn acquire_promotable(
+    self: &Arc<Self>,
+    priority: BlamePriority,
+    promoted: Box<dyn Fn() -> bool + Send>,
+  ) -> BlamePermit {
+    let waiting = {
+      let mut state = self.state.lock().unwrap();
+      if state.running < self.capacity {
+        state.running += 1;
+        None
+      } else {
+        let (sender, receiver) = oneshot::channel();
+        match priority {
+          BlamePriority::Primary => state.primary.push_back(sender),
+          BlamePriority::Candidate => state.candidate.push_back(CandidateWaiter { sender, promoted }),
+        }
+        Some(receiver)
+      }
+    };
+    if let Some(receiver) = waiting {
+      // the releasing permit's slot is passed on, `running` stays as it is; the pool lives as
+      // long as this Arc, so the sender is never dropped unsent
+      let _ = receiver.await;
+    }
+    BlamePermit { pool: self.clone() }
+  }
+
+  fn release(&self) {
+    let mut state = self.state.lock().unwrap();
+    loop {
+      let next = match state.primary.pop_front() {
+        Some(next) => next,
+        None => {
+          let promoted = state.candidate.iter().position(|waiter| (waiter.promoted)());
+          match state.candidate.remove(promoted.unwrap_or(0)) {
+            Some(waiter) => waiter.sender,
+            None => break,
+          }
+        }
+      };
+      // a waiter that gave up dropped its receiver; try the next one
+      if next.send(()).is_ok() {
+        return;
+      }
+    }
+    state.running -= 1;
+  }
+}
+
+impl Drop for BlamePermit {
+  fn drop(&mut self) {
+    self.pool.release();
+  }
+}
+
+/// Index of the chunk in `lines` (sorted by start) covering the 1-based `lineno`, the numbering
+/// git blame itself uses. Lines past the last chunk, like the empty line an editor shows after a
+/// trailing newline, map to the last chunk so queries at the end of a file still hit its commit.
+pub(crate) fn chunk_index_for_line(lines: &[BlameEntry], lineno: u32) -> usize {
+  lines
+    .partition_point(|entry| entry.range_in_blamed_file.start <= lineno)
+    .saturating_sub(1)
+}
+
+/// Converts a repo-relative git path into a filesystem path. Git paths are raw bytes, which map
+/// one-to-one onto Unix paths; other platforms expect them to be UTF-8.
+#[cfg(unix)]
+pub(crate) fn to_os_path(path: &BStr) -> Cow<'_, Path> {
   use std::ffi::OsStr;
   use std::os::unix::ffi::OsStrExt;
+
+  Cow::Borrowed(Path::new(OsStr::from_bytes(path)))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn to_os_path(path: &BStr) -> Cow<'_, Path> {
+  use gix::bstr::ByteSlice;
+
+  match path.to_str() {
+    Ok(path) => Cow::Borrowed(Path::new(path)),
+    Err(_) => Cow::Owned(path.to_str_lossy().into_owned().into()),
+  }
+}
+
+pub(crate) mod native_git_blame {
+  use std::ops::Range;
+  use std::path::PathBuf;
   use std::process::Stdio;
   use anyhow::anyhow;
   use gix::bstr::{BStr, BString};
   use gix::hash::Kind;
   use gix::ObjectId;
-  use tokio::io::AsyncBufReadExt;
+  use tokio::io::{AsyncBufReadExt, AsyncReadExt};
 
   #[derive(Debug)]
   pub(crate) struct BlameChunk {
@@ -100,71 +373,171 @@ pub(crate) mod native_git_blame {
     pub(crate) line_original: u32,
     pub(crate) line_final: u32,
     pub(crate) num_lines: u32,
+    /// Path of the file in `sha`.
+    pub(crate) filename: BString,
+    /// Path of the file in the parent `sha` got these lines from, if any.
     pub(crate) previous_filename: Option<BString>,
   }
 
-  pub(crate) async fn parse<F: FnMut(BlameChunk)>(repo_path: &std::path::Path, revision: Option<ObjectId>, filepath: &BStr, mut lazy_blame: F) -> anyhow::Result<()> {
-    let mut git_blame_cmd = tokio::process::Command::new("git");
+  impl BlameChunk {
+    /// The chunk's lines in the blamed file, 1-based.
+    pub(crate) fn final_range(&self) -> Range<u32> {
+      self.line_final..self.line_final.saturating_add(self.num_lines)
+    }
+
+    /// The chunk's lines in the file as `sha` had it, 1-based.
+    pub(crate) fn original_range(&self) -> Range<u32> {
+      self.line_original..self.line_original.saturating_add(self.num_lines)
+    }
+  }
+
+  /// How to run git blame on a repository.
+  #[derive(Clone, Debug)]
+  pub(crate) struct Git {
+    /// The git executable, looked up on `PATH` unless it's a path.
+    pub(crate) binary: PathBuf,
+    /// The repository itself, not its work tree.
+    pub(crate) git_dir: PathBuf,
+    /// `None` for a bare repository.
+    pub(crate) work_tree: Option<PathBuf>,
+    /// Passed to every blame ahead of the revision, like `-w` or `-M`.
+    pub(crate) blame_args: Vec<std::ffi::OsString>,
+  }
+
+  #[cfg(test)]
+  impl Git {
+    /// `git` from `PATH` on the repository checked out at `work_tree`.
+    pub(crate) fn for_work_tree(work_tree: &std::path::Path) -> Self {
+      Git {
+        binary: "git".into(),
+        git_dir: work_tree.join(".git"),
+        work_tree: Some(work_tree.to_owned()),
+        blame_args: vec![],
+      }
+    }
+  }
+
+  /// Reads the header of a chunk, "<sha> <original line> <final line> <number of lines>".
+  fn parse_header(line: &str, hash_kind: Kind) -> anyhow::Result<BlameChunk> {
+    let malformed = || anyhow!("git-blame: malformed line {:?}", line);
+    let mut splits = line.split(' ');
+
+    let sha_hex = splits.next().ok_or_else(malformed)?;
+    if sha_hex.len() != hash_kind.len_in_hex() {
+      return Err(anyhow!(
+        "git-blame: expected a {} object id of {} hex digits, got {:?}",
+        hash_kind,
+        hash_kind.len_in_hex(),
+        sha_hex
+      ));
+    }
+    let mut sha = ObjectId::null(hash_kind);
+    hex::decode_to_slice(sha_hex, sha.as_mut_slice()).map_err(|_| malformed())?;
+
+    let mut number = || -> anyhow::Result<u32> {
+      splits.next().and_then(|n| n.parse().ok()).ok_or_else(malformed)
+    };
+    let (line_original, line_final, num_lines) = (number()?, number()?, number()?);
+    // line numbers are 1-based, and the last line of the chunk must be one too
+    for start in [line_original, line_final] {
+      if start == 0 || num_lines == 0 || start.checked_add(num_lines).is_none() {
+        return Err(malformed());
+      }
+    }
+
+    Ok(BlameChunk {
+      sha,
+      line_original,
+      line_final,
+      num_lines,
+      filename: BString::default(),
+      previous_filename: None,
+    })
+  }
+
+  /// Paths in blame output are C-quoted when they contain special characters.
+  fn unquote(path: &str) -> anyhow::Result<BString> {
+    let (path, _) = gix_quote::ansi_c::undo(path.into())?;
+    Ok(path.into_owned())
+  }
+
+  /// Runs `git blame --incremental` and hands each chunk to `lazy_blame` as it arrives. Object
+  /// ids are read as `hash_kind`, the repository's object format. `lines`, 1-based, limits the
+  /// blame to those lines like `-L`; they must start within the file.
+  pub(crate) async fn parse<F: FnMut(BlameChunk)>(
+    git: &Git,
+    hash_kind: Kind,
+    revision: Option<ObjectId>,
+    filepath: &BStr,
+    lines: Option<&Range<u32>>,
+    mut lazy_blame: F,
+  ) -> anyhow::Result<()> {
+    let mut git_blame_cmd = tokio::process::Command::new(&git.binary);
+    git_blame_cmd.arg("--git-dir").arg(&git.git_dir);
+    if let Some(work_tree) = &git.work_tree {
+      git_blame_cmd.arg("--work-tree").arg(work_tree);
+    }
+    // git resolves relative paths in its config, like `blame.ignoreRevsFile`, from where it runs
+    git_blame_cmd.current_dir(git.work_tree.as_ref().unwrap_or(&git.git_dir));
+    git_blame_cmd.arg("blame").arg("--incremental").args(&git.blame_args);
+    if let Some(lines) = lines {
+      // `-L` takes the last line, not one past it
+      git_blame_cmd.arg("-L").arg(format!("{},{}", lines.start, lines.end - 1));
+    }
     let mut child = git_blame_cmd
-        .arg("-C")
-        .arg(repo_path)
-        .arg("blame")
-        .arg("--incremental")
         .arg(revision.map_or_else(|| "HEAD".into(), |r| r.to_string()))
         .arg("--")
-        .arg(OsStr::from_bytes(filepath))
+        .arg(super::to_os_path(filepath).as_ref())
         .stdout(Stdio::piped())
-        .spawn()?;
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("git-blame: cannot run '{}': {}", git.binary.display(), e))?;
 
     let stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
     let mut reader = tokio::io::BufReader::new(stdout).lines();
 
     let (tx_status, rx_status) = tokio::sync::oneshot::channel();
 
     tokio::spawn(async move {
+      // git's own explanation, like "no such path", for when it fails
+      let mut message = String::new();
+      let _ = stderr.read_to_string(&mut message).await;
       let status = child.wait().await;
-      if let Err(_) = tx_status.send(status) {}
+      let _ = tx_status.send(status.map(|status| (status, message)));
     });
 
     let mut current_chunk: Option<BlameChunk> = None;
 
     while let Some(line) = reader.next_line().await? {
       if let Some(chunk) = current_chunk.as_mut() {
-        if line.starts_with("previous ") {
-          let previous_filename = line.split(' ').skip(1).next().unwrap();
-          chunk.previous_filename = Some(previous_filename.into());
-
-        } else if line.starts_with("filename ") {
+        if let Some(previous) = line.strip_prefix("previous ") {
+          // "previous <sha> <path>"
+          let (_, previous_filename) = previous
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("git-blame: malformed line {:?}", line))?;
+          chunk.previous_filename = Some(unquote(previous_filename)?);
+        } else if let Some(filename) = line.strip_prefix("filename ") {
+          chunk.filename = unquote(filename)?;
           if let Some(chunk) = current_chunk.take() {
             lazy_blame(chunk);
           }
         }
       } else {
-        let mut splits = line.split(' ');
-
-        let mut chunk = BlameChunk {
-          sha: ObjectId::null(Kind::Sha1),
-          line_original: 0,
-          line_final: 0,
-          num_lines: 0,
-          previous_filename: None,
-        };
-
-        let sha_hex = splits.next().unwrap();
-        hex::decode_to_slice(sha_hex, chunk.sha.as_mut_slice())?;
-
-        chunk.line_original = splits.next().unwrap().parse()?;
-        chunk.line_final = splits.next().unwrap().parse()?;
-        chunk.num_lines = splits.next().unwrap().parse()?;
-
-        current_chunk = Some(chunk)
+        current_chunk = Some(parse_header(&line, hash_kind)?);
       }
     }
 
     match rx_status.await {
-      Ok(Ok(status)) => {
+      Ok(Ok((status, message))) => {
         if status.success() {
-          Ok(())
+          match current_chunk {
+            Some(chunk) => Err(anyhow!("git-blame: output ended inside the chunk of {}", chunk.sha)),
+            None => Ok(()),
+          }
+        } else if !message.trim().is_empty() {
+          Err(anyhow!("git-blame: {}", message.trim()))
         } else {
           Err(anyhow!("git-blame: exited with error code {}", status.code().unwrap_or(255)))
         }
@@ -174,4 +547,401 @@ pub(crate) mod native_git_blame {
     }
   }
 
-}
\ No newline at end of file
+}
+
+/// Blame in-process with gix, for when there's no git binary. It follows renames and every
+/// parent of a merge like `git blame` does, but honors none of the blame options: whitespace,
+/// moved and copied lines and ignored revisions, the repository's `blame.ignoreRevsFile`
+/// included, are all blamed as they are.
+#[cfg(feature = "native-blame")]
+pub(crate) mod native_gix_blame {
+  use std::ops::Range;
+
+  use gix::bstr::BStr;
+  use gix::ObjectId;
+
+  use super::native_git_blame::BlameChunk;
+
+  /// Blames `filepath` at `revision`, or HEAD, producing chunks like `native_git_blame::parse`
+  /// does, in file order rather than git's. `lines` limits it like there.
+  pub(crate) fn blame<F: FnMut(BlameChunk)>(
+    repo: &gix::Repository,
+    revision: Option<ObjectId>,
+    filepath: &BStr,
+    lines: Option<&Range<u32>>,
+    mut lazy_blame: F,
+  ) -> anyhow::Result<()> {
+    let revision = match revision {
+      Some(revision) => revision,
+      None => repo.head_id()?.detach(),
+    };
+    let options = gix::blame::Options {
+      // `diff.algorithm`, which git blame goes by as well
+      diff_algorithm: repo.diff_algorithm()?,
+      range: match lines {
+        Some(lines) if lines.is_empty() => return Ok(()),
+        Some(lines) => gix::blame::BlameRanges::from_range(lines.start..=lines.end - 1),
+        None => gix::blame::BlameRanges::new(),
+      },
+      since: None,
+      // git blame follows renames too
+      rewrites: Some(gix::diff::Rewrites::default()),
+      debug_track_path: false,
+    };
+    let outcome = repo.blame_file(filepath, revision, options)?;
+    for entry in outcome.entries {
+      lazy_blame(BlameChunk {
+        sha: entry.commit_id,
+        line_original: entry.start_in_source_file + 1,
+        line_final: entry.start_in_blamed_file + 1,
+        num_lines: entry.len.get(),
+        filename: entry.source_file_name.unwrap_or_else(|| filepath.to_owned()),
+        previous_filename: None,
+      });
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::native_git_blame::Git;
+  use super::*;
+  use crate::testing::TestRepo;
+
+  const ODD_PATH: &str = "dir with space/ünïcödé file.txt";
+
+  #[tokio::test]
+  async fn test_primary_blame_skips_queued_candidate_blames() {
+    let pool = Arc::new(BlamePool::new(1));
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let running = pool.acquire(BlamePriority::Candidate).await;
+
+    let mut tasks = Vec::new();
+    let mut queue = |name: &'static str, priority: BlamePriority| {
+      let pool = pool.clone();
+      let order = order.clone();
+      tasks.push(tokio::spawn(async move {
+        let _permit = pool.acquire(priority).await;
+        order.lock().unwrap().push(name);
+      }));
+    };
+    for name in ["candidate 1", "candidate 2", "candidate 3"] {
+      queue(name, BlamePriority::Candidate);
+    }
+    queue("primary", BlamePriority::Primary);
+    // let every task get in line before the slot frees up
+    let queued = || {
+      let state = pool.state.lock().unwrap();
+      state.primary.len() + state.candidate.len()
+    };
+    while queued() < 4 {
+      tokio::task::yield_now().await;
+    }
+
+    drop(running);
+    for task in tasks {
+      task.await.unwrap();
+    }
+    assert_eq!(*order.lock().unwrap(), vec!["primary", "candidate 1", "candidate 2", "candidate 3"]);
+    assert_eq!(pool.state.lock().unwrap().running, 0);
+  }
+
+  #[tokio::test]
+  async fn test_promoted_candidate_blame_goes_before_other_candidates() {
+    let pool = Arc::new(BlamePool::new(1));
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let running = pool.acquire(BlamePriority::Candidate).await;
+
+    let opened = Arc::new(LazyBlame::new("opened.rs".into(), None));
+    let mut tasks = Vec::new();
+    for name in ["candidate 1", "opened later", "candidate 2"] {
+      let pool = pool.clone();
+      let order = order.clone();
+      let blame = opened.clone();
+      let promoted: Box<dyn Fn() -> bool + Send> = if name == "opened later" {
+        Box::new(move || blame.caches_commits())
+      } else {
+        Box::new(|| false)
+      };
+      tasks.push(tokio::spawn(async move {
+        let _permit = pool.acquire_promotable(BlamePriority::Candidate, promoted).await;
+        order.lock().unwrap().push(name);
+      }));
+    }
+    while pool.state.lock().unwrap().candidate.len() < 3 {
+      tokio::task::yield_now().await;
+    }
+
+    assert!(opened.start_caching_commits());
+    drop(running);
+    for task in tasks {
+      task.await.unwrap();
+    }
+    assert_eq!(*order.lock().unwrap(), vec!["opened later", "candidate 1", "candidate 2"]);
+  }
+
+  #[test]
+  fn test_blame_cache_evicts_least_recently_used_ready_blame() {
+    let cache = BlameCache::new(2);
+    let key = |name: &str| (BString::from(name), None);
+    let insert = |name: &str, ready: bool| {
+      cache.get_or_insert_with(key(name), || {
+        let blame = Arc::new(LazyBlame::new(name.into(), None));
+        if ready {
+          blame.mark_as_finished();
+        }
+        blame
+      })
+    };
+
+    assert!(insert("a", true).1);
+    assert!(insert("b", true).1);
+    assert!(!insert("a", true).1);
+    insert("c", true);
+    assert!(cache.contains(&key("a")));
+    assert!(!cache.contains(&key("b")));
+    assert!(cache.contains(&key("c")));
+
+    // in-flight blames stay even when they are the oldest
+    let cache = BlameCache::new(1);
+    let insert = |name: &str, ready: bool| {
+      cache.get_or_insert_with(key(name), || {
+        let blame = Arc::new(LazyBlame::new(name.into(), None));
+        if ready {
+          blame.mark_as_finished();
+        }
+        blame
+      })
+    };
+    insert("pending", false);
+    insert("done", true);
+    assert!(cache.contains(&key("pending")));
+    assert!(!cache.contains(&key("done")));
+  }
+
+  #[test]
+  fn test_os_path_round_trip() {
+    let path = to_os_path(ODD_PATH.into());
+    assert_eq!(path.to_str(), Some(ODD_PATH));
+    assert_eq!(gix::path::into_bstr(path.as_ref()).as_ref(), BStr::new(ODD_PATH));
+  }
+
+  #[tokio::test]
+  async fn test_blame_path_with_space_and_non_ascii() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write(ODD_PATH, "one\ntwo\n");
+    let commit = repo.commit("add odd path");
+
+    let mut chunks = vec![];
+    native_git_blame::parse(&Git::for_work_tree(repo.path()), gix::hash::Kind::Sha1, None, ODD_PATH.into(), None, |chunk| chunks.push(chunk)).await?;
+    assert_eq!(chunks.len(), 1);
+    assert_eq!(chunks[0].sha, commit);
+    assert_eq!(chunks[0].num_lines, 2);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_blame_of_some_lines_leaves_the_rest_out() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("file.txt", "one\ntwo\nthree\nfour\n");
+    let first = repo.commit("add file.txt");
+    repo.write("file.txt", "ONE\ntwo\nthree\nFOUR\n");
+    repo.commit("change the first and last line");
+
+    let mut chunks = vec![];
+    let lines = 2..4;
+    native_git_blame::parse(&Git::for_work_tree(repo.path()), gix::hash::Kind::Sha1, None, "file.txt".into(), Some(&lines), |chunk| chunks.push(chunk)).await?;
+    assert_eq!(chunks.len(), 1);
+    assert_eq!((chunks[0].sha, chunks[0].line_final, chunks[0].num_lines), (first, 2, 2));
+
+    let blame = LazyBlame::windowed("file.txt".into(), None, Some(lines));
+    assert!(blame.covers(&(2..=3)));
+    assert!(!blame.covers(&(1..=1)));
+    assert!(!blame.covers(&(3..=4)));
+    assert!(LazyBlame::new("file.txt".into(), None).covers(&(1..=100)));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_blame_runs_the_configured_git_with_extra_args() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("file.txt", "one\ntwo\n");
+    let first = repo.commit("add file.txt");
+    repo.write("file.txt", "one\n  two\n");
+    repo.commit("indent");
+
+    let blamed_by = |git: Git| async move {
+      let mut commits = vec![];
+      native_git_blame::parse(&git, gix::hash::Kind::Sha1, None, "file.txt".into(), None, |chunk| commits.push(chunk.sha)).await?;
+      anyhow::Ok(commits)
+    };
+    let git = Git::for_work_tree(repo.path());
+    assert_eq!(blamed_by(git.clone()).await?.len(), 2);
+    let ignoring_whitespace = Git {
+      blame_args: vec!["-w".into()],
+      ..git.clone()
+    };
+    assert_eq!(blamed_by(ignoring_whitespace).await?, vec![first]);
+
+    let missing = Git {
+      binary: repo.path().join("no-such-git"),
+      ..git
+    };
+    let err = blamed_by(missing).await.unwrap_err();
+    assert!(err.to_string().contains("cannot run '"), "{}", err);
+    assert!(err.to_string().contains("no-such-git"), "{}", err);
+    Ok(())
+  }
+
+  /// A blamed line, its commit, and its path and line number in that commit.
+  #[cfg(feature = "native-blame")]
+  type BlamedLine = (u32, ObjectId, BString, u32);
+
+  /// Each line of `path` at HEAD as blamed by git and by gix, blaming just `lines` if given.
+  #[cfg(feature = "native-blame")]
+  async fn blamed_by_git_and_gix(
+    repo: &TestRepo,
+    path: &str,
+    lines: Option<&Range<u32>>,
+  ) -> anyhow::Result<(Vec<BlamedLine>, Vec<BlamedLine>)> {
+    let by_line = |chunks: Vec<native_git_blame::BlameChunk>| {
+      let mut lines = Vec::new();
+      for chunk in chunks {
+        for i in 0..chunk.num_lines {
+          lines.push((chunk.line_final + i, chunk.sha, chunk.filename.clone(), chunk.line_original + i));
+        }
+      }
+      lines.sort();
+      lines
+    };
+    let mut from_git = vec![];
+    native_git_blame::parse(&Git::for_work_tree(repo.path()), gix::hash::Kind::Sha1, None, path.into(), lines, |chunk| from_git.push(chunk)).await?;
+    let mut native = vec![];
+    native_gix_blame::blame(&gix::open(repo.path())?, None, path.into(), lines, |chunk| native.push(chunk))?;
+    Ok((by_line(from_git), by_line(native)))
+  }
+
+  #[cfg(feature = "native-blame")]
+  #[tokio::test]
+  async fn test_native_blame_matches_git_on_linear_history() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("file.txt", "one\ntwo\nthree\nfour\n");
+    repo.commit("add file.txt");
+    repo.write("other.txt", "unrelated\n");
+    repo.commit("add other.txt");
+    repo.write("file.txt", "one\n2\nthree\nfour\nfive\n");
+    repo.commit("change two, add five");
+    repo.write("file.txt", "zero\none\n2\nfour\nfive\n");
+    repo.commit("add zero, drop three");
+
+    let (from_git, native) = blamed_by_git_and_gix(&repo, "file.txt", None).await?;
+    assert_eq!(native, from_git);
+    let (from_git, native) = blamed_by_git_and_gix(&repo, "file.txt", Some(&(2..4))).await?;
+    assert_eq!(native, from_git);
+    assert_eq!(native.len(), 2);
+
+    let gix_repo = gix::open(repo.path())?;
+    let err = native_gix_blame::blame(&gix_repo, None, "missing.txt".into(), None, |_| {}).unwrap_err();
+    assert!(err.to_string().contains("wasn't found"), "{}", err);
+    Ok(())
+  }
+
+  #[cfg(feature = "native-blame")]
+  #[tokio::test]
+  async fn test_native_blame_matches_git_across_a_rename() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("old.txt", "one\ntwo\nthree\nfour\n");
+    let added = repo.commit("add old.txt");
+    repo.git(&["mv", "old.txt", "new.txt"]);
+    repo.commit("rename old.txt to new.txt");
+    repo.write("new.txt", "one\n2\nthree\nfour\n");
+    repo.commit("change two");
+
+    let (from_git, native) = blamed_by_git_and_gix(&repo, "new.txt", None).await?;
+    assert_eq!(native, from_git);
+    // the lines from before the rename go to the commit that wrote them, under the old name
+    assert_eq!(native[0].1, added);
+    assert_eq!(native[0].2, "old.txt");
+    Ok(())
+  }
+
+  #[cfg(feature = "native-blame")]
+  #[tokio::test]
+  async fn test_native_blame_matches_git_through_a_merge() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("file.txt", "one\ntwo\nthree\nfour\nfive\nsix\n");
+    repo.commit("add file.txt");
+    repo.git(&["checkout", "-q", "-b", "feature"]);
+    repo.write("file.txt", "1\ntwo\nthree\nfour\nfive\nsix\n");
+    let on_branch = repo.commit("change one on a branch");
+    repo.git(&["checkout", "-q", "main"]);
+    repo.write("file.txt", "one\ntwo\nthree\nfour\nfive\n6\n");
+    let on_main = repo.commit("change six on main");
+    repo.git(&["merge", "-q", "--no-edit", "feature"]);
+
+    let (from_git, native) = blamed_by_git_and_gix(&repo, "file.txt", None).await?;
+    assert_eq!(native, from_git);
+    // each side's line goes to its own commit, not to the merge
+    assert_eq!(native[0].1, on_branch);
+    assert_eq!(native[5].1, on_main);
+    Ok(())
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_malformed_blame_output_is_an_error() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let repo = TestRepo::new();
+    let sha = "1".repeat(40);
+    let fake_git = repo.path().join("fake-git");
+    let blame_of = |output: String| {
+      let fake_git = fake_git.clone();
+      let repo_path = repo.path().to_owned();
+      async move {
+        std::fs::write(&fake_git, format!("#!/bin/sh\nprintf '{}'\n", output))?;
+        std::fs::set_permissions(&fake_git, std::fs::Permissions::from_mode(0o755))?;
+        let git = Git {
+          binary: fake_git,
+          ..Git::for_work_tree(&repo_path)
+        };
+        let mut chunks = vec![];
+        native_git_blame::parse(&git, gix::hash::Kind::Sha1, None, "file.txt".into(), None, |chunk| chunks.push(chunk)).await?;
+        anyhow::Ok(chunks)
+      }
+    };
+
+    let chunks = blame_of(format!("{} 4294967294 1 1\\nfilename file.txt\\n", sha)).await?;
+    assert_eq!(chunks[0].original_range(), 4294967294..4294967295);
+    for output in [
+      format!("{} 4294967295 1 1\\nfilename file.txt\\n", sha),
+      format!("{} 1 1\\nfilename file.txt\\n", sha),
+      format!("{} 1 one 1\\nfilename file.txt\\n", sha),
+      format!("{} 0 1 1\\nfilename file.txt\\n", sha),
+      format!("{} 1 1 1\\nfilename file.txt\\n", "x".repeat(40)),
+    ] {
+      let err = blame_of(output.clone()).await.unwrap_err();
+      assert!(err.to_string().contains("malformed line"), "{}: {}", output, err);
+    }
+    let err = blame_of(format!("{} 1 1 1\\nauthor Test\\n", sha)).await.unwrap_err();
+    assert!(err.to_string().contains("output ended inside the chunk"), "{}", err);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_blame_rejects_ids_of_another_hash_kind() -> anyhow::Result<()> {
+    // gix doesn't represent sha256 ids yet, but blame output from such a repository must fail
+    // loudly rather than be cut down to sha1-sized ids
+    let repo = TestRepo::with_object_format("sha256");
+    repo.write("file.txt", "one\ntwo\n");
+    repo.git(&["add", "-A"]);
+    repo.git(&["commit", "-q", "-m", "add file.txt"]);
+
+    let err = native_git_blame::parse(&Git::for_work_tree(repo.path()), gix::hash::Kind::Sha1, None, "file.txt".into(), None, |_| {})
+      .await
+      .unwrap_err();
+    assert!(err.to_string().contains("expected a SHA1 object id of 40 hex digits"), "{}", err);
+    Ok(())
+  }
+}
@@ -1,16 +1,102 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
 use gix::bstr::{BStr, BString};
 use gix::ObjectId;
 
+/// The files a commit touched. For a merge that's every file differing from any of its parents,
+/// so files brought in from a merged branch count too, unless `GraphConfig::first_parent_only`
+/// limits it to the first parent like `git log --first-parent`. A root commit touches everything
+/// it contains.
 #[derive(Clone)]
 pub(crate) struct CachedCommit {
+  /// Sorted and deduplicated path ids.
   pub(crate) changed_paths: Vec<u32>,
 }
 
+/// A commit's author and date as the commit object records them, before any mailmap.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CachedCommitMeta {
+  pub(crate) author_name: BString,
+  pub(crate) author_email: BString,
+  /// Commit time in seconds since the Unix epoch.
+  pub(crate) committed_at: i64,
+  /// First line of the message.
+  pub(crate) summary: BString,
+}
+
+/// A finished blame of a whole file at one commit, kept so a later session can skip running it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CachedBlame {
+  /// Fingerprint of the options the blame ran with; a blame made with others doesn't apply.
+  pub(crate) options: u64,
+  pub(crate) entries: Vec<crate::blame::BlameEntry>,
+}
+
+/// What `Cache::prune` removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PrunedCache {
+  pub(crate) commits: usize,
+  pub(crate) paths: usize,
+}
+
 pub(crate) trait Cache: Send + Sync {
   fn cache_path(&self, path: &BStr) -> anyhow::Result<u32>;
+  /// `cache_path` for many paths at once, returning their ids in the same order.
+  fn cache_paths(&self, paths: &[&BStr]) -> anyhow::Result<Vec<u32>> {
+    paths.iter().map(|path| self.cache_path(path)).collect()
+  }
+  /// The id `path` is interned under, `None` if it never was. Unlike `cache_path`, never adds it.
+  fn path_id(&self, path: &BStr) -> anyhow::Result<Option<u32>>;
   fn cache_rename(&self, old_path: &BStr, new_path: u32) -> anyhow::Result<()>;
   fn resolve_path(&self, path_id: u32) -> anyhow::Result<Option<BString>>;
+  /// `resolve_path` for many ids at once. Ids that don't resolve are left out.
+  fn resolve_paths(&self, path_ids: &[u32]) -> anyhow::Result<HashMap<u32, BString>> {
+    let mut paths = HashMap::new();
+    for &path_id in path_ids {
+      if let Some(path) = self.resolve_path(path_id)? {
+        paths.insert(path_id, path);
+      }
+    }
+    Ok(paths)
+  }
+  /// The id at the end of `path_id`'s rename chain, i.e. the id `resolve_path` reads the path from.
+  fn resolve_path_id(&self, path_id: u32) -> anyhow::Result<u32>;
+  /// The path interned under `path_id`, without following renames.
+  fn path(&self, path_id: u32) -> anyhow::Result<Option<BString>>;
+  /// Every interned path with its id, in id order, without following renames.
+  fn paths(&self) -> anyhow::Result<Vec<(u32, BString)>>;
   fn cached_commit(&self, id: &ObjectId) -> anyhow::Result<Option<CachedCommit>>;
   fn update_cached_commit(&self, id: &ObjectId, commit: CachedCommit) -> anyhow::Result<()>;
   fn is_commit_cached(&self, id: &ObjectId) -> anyhow::Result<bool>;
+  /// The ones of `ids` that are cached, for checking a batch in one go.
+  fn cached_commits(&self, ids: &[ObjectId]) -> anyhow::Result<HashSet<ObjectId>> {
+    let mut cached = HashSet::new();
+    for id in ids {
+      if self.is_commit_cached(id)? {
+        cached.insert(*id);
+      }
+    }
+    Ok(cached)
+  }
+  /// Records the 1-based line ranges of `path_id` as of commit `id` that the commit changed.
+  fn update_changed_lines(&self, id: &ObjectId, path_id: u32, lines: &[Range<u32>]) -> anyhow::Result<()>;
+  /// Line ranges stored by `update_changed_lines`, `None` if none were recorded.
+  fn changed_lines(&self, id: &ObjectId, path_id: u32) -> anyhow::Result<Option<Vec<Range<u32>>>>;
+  fn update_commit_meta(&self, id: &ObjectId, meta: &CachedCommitMeta) -> anyhow::Result<()>;
+  /// Metadata stored by `update_commit_meta`, `None` if the commit was cached without it.
+  fn commit_meta(&self, id: &ObjectId) -> anyhow::Result<Option<CachedCommitMeta>>;
+  /// Every metadata `update_commit_meta` stored, with its commit's id, in no particular order.
+  fn commit_metas(&self) -> anyhow::Result<Vec<(ObjectId, CachedCommitMeta)>>;
+  /// Stores the blame of `path_id` at commit `id`, replacing one stored at any other commit.
+  fn update_blame(&self, path_id: u32, id: &ObjectId, blame: &CachedBlame) -> anyhow::Result<()>;
+  /// The blame stored for `path_id` at commit `id`, `None` if there's none or it's of another
+  /// commit.
+  fn blame(&self, path_id: u32, id: &ObjectId) -> anyhow::Result<Option<CachedBlame>>;
+  /// Drops every cached commit not in `keep`, with its line ranges and metadata, then the paths
+  /// nothing left refers to: no remaining commit, line ranges or blame, and no kept path renamed
+  /// to them. Compacts the storage afterwards.
+  fn prune(&self, keep: &HashSet<ObjectId>) -> anyhow::Result<PrunedCache>;
+  /// Flushes and releases the cache; every later call fails.
+  fn close(&self) -> anyhow::Result<()>;
 }
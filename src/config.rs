@@ -0,0 +1,202 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where a graph keeps its path and commit cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum CacheBackend {
+  #[default]
+  Sqlite,
+  /// Plain maps, for short-lived graphs that don't need sqlite.
+  Memory,
+}
+
+/// What runs a graph's blames.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum BlameBackend {
+  /// `git blame` on the configured `git_binary`.
+  #[default]
+  Git,
+  /// gix in-process, needing no git binary. Follows renames and merges like git, but supports
+  /// none of `blame_args`, `ignore_whitespace`, `detect_moves`, `detect_copies`, `ignore_revs`
+  /// and `ignore_revs_file`, so a graph setting any of them fails to open, and the repository's
+  /// own `blame.ignoreRevsFile` goes unread. Only available with the `native-blame` feature.
+  Native,
+}
+
+/// Tuning for a `LocalGitGraph`, fixed when the graph is created. The defaults are the values
+/// the graph always used before they were configurable.
+#[derive(Clone, Debug)]
+pub(crate) struct GraphConfig {
+  /// Number of blame chunks around the queried line whose commits are considered.
+  pub(crate) chunk_range: u32,
+  /// How many of the strongest candidates get their locations resolved and returned.
+  pub(crate) max_candidates: usize,
+  /// Weight a commit contributes from the queried line's own chunk.
+  pub(crate) base_weight: f32,
+  /// Weight lost per chunk of distance from the queried line.
+  pub(crate) distance_decay: f32,
+  /// Attribute a merge only the changes relative to its first parent instead of to all of them.
+  pub(crate) first_parent_only: bool,
+  /// Also cache which lines each commit changed in each file, so a candidate that only one
+  /// interesting commit connects can be located without running blame on it.
+  pub(crate) cache_line_ranges: bool,
+  /// Leave files with binary content, like images or fonts, out of the paths each commit
+  /// changed, so they never come up as candidates. Commits cached before keep theirs.
+  pub(crate) skip_binary_files: bool,
+  /// Leave symlinks out of the paths each commit changed too; their content is a link target,
+  /// not source to relate. Submodules are always left out.
+  pub(crate) skip_symlinks: bool,
+  /// Commits older than this, by commit time, aren't cached for an opened file, so files with a
+  /// long history don't diff all of it. They don't contribute candidates unless cached before.
+  pub(crate) max_commit_age: Option<Duration>,
+  /// Likewise for commits that aren't among the newest `max_commit_depth` reachable from HEAD.
+  pub(crate) max_commit_depth: Option<usize>,
+  /// Also store each finished blame of a whole file in the cache, so the file opened again at
+  /// the same commit, in this session or, with a `cache_path`, a later one, skips git blame.
+  /// Only the last commit each path was blamed at is kept. Edits to an ignore-revs file aren't
+  /// noticed.
+  pub(crate) persist_blames: bool,
+  /// How many file blames to keep in memory before dropping the least recently used.
+  pub(crate) blame_cache_capacity: usize,
+  /// How many commits may be cached in the background at once; the rest wait in a queue.
+  pub(crate) max_caching_tasks: usize,
+  /// How many `git blame` processes may run at once. Blames of opened files get the next free
+  /// slot before blames that locate candidates.
+  pub(crate) max_blame_processes: usize,
+  /// How long locating a candidate waits for its blame before making do with the lines blamed so
+  /// far, marking the candidate `partial`. `None` waits for the whole blame.
+  pub(crate) locate_timeout: Option<Duration>,
+  /// How many candidates may be located at once across all queries. Queries past the limit wait
+  /// for a slot, so under load candidates get resolved one after another instead of piling up.
+  pub(crate) max_locating_tasks: usize,
+  /// Bytes of decoded objects each repo handle keeps for the tree diffs; zero disables it.
+  pub(crate) object_cache_size: usize,
+  /// How many of the strongest results a `depth` query expands from.
+  pub(crate) expansion_seeds: usize,
+  /// Factor applied to the weight of files found through expansion, once per level.
+  pub(crate) expansion_decay: f32,
+  /// Candidates score `blame_coefficient * blame + cochange_coefficient * cochange +
+  /// recency_coefficient * recency`. `blame` is the distance-decayed weight scaled by touched
+  /// lines, from 0 up to about `base_weight` per commit near the line. `cochange` is how many
+  /// distinct commits near the line also changed the candidate, 1 or more.
+  pub(crate) blame_coefficient: f32,
+  pub(crate) cochange_coefficient: f32,
+  /// `recency` is in `0..=1`: `0.5^(age / half_life)` for the newest of those commits, with the
+  /// query's `recency_half_life` or else `DEFAULT_RECENCY_HALF_LIFE`.
+  pub(crate) recency_coefficient: f32,
+  pub(crate) blame_backend: BlameBackend,
+  /// Lines blamed either side of the line a one-off query on an unopened file asks about.
+  pub(crate) blame_window_lines: u32,
+  /// The git executable blame runs, looked up on `PATH` unless it's a path.
+  pub(crate) git_binary: PathBuf,
+  /// Extra arguments for every `git blame`, like `-w` to ignore whitespace or `-M`/`-C` to follow
+  /// moved and copied lines. They must leave the `--incremental` output format alone.
+  pub(crate) blame_args: Vec<String>,
+  /// Blame through whitespace-only changes, like reindentation, to the commit before them.
+  pub(crate) ignore_whitespace: bool,
+  /// Blame lines moved within a file to the commit that wrote them, git's `-M`. A move needs
+  /// `move_threshold` alphanumeric characters to count, or git's 20.
+  pub(crate) detect_moves: bool,
+  pub(crate) move_threshold: Option<u32>,
+  /// Also follow lines moved or copied from other files changed in the same commit, git's `-C`,
+  /// with `copy_threshold` characters or git's 40. Costly: each commit blame passes through has
+  /// its other changed files searched for the lines, which can make a blame several times slower.
+  pub(crate) detect_copies: bool,
+  pub(crate) copy_threshold: Option<u32>,
+  /// Revisions blame looks through as if they never happened, like a big reformatting commit.
+  pub(crate) ignore_revs: Vec<String>,
+  /// A file listing more such revisions, like `.git-blame-ignore-revs`. Relative paths are
+  /// resolved against the work tree. The repository's own `blame.ignoreRevsFile` setting is
+  /// honored either way, since git reads it itself.
+  pub(crate) ignore_revs_file: Option<PathBuf>,
+  pub(crate) cache_backend: CacheBackend,
+  /// Database file for the sqlite backend, so the cache survives restarts. `None` keeps it in
+  /// memory.
+  pub(crate) cache_path: Option<PathBuf>,
+}
+
+impl Default for GraphConfig {
+  fn default() -> Self {
+    GraphConfig {
+      chunk_range: 6,
+      max_candidates: 20,
+      base_weight: 2.0,
+      distance_decay: 0.2,
+      first_parent_only: false,
+      cache_line_ranges: false,
+      skip_binary_files: false,
+      skip_symlinks: true,
+      max_commit_age: None,
+      max_commit_depth: None,
+      persist_blames: false,
+      blame_cache_capacity: 256,
+      max_caching_tasks: std::thread::available_parallelism().map_or(4, |n| n.get()),
+      max_blame_processes: std::thread::available_parallelism().map_or(4, |n| n.get()),
+      locate_timeout: Some(Duration::from_millis(250)),
+      max_locating_tasks: std::thread::available_parallelism().map_or(8, |n| 2 * n.get()),
+      object_cache_size: 16 * 1024 * 1024,
+      expansion_seeds: 3,
+      expansion_decay: 0.5,
+      blame_coefficient: 1.0,
+      cochange_coefficient: 0.0,
+      recency_coefficient: 0.0,
+      blame_backend: BlameBackend::Git,
+      blame_window_lines: 100,
+      git_binary: "git".into(),
+      blame_args: vec![],
+      ignore_whitespace: false,
+      detect_moves: false,
+      move_threshold: None,
+      detect_copies: false,
+      copy_threshold: None,
+      ignore_revs: vec![],
+      ignore_revs_file: None,
+      cache_backend: CacheBackend::Sqlite,
+      cache_path: None,
+    }
+  }
+}
+
+/// Half-life of the `recency` score component when the query sets none.
+pub(crate) const DEFAULT_RECENCY_HALF_LIFE: std::time::Duration = std::time::Duration::from_secs(30 * 24 * 60 * 60);
+
+impl GraphConfig {
+  /// Weight an interesting commit `dist` chunks away from the queried line adds to each file it
+  /// touched, never less than `floor`. Saturates at zero even for a negative `floor` or a
+  /// `distance_decay` past `base_weight` within a wide window, so a far commit adds little but
+  /// never takes weight away.
+  pub(crate) fn commit_contribution(&self, dist: f32, floor: f32) -> f32 {
+    (self.base_weight - dist * self.distance_decay).max(floor).max(0.0)
+  }
+
+  /// Everything passed to `git blame` on top of the output format: `blame_args` and the options
+  /// for what blame ignores.
+  pub(crate) fn git_blame_args(&self, work_tree: Option<&Path>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = self.blame_args.iter().map(Into::into).collect();
+    if self.ignore_whitespace {
+      args.push("-w".into());
+    }
+    let detections = [
+      (self.detect_moves, "-M", self.move_threshold),
+      (self.detect_copies, "-C", self.copy_threshold),
+    ];
+    for (enabled, flag, threshold) in detections {
+      if enabled {
+        args.push(threshold.map_or_else(|| flag.into(), |n| format!("{}{}", flag, n)).into());
+      }
+    }
+    for rev in &self.ignore_revs {
+      args.push("--ignore-rev".into());
+      args.push(rev.into());
+    }
+    if let Some(file) = &self.ignore_revs_file {
+      args.push("--ignore-revs-file".into());
+      args.push(match work_tree {
+        Some(work_tree) => work_tree.join(file).into(),
+        None => file.into(),
+      });
+    }
+    args
+  }
+}
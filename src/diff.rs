@@ -0,0 +1,95 @@
+use std::cmp::min;
+use std::ops::Range;
+
+use gix::bstr::BString;
+use gix::diff::blob::intern::InternedInput;
+use gix::diff::blob::sources::byte_lines_with_terminator;
+use gix::diff::blob::Algorithm;
+
+/// Pairs of 0-based line ranges `(before, after)` that differ between `old` and `new`, in order.
+/// Uses Myers like git's default so the ranges line up with what git blame and git show see.
+pub(crate) fn changed_lines(old: &[u8], new: &[u8]) -> Vec<(Range<u32>, Range<u32>)> {
+  let input = InternedInput::new(byte_lines_with_terminator(old), byte_lines_with_terminator(new));
+  let mut changes = Vec::new();
+  gix::diff::blob::diff(Algorithm::Myers, &input, |before: Range<u32>, after: Range<u32>| {
+    changes.push((before, after))
+  });
+  changes
+}
+
+/// Whether `data` is binary by git's own rule: a NUL byte among its first 8000 bytes.
+pub(crate) fn is_binary(data: &[u8]) -> bool {
+  data[..min(data.len(), 8000)].contains(&0)
+}
+
+/// One hunk of a unified diff, numbered the way a `@@ -old_start,old_lines +new_start,new_lines @@`
+/// header is.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Hunk {
+  pub(crate) old_start: u32,
+  pub(crate) old_lines: u32,
+  pub(crate) new_start: u32,
+  pub(crate) new_lines: u32,
+  /// Hunk body, each line prefixed with ` `, `-` or `+` and without its line terminator.
+  pub(crate) lines: Vec<BString>,
+}
+
+/// Unified diff hunks turning `old` into `new` with `context` lines around each change.
+pub(crate) fn hunks(old: &[u8], new: &[u8], context: u32) -> Vec<Hunk> {
+  let old_lines: Vec<&[u8]> = byte_lines_with_terminator(old).collect();
+  let new_lines: Vec<&[u8]> = byte_lines_with_terminator(new).collect();
+  let changes = changed_lines(old, new);
+
+  let line = |prefix: u8, content: &[u8]| {
+    let content = content.strip_suffix(b"\n").unwrap_or(content);
+    let mut line = Vec::with_capacity(content.len() + 1);
+    line.push(prefix);
+    line.extend_from_slice(content);
+    BString::from(line)
+  };
+
+  let mut hunks = Vec::new();
+  let mut first = 0;
+  while first < changes.len() {
+    // changes whose context would touch or overlap end up in the same hunk
+    let mut last = first;
+    while last + 1 < changes.len() && changes[last + 1].0.start - changes[last].0.end <= 2 * context {
+      last += 1;
+    }
+
+    let old_start = changes[first].0.start.saturating_sub(context);
+    let new_start = changes[first].1.start - (changes[first].0.start - old_start);
+    let old_end = min(changes[last].0.end + context, old_lines.len() as u32);
+    let new_end = changes[last].1.end + (old_end - changes[last].0.end);
+
+    let mut lines = Vec::new();
+    let mut old_pos = old_start;
+    for (before, after) in &changes[first..=last] {
+      for i in old_pos..before.start {
+        lines.push(line(b' ', old_lines[i as usize]));
+      }
+      for i in before.clone() {
+        lines.push(line(b'-', old_lines[i as usize]));
+      }
+      for i in after.clone() {
+        lines.push(line(b'+', new_lines[i as usize]));
+      }
+      old_pos = before.end;
+    }
+    for i in old_pos..old_end {
+      lines.push(line(b' ', old_lines[i as usize]));
+    }
+
+    // like git, an empty side is numbered by the line it comes after
+    let header_start = |start: u32, count: u32| if count == 0 { start } else { start + 1 };
+    hunks.push(Hunk {
+      old_start: header_start(old_start, old_end - old_start),
+      old_lines: old_end - old_start,
+      new_start: header_start(new_start, new_end - new_start),
+      new_lines: new_end - new_start,
+      lines,
+    });
+    first = last + 1;
+  }
+  hunks
+}
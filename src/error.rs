@@ -0,0 +1,89 @@
+use gix::bstr::BString;
+use gix::ObjectId;
+
+/// Why a graph operation failed, for callers that react differently to each. Messages start with
+/// the variant's name, which is how they reach JavaScript.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum GraphError {
+  #[error("RepoOpen: cannot open the repository at '{path}': {source}")]
+  RepoOpen {
+    path: String,
+    source: Box<gix::open::Error>,
+  },
+  #[error("RevisionNotFound: cannot resolve revision '{spec}': {reason}")]
+  RevisionNotFound { spec: String, reason: String },
+  /// A path that, once `.` and `..` are resolved, leaves the repository's work tree.
+  #[error("PathOutsideRepo: '{path}' is outside the repository")]
+  PathOutsideRepo { path: BString },
+  #[error("PathNotTracked: '{path}' is not tracked in {revision}")]
+  PathNotTracked { path: BString, revision: ObjectId },
+  #[error("BlameFailed: blaming '{path}' failed: {reason}")]
+  BlameFailed { path: BString, reason: String },
+  /// A query asked about a line the file doesn't have.
+  #[error("LineOutOfRange: line {line} is outside '{path}', which has {line_count} lines")]
+  LineOutOfRange {
+    path: BString,
+    line: usize,
+    line_count: u32,
+  },
+  /// A query was asked not to wait for a file whose blame is still loading.
+  #[error("NotReady: the blame of '{path}' is still loading")]
+  NotReady { path: BString },
+  /// The caller cancelled the query.
+  #[error("Cancelled: the query was cancelled")]
+  Cancelled,
+  /// The graph was closed or shut down.
+  #[error("Closed: the graph has been closed")]
+  Closed,
+  #[error("Cache: {0:#}")]
+  Cache(anyhow::Error),
+  #[error(transparent)]
+  Other(anyhow::Error),
+}
+
+/// Recovers the `GraphError` an operation failed with through `anyhow`, even under added
+/// context. Errors raised by the cache's database count as `Cache`, anything else unrecognized
+/// as `Other`.
+impl From<anyhow::Error> for GraphError {
+  fn from(e: anyhow::Error) -> Self {
+    let e = match e.downcast::<GraphError>() {
+      Ok(e) => return e,
+      Err(e) => e,
+    };
+    if e.chain().any(|cause| cause.is::<rusqlite::Error>()) {
+      GraphError::Cache(e)
+    } else {
+      GraphError::Other(e)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_classifies_errors_passed_through_anyhow() {
+    let tracked = anyhow::Error::from(GraphError::PathNotTracked {
+      path: "a.rs".into(),
+      revision: ObjectId::null(gix::hash::Kind::Sha1),
+    });
+    assert!(matches!(
+      GraphError::from(tracked.context("while querying")),
+      GraphError::PathNotTracked { .. }
+    ));
+
+    let tracked = anyhow::Error::from(GraphError::BlameFailed {
+      path: "a.rs".into(),
+      reason: "exited with error code 128".into(),
+    });
+    let e = GraphError::from(tracked);
+    assert!(matches!(e, GraphError::BlameFailed { .. }));
+    assert!(e.to_string().starts_with("BlameFailed: "), "{}", e);
+
+    let sqlite = anyhow::Error::from(rusqlite::Error::InvalidQuery).context("reading cached commits");
+    let e = GraphError::from(sqlite);
+    assert!(matches!(e, GraphError::Cache(_)));
+    assert!(e.to_string().starts_with("Cache: reading cached commits: "), "{}", e);
+  }
+}
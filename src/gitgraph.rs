@@ -1,14 +1,20 @@
 use dashmap::DashMap;
 use gix::bstr::{BStr, BString, ByteSlice};
 use gix::object::tree::diff::{Action, Change};
+use gix::object::tree::EntryKind;
 use std::cmp::{max, min};
-use std::collections::{HashMap, HashSet};
-use std::ops::Range;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::{Range, RangeInclusive};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::{time};
 use gix::ObjectId;
 
 use crate::blame;
+use crate::cache::{CachedBlame, CachedCommitMeta, PrunedCache};
+use crate::config::{BlameBackend, CacheBackend, GraphConfig};
+use crate::diff;
+use crate::error::GraphError;
 
 #[derive(Debug)]
 pub(crate) struct Candidate {
@@ -16,312 +22,5400 @@ pub(crate) struct Candidate {
   pub(crate) locations: Vec<Range<u32>>,
   pub(crate) touched_lines: u32,
   pub(crate) weight: f32,
+  /// The path id the candidate was collected under, its current path's with
+  /// `QueryOptions::merge_renames`. Breaks ties between equal weights.
+  pub(crate) path_id: u32,
   pub(crate) commit: ObjectId,
+  /// The path id `commit` touched this file under; differs from the candidate's own id when the
+  /// file has been renamed since.
+  pub(crate) commit_path_id: u32,
+  /// Weight each interesting commit contributed to this candidate, before touched-lines scaling.
+  pub(crate) contributions: Vec<(ObjectId, f32)>,
+  /// The fewest commits accounting for `QueryOptions::explain_cutoff` of the weight, strongest first.
+  pub(crate) explaining_commits: Vec<ObjectId>,
+  /// `QueryOptions::calibration` applied to the weight before it's scaled against the other
+  /// candidates.
+  pub(crate) calibrated_score: Option<f32>,
+  /// `weight` relative to the strongest candidate of the query, which gets 1.0.
+  pub(crate) normalized_weight: f32,
+  /// For a file found by `QueryOptions::depth` expansion, the directly related file it's related to.
+  pub(crate) via: Option<BString>,
+  /// `locations` split by the interesting commit each range comes from, in line order. Only
+  /// filled in with `QueryOptions::attribute_locations`.
+  pub(crate) attributions: Vec<Attribution>,
+  /// Who made `commit` and when. Only looked up with `QueryOptions::commit_info`.
+  pub(crate) commit_meta: Option<Arc<CommitMeta>>,
+  /// Located from a blame still loading after `GraphConfig::locate_timeout`, so `locations` may
+  /// be missing some lines.
+  pub(crate) partial: bool,
+}
+
+impl Candidate {
+  /// Every interesting commit that added weight to the candidate, strongest first. For a file
+  /// found through expansion, the commits linking it to its `via` file.
+  pub(crate) fn linking_commits(&self) -> Vec<ObjectId> {
+    by_contribution(&self.contributions)
+      .into_iter()
+      .map(|(commit, _)| commit)
+      .collect()
+  }
+
+  /// Folds in `other`, the same file found by another query: weights and contributions add up
+  /// and locations are united. `commit` is that of whichever weighed more.
+  fn absorb(&mut self, other: Candidate) {
+    if other.weight > self.weight {
+      self.commit = other.commit;
+      self.commit_path_id = other.commit_path_id;
+      self.commit_meta = other.commit_meta;
+    }
+    self.weight += other.weight;
+    for (commit, weight) in other.contributions {
+      match self.contributions.iter_mut().find(|(c, _)| *c == commit) {
+        Some((_, w)) => *w += weight,
+        None => self.contributions.push((commit, weight)),
+      }
+    }
+    self.locations = merge_ranges(std::mem::take(&mut self.locations).into_iter().chain(other.locations).collect());
+    self.touched_lines = self.locations.iter().map(|loc| loc.end - loc.start).sum();
+    if !other.attributions.is_empty() {
+      // the same lines may be attributed by both, so ranges are merged per commit first
+      let mut by_commit: HashMap<ObjectId, Vec<Range<u32>>> = HashMap::new();
+      for attribution in std::mem::take(&mut self.attributions).into_iter().chain(other.attributions) {
+        by_commit.entry(attribution.commit).or_default().push(attribution.range);
+      }
+      let located = by_commit
+        .into_iter()
+        .flat_map(|(commit, ranges)| merge_ranges(ranges).into_iter().map(move |range| (range, commit)))
+        .collect();
+      self.attributions = attributions(located);
+    }
+    self.calibrated_score = match (self.calibrated_score, other.calibrated_score) {
+      (Some(a), Some(b)) => Some(a.max(b)),
+      (a, b) => a.or(b),
+    };
+    self.via = self.via.take().or(other.via);
+    self.partial |= other.partial;
+  }
+}
+
+/// The chunks a query of `lines` weighs: every chunk the lines cover plus `chunk_range / 2`
+/// before and `chunk_range / 2 - 1` after, so a one-line query spans `chunk_range` chunks, along
+/// with the chunk indices of the first and last line. The chunks holding the lines themselves
+/// are always in, even with a `chunk_range` below 2.
+fn query_window(
+  config: &GraphConfig,
+  blame_lines: &[blame::BlameEntry],
+  lines: &RangeInclusive<usize>,
+) -> (isize, isize, Range<isize>) {
+  let first = blame::chunk_index_for_line(blame_lines, *lines.start() as u32) as isize;
+  let last = blame::chunk_index_for_line(blame_lines, *lines.end() as u32) as isize;
+  let chunk_range = config.chunk_range as isize;
+  let start = max(0, first - chunk_range / 2);
+  let end = min(max(last + chunk_range / 2, last + 1), blame_lines.len() as isize);
+  (first, last, start..end)
+}
+
+/// Fails with `GraphError::LineOutOfRange` when `lines` aren't all in the blamed file, or one past
+/// its end, the empty line an editor shows after a trailing newline. A blame still loading or
+/// of part of the file can't tell, so anything goes.
+fn check_line_range(blame: &blame::LazyBlame, lines: &RangeInclusive<usize>) -> Result<(), GraphError> {
+  let Some(line_count) = blame.line_count() else {
+    return Ok(());
+  };
+  let valid = 1..=line_count as usize + 1;
+  match [*lines.start(), *lines.end()].into_iter().find(|line| !valid.contains(line)) {
+    Some(line) => Err(GraphError::LineOutOfRange {
+      path: blame.file_path.clone(),
+      line,
+      line_count,
+    }),
+    None => Ok(()),
+  }
+}
+
+/// 64-bit FNV-1a, spelled out so signatures stay the same across builds and Rust versions.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+  fn new() -> Self {
+    Fnv1a(0xcbf2_9ce4_8422_2325)
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    for byte in bytes {
+      self.0 ^= *byte as u64;
+      self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+    }
+  }
+}
+
+/// Lines of a candidate that one interesting commit connects to the query.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Attribution {
+  pub(crate) range: Range<u32>,
+  pub(crate) commit: ObjectId,
+  /// Derived from the sha alone, so a commit gets the same color in every query.
+  pub(crate) color_seed: u32,
+}
+
+/// Merges touching ranges of the same commit and sorts by line. Ranges come from blame chunks,
+/// so they never overlap.
+fn attributions(mut located: Vec<(Range<u32>, ObjectId)>) -> Vec<Attribution> {
+  located.sort_by_key(|(range, _)| range.start);
+  let mut merged: Vec<Attribution> = Vec::with_capacity(located.len());
+  for (range, commit) in located {
+    match merged.last_mut() {
+      Some(last) if last.commit == commit && last.range.end == range.start => last.range.end = range.end,
+      _ => {
+        let seed = commit.as_slice();
+        merged.push(Attribution {
+          range,
+          commit,
+          color_seed: u32::from_be_bytes([seed[0], seed[1], seed[2], seed[3]]),
+        })
+      }
+    }
+  }
+  merged
+}
+
+/// `ranges` sorted, with overlapping and touching ones merged, so each line is in at most one.
+fn merge_ranges(mut ranges: Vec<Range<u32>>) -> Vec<Range<u32>> {
+  ranges.sort_by_key(|range| range.start);
+  let mut merged: Vec<Range<u32>> = Vec::with_capacity(ranges.len());
+  for range in ranges {
+    match merged.last_mut() {
+      Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+      _ => merged.push(range),
+    }
+  }
+  merged
+}
+
+fn open_repo(path: &str) -> Result<gix::Repository, GraphError> {
+  gix::open(path).map_err(|source| GraphError::RepoOpen {
+    path: path.to_owned(),
+    source: Box::new(source),
+  })
+}
+
+/// Fails with `BlameFailed` if git blame couldn't blame the file, like when it isn't at the
+/// blamed revision. A file git blamed to no lines, like an empty one, succeeded.
+fn blame_succeeded(blame: &blame::LazyBlame) -> Result<(), GraphError> {
+  match blame.error() {
+    Some(reason) => Err(GraphError::BlameFailed {
+      path: blame.file_path.clone(),
+      reason,
+    }),
+    None => Ok(()),
+  }
+}
+
+/// Blocking calls return while their blame still runs on the runtime's workers, which a
+/// current-thread runtime doesn't have.
+fn ensure_multi_thread(runtime: &tokio::runtime::Handle) -> anyhow::Result<()> {
+  anyhow::ensure!(
+    runtime.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread,
+    "blocking calls need a multi-thread tokio runtime"
+  );
+  Ok(())
+}
+
+/// Candidates under one directory, see `related_dirs`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RelatedDir {
+  /// The first `depth` components of the files' directory, with a trailing slash; empty for
+  /// files at the top of the repository.
+  pub(crate) dir: BString,
+  pub(crate) file_count: usize,
+  pub(crate) total_weight: f32,
+}
+
+/// Rolls `candidates` up into their directories cut to `depth` components, strongest first. A
+/// file in a directory shallower than `depth` counts towards that directory.
+pub(crate) fn related_dirs(candidates: &[Candidate], depth: usize) -> Vec<RelatedDir> {
+  let mut dirs: Vec<RelatedDir> = Vec::new();
+  for candidate in candidates {
+    let Some(path) = &candidate.path else {
+      continue;
+    };
+    let components: Vec<&[u8]> = path.split_str("/").collect();
+    let parents = &components[..components.len() - 1];
+    let mut dir = BString::default();
+    for component in parents.iter().take(depth) {
+      dir.extend_from_slice(component);
+      dir.push(b'/');
+    }
+    match dirs.iter_mut().find(|d| d.dir == dir) {
+      Some(existing) => {
+        existing.file_count += 1;
+        existing.total_weight += candidate.weight;
+      }
+      None => dirs.push(RelatedDir {
+        dir,
+        file_count: 1,
+        total_weight: candidate.weight,
+      }),
+    }
+  }
+  dirs.sort_by(|a, b| b.total_weight.total_cmp(&a.total_weight).then_with(|| a.dir.cmp(&b.dir)));
+  dirs
+}
+
+/// Files related to a symbol, like a function, whose lines the caller resolved.
+#[derive(Debug)]
+pub(crate) struct SymbolRelatedFiles {
+  /// The symbol's name as given, only echoed back.
+  pub(crate) name: String,
+  pub(crate) candidates: Vec<Candidate>,
+}
+
+/// What a related-files query found.
+#[derive(Debug)]
+pub(crate) struct RelatedFiles {
+  pub(crate) candidates: Vec<Candidate>,
+  /// Commits near the queried line that weren't cached yet, so their files are missing from
+  /// `candidates`. Watch them to learn when asking again would give a fuller answer.
+  pub(crate) uncached_commits: Vec<ObjectId>,
+  /// Whether `GraphConfig::max_candidates` cut off weaker related files.
+  pub(crate) truncated: bool,
+}
+
+impl RelatedFiles {
+  /// Why `candidates` may be an approximation of the full answer, `None` if it's complete.
+  /// Uncached commits and timed-out blames resolve with time, so asking again later can help.
+  pub(crate) fn incomplete_reason(&self) -> Option<String> {
+    let mut reasons = Vec::new();
+    if !self.uncached_commits.is_empty() {
+      reasons.push(format!(
+        "not all commits near the line are cached yet ({} missing)",
+        self.uncached_commits.len()
+      ));
+    }
+    if self.candidates.iter().any(|candidate| candidate.partial) {
+      reasons.push("some candidates' blames timed out, so their locations may be missing lines".to_owned());
+    }
+    if self.truncated {
+      reasons.push("weaker related files were cut off at maxCandidates".to_owned());
+    }
+    (!reasons.is_empty()).then(|| reasons.join("; "))
+  }
+}
+
+/// Commits waiting to be cached in the background, and how many workers are draining them.
+#[derive(Default)]
+struct CachingQueue {
+  pending: VecDeque<ObjectId>,
+  workers: usize,
+}
+
+/// A thread-local repo borrowed from `InnerGraph::repos`, given back on drop so its object cache
+/// stays warm for the next diff.
+struct PooledRepo<'a> {
+  repo: Option<gix::Repository>,
+  pool: &'a std::sync::Mutex<Vec<gix::Repository>>,
+}
+
+impl std::ops::Deref for PooledRepo<'_> {
+  type Target = gix::Repository;
+
+  fn deref(&self) -> &gix::Repository {
+    self.repo.as_ref().unwrap()
+  }
+}
+
+impl Drop for PooledRepo<'_> {
+  fn drop(&mut self) {
+    if let Some(repo) = self.repo.take() {
+      self.pool.lock().unwrap().push(repo);
+    }
+  }
+}
+
+/// Waits for a set of commits to finish caching.
+struct CommitWatcher {
+  pending: HashSet<ObjectId>,
+  notify: Box<dyn FnOnce() + Send>,
+}
+
+/// How one file changed in one commit.
+#[derive(Debug)]
+pub(crate) struct FileDiff {
+  /// The path before the commit, when it renamed the file.
+  pub(crate) old_path: Option<BString>,
+  /// The path the commit left the file at.
+  pub(crate) new_path: BString,
+  pub(crate) hunks: Vec<diff::Hunk>,
+}
+
+/// A path the cache has seen, as listed by `list_paths`.
+#[derive(Debug)]
+pub(crate) struct PathInfo {
+  pub(crate) id: u32,
+  /// The path as it was interned, maybe an old name of the file.
+  pub(crate) path: BString,
+  /// Where the file lives now, following recorded renames.
+  pub(crate) current_path: BString,
+  /// Whether `current_path` is in HEAD's tree.
+  pub(crate) exists: bool,
+}
+
+/// How the cache resolves a path id, see `debug_resolve_path`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ResolvedPathId {
+  /// The path interned under the id, `None` for an id the cache never handed out.
+  pub(crate) path: Option<BString>,
+  /// The id at the end of the id's rename chain.
+  pub(crate) current_id: u32,
+  /// The path candidates with the id are reported under; they drop out of results when `None`.
+  pub(crate) current_path: Option<BString>,
+}
+
+/// Per-query knobs for `related_files`. The default reproduces the plain ranked list.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct QueryOptions {
+  /// Fraction (0..=1) of a candidate's weight its `explaining_commits` must cover.
+  pub(crate) explain_cutoff: Option<f32>,
+  /// Fold a renamed file's old path ids into its current one, so its weight isn't split across
+  /// the names it had.
+  pub(crate) merge_renames: bool,
+  /// Lowest weight a single interesting commit contributes, however far it is from the queried
+  /// line. Zero keeps distance from turning a shared commit into a penalty.
+  pub(crate) weight_floor: f32,
+  /// Only count commits whose author email, after mailmapping, is one of these. Aliases the
+  /// mailmap knows are mapped too; emails compare case-insensitively.
+  pub(crate) authors: Option<Vec<BString>>,
+  /// Also score each candidate on a logistic curve, for thresholds that mean the same across
+  /// queries.
+  pub(crate) calibration: Option<Calibration>,
+  /// 2 also brings in files related to the strongest results, with decayed weight, and so on for
+  /// larger values. 0 and 1 only return directly related files.
+  pub(crate) depth: u8,
+  /// Scale each commit's contribution by how many lines it changed around the queried region, so
+  /// rewrites count more than one-character fixes. Needs `GraphConfig::cache_line_ranges`;
+  /// commits without cached line ranges keep their plain weight.
+  pub(crate) weight_by_diff_size: bool,
+  /// Halve a commit's contribution for every this much time since it was committed, so recent
+  /// work counts more than old refactors. `None` weighs commits the same regardless of age.
+  pub(crate) recency_half_life: Option<time::Duration>,
+  /// Leave recent commits out of the coupling signal, so results reflect established history.
+  /// The queried file's lines stay as they are; lines last changed by an excluded commit just
+  /// don't contribute.
+  pub(crate) exclude_recent: Option<ExcludeRecent>,
+  /// Fill in `Candidate::attributions`.
+  pub(crate) attribute_locations: bool,
+  /// Fill in `Candidate::commit_meta`, which decodes each candidate's commit.
+  pub(crate) commit_info: bool,
+  /// Drop candidates whose current path isn't a file in HEAD, like deleted files, which are no
+  /// use to jump to.
+  pub(crate) only_existing: bool,
+  /// Only return candidates whose current path this lets through, like no lockfiles or test
+  /// fixtures. Filtered files are never located.
+  pub(crate) paths: Option<PathFilter>,
+  /// Drop candidates this author, after mailmapping, changed in any cached commit, leaving related
+  /// files that are new to them. History that isn't cached yet doesn't count.
+  pub(crate) unfamiliar_to: Option<BString>,
+  /// Record how long each phase of the query and each candidate's resolution took.
+  #[cfg(feature = "trace")]
+  pub(crate) trace: Option<Arc<crate::trace::QueryTrace>>,
+  /// Give up on the query, failing it with `Cancelled`, once this is cancelled.
+  pub(crate) cancellation: Option<Arc<Cancellation>>,
+}
+
+/// Lets a caller abandon queries that are no longer wanted, like ones for a line the cursor has
+/// left. Candidates still being located are aborted; blames they started run on for later.
+#[derive(Debug, Default)]
+pub(crate) struct Cancellation {
+  cancelled: AtomicBool,
+  notify: tokio::sync::Notify,
+}
+
+impl Cancellation {
+  pub(crate) fn cancel(&self) {
+    self.cancelled.store(true, Ordering::Release);
+    self.notify.notify_waiters();
+  }
+
+  pub(crate) fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::Acquire)
+  }
+
+  /// Resolves once `cancel` is called, right away if it was already.
+  async fn cancelled(&self) {
+    loop {
+      let future = self.notify.notified();
+      if self.is_cancelled() {
+        return;
+      }
+      future.await;
+    }
+  }
+}
+
+/// Globs a candidate's repo-relative path must match one of, if any, and must match none of, in
+/// `.gitignore` syntax: `*.lock` matches the file in any directory, `fixtures/**` only at the root.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PathFilter {
+  /// `None` lets every path through; a list, even one left empty, only the paths it matches.
+  pub(crate) include: Option<Vec<gix::glob::Pattern>>,
+  pub(crate) exclude: Vec<gix::glob::Pattern>,
+}
+
+impl PathFilter {
+  /// A filter from glob strings. Empty ones match nothing and are left out, so an include list of
+  /// only empty globs lets nothing through rather than everything.
+  pub(crate) fn new<'a, I: IntoIterator<Item = &'a BStr>>(
+    include: Option<I>,
+    exclude: impl IntoIterator<Item = &'a BStr>,
+  ) -> Self {
+    let parse = |globs: Vec<&BStr>| -> Vec<gix::glob::Pattern> {
+      globs
+        .into_iter()
+        .filter_map(|glob| gix::glob::Pattern::from_bytes_without_negation(glob))
+        .collect()
+    };
+    PathFilter {
+      include: include.map(|include| parse(include.into_iter().collect())),
+      exclude: parse(exclude.into_iter().collect()),
+    }
+  }
+
+  pub(crate) fn allows(&self, path: &BStr) -> bool {
+    let matches = |pattern: &gix::glob::Pattern| {
+      pattern.matches_repo_relative_path(
+        path,
+        path.rfind_byte(b'/').map(|slash| slash + 1),
+        Some(false),
+        gix::glob::pattern::Case::Sensitive,
+        gix::glob::wildmatch::Mode::NO_MATCH_SLASH_LITERAL,
+      )
+    };
+    self.include.as_ref().is_none_or(|include| include.iter().any(matches)) && !self.exclude.iter().any(matches)
+  }
+}
+
+/// Which recent commits `QueryOptions::exclude_recent` leaves out.
+#[derive(Clone, Debug)]
+pub(crate) enum ExcludeRecent {
+  /// The newest `n` commits reachable from HEAD, by commit time.
+  Count(usize),
+  /// Commits reachable from HEAD but not from this revision, like `base..HEAD`.
+  Since(String),
+}
+
+/// Logistic map `1 / (1 + e^-(slope * weight + intercept))` from a raw weight to `0..=1`. The
+/// default puts a single interesting commit in the queried chunk at 0.5.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Calibration {
+  pub(crate) slope: f32,
+  pub(crate) intercept: f32,
+}
+
+impl Default for Calibration {
+  fn default() -> Self {
+    Calibration {
+      slope: 1.0,
+      intercept: -2.0,
+    }
+  }
+}
+
+impl Calibration {
+  pub(crate) fn score(&self, weight: f32) -> f32 {
+    1.0 / (1.0 + (-(self.slope * weight + self.intercept)).exp())
+  }
+}
+
+/// Who made a commit and what it's about, with the author mapped through the repo's mailmap.
+#[derive(Debug)]
+pub(crate) struct CommitMeta {
+  pub(crate) author_email: BString,
+  pub(crate) author_name: BString,
+  /// Commit time in seconds since the Unix epoch.
+  pub(crate) committed_at: i64,
+  /// First line of the message.
+  pub(crate) summary: BString,
+}
+
+/// What the cache keeps of `commit` for `CommitMeta`.
+fn stored_commit_meta(commit: &gix::Commit<'_>) -> anyhow::Result<CachedCommitMeta> {
+  let author = commit.author()?;
+  Ok(CachedCommitMeta {
+    author_name: author.name.to_owned(),
+    author_email: author.email.to_owned(),
+    committed_at: commit.time()?.seconds,
+    summary: commit.message()?.summary().into_owned(),
+  })
+}
+
+/// `0.5^(age / half_life)` for a commit made at `committed_at` seconds since the epoch. Commits
+/// dated after `now` count in full.
+fn recency_factor(committed_at: i64, now: i64, half_life: time::Duration) -> f32 {
+  let age = now.saturating_sub(committed_at).max(0) as f64;
+  0.5f64.powf(age / half_life.as_secs_f64()) as f32
+}
+
+/// Ranks candidates heaviest first, equal weights by ascending path id, so a query returns the
+/// same order every run whatever order the candidates were collected in, and whichever query
+/// found them. `total_cmp` keeps a `NaN` weight from panicking the sort; it ranks above every
+/// number.
+fn heaviest_first(a: &Candidate, b: &Candidate) -> std::cmp::Ordering {
+  b.weight.total_cmp(&a.weight).then_with(|| a.path_id.cmp(&b.path_id))
+}
+
+/// `contributions` strongest first, ties broken by commit id so the order is stable.
+fn by_contribution(contributions: &[(ObjectId, f32)]) -> Vec<(ObjectId, f32)> {
+  let mut sorted = contributions.to_vec();
+  sorted.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+  sorted
+}
+
+/// Returns the commits sorted by contribution, cut off once their cumulative share of the total
+/// reaches `cutoff`.
+fn explaining_commits(contributions: &[(ObjectId, f32)], cutoff: f32) -> Vec<ObjectId> {
+  let sorted = by_contribution(contributions);
+
+  let total: f32 = sorted.iter().map(|(_, w)| w.max(0.0)).sum();
+  let mut explaining = Vec::new();
+  let mut covered = 0.0f32;
+  for (commit, w) in sorted {
+    if !explaining.is_empty() && covered >= total * cutoff {
+      break;
+    }
+    covered += w.max(0.0);
+    explaining.push(commit);
+  }
+  explaining
+}
+
+/// Where the graph's time goes, for performance diagnosis.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct GraphStats {
+  pub(crate) blame_cache_hits: usize,
+  pub(crate) blame_cache_misses: usize,
+  /// `load_cached_commit` calls for a commit already cached.
+  pub(crate) commit_cache_hits: usize,
+  pub(crate) commit_cache_misses: usize,
+  /// Commits whose trees were diffed against their parents to be cached.
+  pub(crate) commits_diffed: usize,
+  /// Related file queries, expansion's nested ones not counted.
+  pub(crate) queries: usize,
+  /// Mean time a query took, zero before the first.
+  pub(crate) average_query_latency: time::Duration,
+}
+
+/// The counters behind `GraphStats`.
+#[derive(Default)]
+struct Counters {
+  blame_cache_hits: AtomicUsize,
+  blame_cache_misses: AtomicUsize,
+  commit_cache_hits: AtomicUsize,
+  commit_cache_misses: AtomicUsize,
+  commits_diffed: AtomicUsize,
+  queries: AtomicUsize,
+  query_micros: AtomicU64,
+}
+
+impl Counters {
+  fn count(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn snapshot(&self) -> GraphStats {
+    let queries = self.queries.load(Ordering::Relaxed);
+    let query_micros = self.query_micros.load(Ordering::Relaxed);
+    GraphStats {
+      blame_cache_hits: self.blame_cache_hits.load(Ordering::Relaxed),
+      blame_cache_misses: self.blame_cache_misses.load(Ordering::Relaxed),
+      commit_cache_hits: self.commit_cache_hits.load(Ordering::Relaxed),
+      commit_cache_misses: self.commit_cache_misses.load(Ordering::Relaxed),
+      commits_diffed: self.commits_diffed.load(Ordering::Relaxed),
+      queries,
+      average_query_latency: time::Duration::from_micros(query_micros.checked_div(queries as u64).unwrap_or(0)),
+    }
+  }
+}
+
+/// Counts background blame and caching tasks so shutdown can wait for them.
+struct Outstanding {
+  count: AtomicUsize,
+  notify: tokio::sync::Notify,
+}
+
+/// Held by a background task for as long as it runs.
+struct TaskGuard(Arc<InnerGraph>);
+
+impl Drop for TaskGuard {
+  fn drop(&mut self) {
+    let outstanding = &self.0.outstanding;
+    if outstanding.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+      outstanding.notify.notify_waiters();
+    }
+  }
 }
 
 struct InnerGraph {
   repo: gix::ThreadSafeRepository,
+  /// How blames run; they read committed revisions only, so bare repositories do without a
+  /// work tree.
+  git: blame::native_git_blame::Git,
+  /// Idle thread-local repos, as many as were ever in use at once.
+  repos: std::sync::Mutex<Vec<gix::Repository>>,
+  config: GraphConfig,
   disk_cache: Box<dyn crate::cache::Cache>,
-  blame_cache: DashMap<BString, Arc<blame::LazyBlame>>,
+  blame_cache: blame::BlameCache,
+  blame_pool: Arc<blame::BlamePool>,
+  /// One permit per candidate being located, shared by all queries.
+  locating: tokio::sync::Semaphore,
+  /// Most permits of `locating` ever held at once.
+  #[cfg(test)]
+  locating_peak: AtomicUsize,
+  mailmap: gix::mailmap::Snapshot,
+  commit_meta: DashMap<ObjectId, Arc<CommitMeta>>,
+  /// The newest `GraphConfig::max_commit_depth` commits, with the HEAD they were walked from.
+  history_window: std::sync::Mutex<Option<(ObjectId, Arc<HashSet<ObjectId>>)>>,
+  watchers: std::sync::Mutex<Vec<CommitWatcher>>,
+  /// Held for writing while the cache is pruned, and for reading by whatever interns paths and
+  /// then refers to them, so pruning can't drop a path in between.
+  pruning: std::sync::RwLock<()>,
+  caching: std::sync::Mutex<CachingQueue>,
+  closed: AtomicBool,
+  outstanding: Outstanding,
+  /// How many times `refresh` was called.
+  refreshes: AtomicUsize,
+  /// Blames running in the background, for `close` to abort.
+  blame_tasks: std::sync::Mutex<Vec<(tokio::task::AbortHandle, Arc<blame::LazyBlame>)>>,
+  counters: Counters,
 }
 
-impl InnerGraph {
-  pub async fn load_blame(self: &Arc<Self>, revision: Option<ObjectId>, filepath: &BStr, recursive: bool) -> anyhow::Result<Arc<blame::LazyBlame>> {
-    match self.blame_cache.entry(filepath.to_owned()) {
-      dashmap::Entry::Occupied(e) => Ok(e.get().clone()),
-      dashmap::Entry::Vacant(e) => {
-        let blame = Arc::new(blame::LazyBlame::new(filepath.to_owned()));
-        let blame = e.insert(blame);
-
-        let blame_owned = blame.clone();
-        let repo_path_owned = self.repo.work_dir().unwrap().to_owned();
-        let filepath_owned = filepath.to_owned();
-        let inner = self.clone();
-
-        tokio::spawn(async move {
-          let mut seen = HashSet::new();
-          let blame_owned_inner = blame_owned.clone();
-          let _ = blame::native_git_blame::parse(&repo_path_owned, revision, filepath_owned.as_bstr(), move |chunk| {
-            let entry = blame::BlameEntry {
-              range_in_blamed_file: chunk.line_final..chunk.line_final + chunk.num_lines,
-              range_in_original_file: chunk.line_original..chunk.line_original + chunk.num_lines,
-              commit_id: chunk.sha,
-            };
-
-            blame_owned_inner.add_entry(entry);
-            if recursive && seen.insert(chunk.sha) {
-              let inner = inner.clone();
-              rayon::spawn(move || {
-                inner.load_cached_commit(&chunk.sha).unwrap();
-              });
-            }
-          }).await;
-          blame_owned.mark_as_finished();
-        });
+impl InnerGraph {
+  /// Fails with `Closed` once the graph is shutting down or closed.
+  fn ensure_open(&self) -> Result<(), GraphError> {
+    if self.closed.load(Ordering::Acquire) {
+      return Err(GraphError::Closed);
+    }
+    Ok(())
+  }
+
+  /// Registers a background task, or `None` once the graph is shutting down.
+  fn start_task(self: &Arc<Self>) -> Option<TaskGuard> {
+    self.outstanding.count.fetch_add(1, Ordering::AcqRel);
+    let guard = TaskGuard(self.clone());
+    if self.closed.load(Ordering::Acquire) {
+      return None;
+    }
+    Some(guard)
+  }
+
+  async fn wait_for_tasks(&self) {
+    loop {
+      let future = self.outstanding.notify.notified();
+      if self.outstanding.count.load(Ordering::Acquire) == 0 {
+        return;
+      }
+      future.await;
+    }
+  }
+
+  /// The blame of `filepath` at `revision`, shared by every request for the same file content.
+  /// A `recursive` request is for a file someone opened: the blame's commits get cached, and it
+  /// takes a git process ahead of blames that only locate candidates. One that comes after a
+  /// non-recursive request for the same blame upgrades it: if it's still waiting for a git
+  /// process it goes ahead of the other candidates, and its commits are cached once it's done.
+  pub async fn load_blame(self: &Arc<Self>, revision: Option<ObjectId>, filepath: &BStr, recursive: bool) -> anyhow::Result<Arc<blame::LazyBlame>> {
+    let key = self.blame_key(revision, filepath)?;
+    if let Some(blame) = self.blame_cache.get(&key) {
+      Counters::count(&self.counters.blame_cache_hits);
+      if recursive {
+        self.cache_blamed_commits(&blame);
+      }
+      return Ok(blame);
+    }
+
+    let Some(task) = self.start_task() else {
+      return Err(GraphError::Closed.into());
+    };
+    let tracked = key.1.is_some();
+    let (blame, created) = self
+      .blame_cache
+      .get_or_insert_with(key, || Arc::new(blame::LazyBlame::new(filepath.to_owned(), revision)));
+    if !created {
+      Counters::count(&self.counters.blame_cache_hits);
+      if recursive {
+        self.cache_blamed_commits(&blame);
+      }
+      return Ok(blame);
+    }
+    Counters::count(&self.counters.blame_cache_misses);
+    if !tracked {
+      // git blame would only fail on it
+      blame.mark_as_failed("the file isn't in the blamed revision".into());
+      return Ok(blame);
+    }
+    if let Ok(Some(entries)) = self.stored_blame(&blame) {
+      for entry in entries {
+        blame.add_entry(entry);
+      }
+      blame.mark_as_finished();
+      if recursive {
+        self.cache_blamed_commits(&blame);
+      }
+      return Ok(blame);
+    }
+    // commits are queued as the chunks come in
+    let recursive = recursive && blame.start_caching_commits();
+    self.spawn_blame(&blame, task, recursive);
+    Ok(blame)
+  }
+
+  /// A blame of `lines` of `filepath` at `revision` for one query. That's the file's full blame
+  /// if one is cached, else a new one of just `lines`, left out of the cache since other queries
+  /// rarely ask for the same window. Its commits are cached as they come in.
+  fn load_blame_window(
+    self: &Arc<Self>,
+    revision: ObjectId,
+    filepath: &BStr,
+    lines: Range<u32>,
+  ) -> anyhow::Result<Arc<blame::LazyBlame>> {
+    let key = self.blame_key(Some(revision), filepath)?;
+    if let Some(blame) = self.blame_cache.get(&key) {
+      Counters::count(&self.counters.blame_cache_hits);
+      return Ok(blame);
+    }
+    let Some(task) = self.start_task() else {
+      return Err(GraphError::Closed.into());
+    };
+    Counters::count(&self.counters.blame_cache_misses);
+    let blame = Arc::new(blame::LazyBlame::windowed(filepath.to_owned(), Some(revision), Some(lines)));
+    let recursive = blame.start_caching_commits();
+    self.spawn_blame(&blame, task, recursive);
+    Ok(blame)
+  }
+
+  /// Runs `blame` in the background, queueing its commits for caching if `recursive`.
+  fn spawn_blame(self: &Arc<Self>, blame: &Arc<blame::LazyBlame>, task: TaskGuard, recursive: bool) {
+    let blame_owned = blame.clone();
+    let git_owned = self.git.clone();
+    let hash_kind = self.thread_local_repo().object_hash();
+    let filepath_owned = blame.file_path.clone();
+    let revision = blame.revision;
+    let lines = blame.lines.clone();
+    let inner = self.clone();
+
+    // a recursive blame is a file someone opened, the others are for candidates
+    let priority = if recursive {
+      blame::BlamePriority::Primary
+    } else {
+      blame::BlamePriority::Candidate
+    };
+    // a recursive request coming in while a candidate blame waits moves it up
+    let opened = blame.clone();
+    let running = tokio::spawn(async move {
+      let _task = task;
+      let _permit = match priority {
+        blame::BlamePriority::Primary => inner.blame_pool.acquire(priority).await,
+        blame::BlamePriority::Candidate => {
+          let promoted = Box::new(move || opened.caches_commits());
+          inner.blame_pool.acquire_promotable(priority, promoted).await
+        }
+      };
+      let mut seen = HashSet::new();
+      let mut seen_renames = HashSet::new();
+      let blame_owned_inner = blame_owned.clone();
+      let blamed_path = filepath_owned.clone();
+      let recording = inner.clone();
+      let on_chunk = move |chunk: blame::native_git_blame::BlameChunk| {
+        // a commit that only renamed the file owns none of its lines, so it's never cached and
+        // its rename never recorded; blame saw it happen though. With move and copy detection
+        // lines also come from files that stay around, which is no rename.
+        let renames = [
+          chunk.previous_filename.as_ref().map(|previous| (previous, &chunk.filename, Some(chunk.sha))),
+          Some((&chunk.filename, &blamed_path, revision)),
+        ];
+        for (from, to, at) in renames.into_iter().flatten() {
+          if from != to
+            && seen_renames.insert((from.clone(), to.clone()))
+            && recording.blame_key(at, from.as_ref()).is_ok_and(|(_, blob)| blob.is_none())
+          {
+            let _ = recording.record_rename(from.as_ref(), to.as_ref());
+          }
+        }
+
+        let entry = blame::BlameEntry {
+          range_in_blamed_file: chunk.final_range(),
+          range_in_original_file: chunk.original_range(),
+          commit_id: chunk.sha,
+          original_path: chunk.filename,
+        };
+
+        let uncommitted = entry.is_uncommitted();
+        blame_owned_inner.add_entry(entry);
+        if recursive && !uncommitted && seen.insert(chunk.sha) {
+          recording.cache_commit_in_background(chunk.sha);
+        }
+      };
+      let parsed = match inner.config.blame_backend {
+        BlameBackend::Git => {
+          blame::native_git_blame::parse(&git_owned, hash_kind, revision, filepath_owned.as_bstr(), lines.as_ref(), on_chunk)
+            .await
+        }
+        #[cfg(feature = "native-blame")]
+        BlameBackend::Native => {
+          let inner = inner.clone();
+          tokio::task::spawn_blocking(move || {
+            let repo = inner.thread_local_repo();
+            blame::native_gix_blame::blame(&repo, revision, filepath_owned.as_bstr(), lines.as_ref(), on_chunk)
+          })
+          .await
+          .unwrap_or_else(|e| Err(e.into()))
+        }
+        #[cfg(not(feature = "native-blame"))]
+        BlameBackend::Native => unreachable!("refused when the graph was opened"),
+      };
+      match parsed {
+        Ok(()) => {
+          // a blame that can't be stored is just run again next time
+          let _ = inner.store_blame(&blame_owned);
+          blame_owned.mark_as_finished()
+        }
+        Err(e) => blame_owned.mark_as_failed(format!("{:#}", e)),
+      }
+    });
+    let mut blame_tasks = self.blame_tasks.lock().unwrap();
+    blame_tasks.retain(|(task, _)| !task.is_finished());
+    blame_tasks.push((running.abort_handle(), blame.clone()));
+  }
+
+  /// Blames are keyed by content rather than revision, so moving HEAD past commits that didn't
+  /// touch a file reuses its blame. A file changed and then changed back gets the blame from
+  /// before, which only differs in crediting the restored lines to their first author.
+  fn blame_key(&self, revision: Option<ObjectId>, filepath: &BStr) -> anyhow::Result<blame::BlameKey> {
+    let repo = self.thread_local_repo();
+    let commit = match revision {
+      Some(id) => repo.find_commit(id)?,
+      None => repo.head_commit()?,
+    };
+    // a directory or submodule has nothing to blame
+    let blob = commit
+      .tree()?
+      .lookup_entry(filepath.split_str("/"))?
+      .filter(|entry| entry.mode().is_blob_or_symlink())
+      .map(|entry| entry.object_id());
+    Ok((filepath.to_owned(), blob))
+  }
+
+  /// `filepath` relative to the work tree's root, with empty and `.` components dropped and `..`
+  /// applied, so every spelling of a file blames it under one key. An absolute path has to be in
+  /// the work tree. Fails with `PathOutsideRepo` for a path that leaves it.
+  fn repo_relative_path(&self, filepath: &BStr) -> Result<BString, GraphError> {
+    let outside = || GraphError::PathOutsideRepo {
+      path: filepath.to_owned(),
+    };
+    let mut relative = filepath.to_owned();
+    if let Ok(path) = gix::path::try_from_bstr(filepath) {
+      if path.is_absolute() {
+        let work_tree = self.git.work_tree.as_deref().ok_or_else(outside)?;
+        let canonical = work_tree.canonicalize().ok();
+        let inside = std::iter::once(work_tree)
+          .chain(canonical.as_deref())
+          .find_map(|root| path.strip_prefix(root).ok())
+          .ok_or_else(outside)?;
+        relative = gix::path::to_unix_separators_on_windows(gix::path::into_bstr(inside)).into_owned();
+      }
+    }
+
+    let mut components: Vec<&[u8]> = Vec::new();
+    for component in relative.split_str("/") {
+      match component {
+        b"" | b"." => {}
+        b".." => {
+          components.pop().ok_or_else(outside)?;
+        }
+        name => components.push(name),
+      }
+    }
+    Ok(components.join(&b'/').into())
+  }
+
+  /// Fingerprint of what decides a blame's output besides the file and revision: the backend and
+  /// the arguments git blame runs with.
+  fn blame_options(&self) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write(&[self.config.blame_backend as u8]);
+    for arg in &self.git.blame_args {
+      hasher.write(arg.as_encoded_bytes());
+      hasher.write(&[0]);
+    }
+    hasher.0
+  }
+
+  /// The chunks of `blame` as an earlier blame of the whole file at the same commit with the same
+  /// options stored them, with `GraphConfig::persist_blames` on.
+  fn stored_blame(&self, blame: &blame::LazyBlame) -> anyhow::Result<Option<Vec<blame::BlameEntry>>> {
+    let (true, None, Some(revision)) = (self.config.persist_blames, &blame.lines, blame.revision) else {
+      return Ok(None);
+    };
+    let Some(path_id) = self.disk_cache.path_id(blame.file_path.as_ref())? else {
+      return Ok(None);
+    };
+    let stored = self.disk_cache.blame(path_id, &revision)?;
+    Ok(stored.filter(|stored| stored.options == self.blame_options()).map(|stored| stored.entries))
+  }
+
+  /// Stores a finished blame of a whole file for `stored_blame`, with `GraphConfig::persist_blames`
+  /// on.
+  fn store_blame(&self, blame: &blame::LazyBlame) -> anyhow::Result<()> {
+    let (true, None, Some(revision)) = (self.config.persist_blames, &blame.lines, blame.revision) else {
+      return Ok(());
+    };
+    let _pruning = self.pruning.read().unwrap();
+    let path_id = self.disk_cache.cache_path(blame.file_path.as_ref())?;
+    self.disk_cache.update_blame(
+      path_id,
+      &revision,
+      &CachedBlame {
+        options: self.blame_options(),
+        entries: blame.lines(),
+      },
+    )
+  }
+
+  /// Caches the commits of a blame that was started without doing so, like one made to locate a
+  /// candidate, once someone opens the same file at the same commit.
+  fn cache_blamed_commits(self: &Arc<Self>, blame: &Arc<blame::LazyBlame>) {
+    if !blame.start_caching_commits() {
+      return;
+    }
+    let Some(task) = self.start_task() else {
+      return;
+    };
+    let inner = self.clone();
+    let blame = blame.clone();
+    tokio::spawn(async move {
+      let _task = task;
+      blame.wait_for_ready().await;
+      let mut seen = HashSet::new();
+      for line in blame.lines() {
+        if !line.is_uncommitted() && seen.insert(line.commit_id) {
+          inner.cache_commit_in_background(line.commit_id);
+        }
+      }
+    });
+  }
+
+  /// Waits for `blame`, for at most `GraphConfig::locate_timeout`; false if it's still loading.
+  async fn wait_to_locate(&self, blame: &blame::LazyBlame) -> bool {
+    if !blame.is_ready() {
+      match self.config.locate_timeout {
+        Some(timeout) => {
+          let _ = tokio::time::timeout(timeout, blame.wait_for_ready()).await;
+        }
+        None => blame.wait_for_ready().await,
+      }
+    }
+    blame.is_ready()
+  }
+
+  /// The lines of `path` that `interesting_shas` account for, and whether its blame was still
+  /// loading so there may be more.
+  async fn find_related_locations(
+    self: &Arc<Self>,
+    revision: Option<ObjectId>,
+    path: &BStr,
+    interesting_shas: &HashSet<ObjectId>,
+  ) -> Option<(Vec<(Range<u32>, ObjectId)>, bool)> {
+    let blame = match self.load_blame(revision, path.as_bstr(), false).await {
+      Ok(blame) => blame,
+      Err(_) => return None,
+    };
+    let partial = !self.wait_to_locate(&blame).await;
+
+    let related_locations: Vec<(Range<u32>, ObjectId)> = blame.lines().into_iter().filter_map(|chunk| {
+      if interesting_shas.contains(&chunk.commit_id) {
+        Some((chunk.range_in_blamed_file, chunk.commit_id))
+      } else {
+        None
+      }
+    }).collect();
+
+    if related_locations.is_empty() {
+      None
+    } else {
+      Some((related_locations, partial))
+    }
+  }
+
+  /// A thread-local repo with the configured object cache. `to_thread_local` starts every repo
+  /// without one, and a fresh repo per diff would start cold anyway, so repos are pooled.
+  fn thread_local_repo(&self) -> PooledRepo<'_> {
+    let pooled = self.repos.lock().unwrap().pop();
+    let repo = pooled.unwrap_or_else(|| {
+      let mut repo = self.repo.to_thread_local();
+      repo.object_cache_size(self.config.object_cache_size);
+      repo
+    });
+    PooledRepo {
+      repo: Some(repo),
+      pool: &self.repos,
+    }
+  }
+
+  /// Queues `commit` for caching, starting another worker if fewer than
+  /// `GraphConfig::max_caching_tasks` are running.
+  fn cache_commit_in_background(self: &Arc<Self>, commit: ObjectId) {
+    let Some(task) = self.start_task() else {
+      return;
+    };
+    let mut queue = self.caching.lock().unwrap();
+    queue.pending.push_back(commit);
+    if queue.workers >= self.config.max_caching_tasks.max(1) {
+      return;
+    }
+    queue.workers += 1;
+    drop(queue);
+
+    let inner = self.clone();
+    rayon::spawn(move || {
+      let _task = task;
+      inner.drain_caching_queue();
+    });
+  }
+
+  fn drain_caching_queue(self: &Arc<Self>) {
+    loop {
+      // commits queued before a shutdown still get cached, shutdown waits for them. Each worker
+      // takes its share of the queue, so cached ones are weeded out in one lookup.
+      let batch: Vec<ObjectId> = {
+        let mut queue = self.caching.lock().unwrap();
+        if queue.pending.is_empty() {
+          queue.workers -= 1;
+          return;
+        }
+        let share = queue.pending.len().div_ceil(queue.workers.max(1));
+        queue.pending.drain(..share).collect()
+      };
+      let cached = self.disk_cache.cached_commits(&batch).unwrap_or_default();
+      for commit in batch {
+        // a panic here would abort the whole process; a commit we fail to cache just doesn't
+        // contribute candidates
+        if !cached.contains(&commit) && matches!(self.within_history_bounds(&commit), Ok(true)) {
+          let _ = self.load_cached_commit(&commit);
+        }
+        self.commit_settled(&commit);
+      }
+    }
+  }
+
+  /// Whether `commit` is recent enough, by `GraphConfig::max_commit_age` and `max_commit_depth`,
+  /// to be cached for an opened file.
+  fn within_history_bounds(&self, commit: &ObjectId) -> anyhow::Result<bool> {
+    if let Some(max_age) = self.config.max_commit_age {
+      let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64);
+      if now.saturating_sub(self.commit_meta(commit)?.committed_at) > max_age.as_secs() as i64 {
+        return Ok(false);
+      }
+    }
+    let Some(depth) = self.config.max_commit_depth else {
+      return Ok(true);
+    };
+    let repo = self.thread_local_repo();
+    let head = repo.head_id()?;
+    let mut window = self.history_window.lock().unwrap();
+    let recent = match &*window {
+      Some((walked_from, recent)) if *walked_from == head.detach() => recent.clone(),
+      _ => {
+        let newest_first = gix::revision::walk::Sorting::ByCommitTime(Default::default());
+        let mut recent = HashSet::new();
+        for info in head.ancestors().sorting(newest_first).all()?.take(depth) {
+          recent.insert(info?.id);
+        }
+        let recent = Arc::new(recent);
+        *window = Some((head.detach(), recent.clone()));
+        recent
+      }
+    };
+    Ok(recent.contains(commit))
+  }
+
+  /// Tells the watchers `commit` is done caching, successfully or not.
+  fn commit_settled(&self, commit: &ObjectId) {
+    let done: Vec<CommitWatcher> = {
+      let mut watchers = self.watchers.lock().unwrap();
+      for watcher in watchers.iter_mut() {
+        watcher.pending.remove(commit);
+      }
+      let (done, waiting) = std::mem::take(&mut *watchers)
+        .into_iter()
+        .partition(|watcher| watcher.pending.is_empty());
+      *watchers = waiting;
+      done
+    };
+    for watcher in done {
+      (watcher.notify)();
+    }
+  }
+
+  fn record_rename(&self, from: &BStr, to: &BStr) -> anyhow::Result<()> {
+    let _pruning = self.pruning.read().unwrap();
+    let to = self.disk_cache.cache_path(to)?;
+    self.disk_cache.cache_rename(from, to)
+  }
+
+  /// Where `candidate` was touched, read from the cached line ranges instead of blaming it. Only
+  /// exact when a single interesting commit connects the candidate and it's the one we'd blame
+  /// at, since lines of any other commit may have moved by then; `None` means blame is needed.
+  fn cached_locations(&self, candidate: &Candidate) -> anyhow::Result<Option<Vec<Range<u32>>>> {
+    if !self.config.cache_line_ranges {
+      return Ok(None);
+    }
+    match candidate.contributions.as_slice() {
+      [(commit, _)] if *commit == candidate.commit => {}
+      _ => return Ok(None),
+    }
+    let lines = self.disk_cache.changed_lines(&candidate.commit, candidate.commit_path_id)?;
+    Ok(lines.filter(|lines| !lines.is_empty()))
+  }
+
+  /// Ids of the `paths` that are files in HEAD's tree.
+  fn existing_paths(&self, paths: &HashMap<u32, BString>) -> anyhow::Result<HashSet<u32>> {
+    let repo = self.thread_local_repo();
+    let head_tree = repo.head_commit()?.tree()?;
+    let mut existing = HashSet::new();
+    for (path_id, path) in paths {
+      if head_tree
+        .lookup_entry(path.split_str("/"))?
+        .is_some_and(|entry| !entry.mode().is_tree())
+      {
+        existing.insert(*path_id);
+      }
+    }
+    Ok(existing)
+  }
+
+  /// Factor for a blame chunk's contribution from the size of the commit's change around it: the
+  /// cached changed lines of the file at that commit overlapping the chunk, log-scaled so a
+  /// one-line touch keeps factor 1. `1.0` when the commit's lines aren't cached.
+  fn diff_size_factor(&self, entry: &blame::BlameEntry) -> anyhow::Result<f32> {
+    // a path the cache never saw has no line ranges either
+    let Some(path_id) = self.disk_cache.path_id(entry.original_path.as_ref())? else {
+      return Ok(1.0);
+    };
+    let Some(lines) = self.disk_cache.changed_lines(&entry.commit_id, path_id)? else {
+      return Ok(1.0);
+    };
+    let chunk = &entry.range_in_original_file;
+    let changed: u32 = lines
+      .iter()
+      .filter(|range| range.start < chunk.end && chunk.start < range.end)
+      .map(|range| range.end - range.start)
+      .sum();
+    Ok(1.0 + (changed.max(1) as f32).log2())
+  }
+
+  fn commit_meta(&self, id: &ObjectId) -> anyhow::Result<Arc<CommitMeta>> {
+    if let Some(meta) = self.commit_meta.get(id) {
+      return Ok(meta.clone());
+    }
+
+    let stored = match self.disk_cache.commit_meta(id)? {
+      Some(stored) => stored,
+      None => {
+        // cached before its metadata was, or not cached at all
+        let repo = self.thread_local_repo();
+        let stored = stored_commit_meta(&repo.find_commit(*id)?)?;
+        self.disk_cache.update_commit_meta(id, &stored)?;
+        stored
+      }
+    };
+    // mapped on the way out, so a mailmap edit applies to commits cached before it
+    let author = self.mailmap.resolve(gix::actor::SignatureRef {
+      name: stored.author_name.as_ref(),
+      email: stored.author_email.as_ref(),
+      time: Default::default(),
+    });
+    let meta = Arc::new(CommitMeta {
+      author_email: author.email,
+      author_name: author.name,
+      committed_at: stored.committed_at,
+      summary: stored.summary,
+    });
+    self.commit_meta.insert(*id, meta.clone());
+    Ok(meta)
+  }
+
+  /// Maps `email` to its canonical address if the mailmap lists it as an alias.
+  fn canonical_email(&self, email: &BStr) -> BString {
+    let signature = gix::actor::SignatureRef {
+      name: "".into(),
+      email,
+      time: Default::default(),
+    };
+    match self.mailmap.try_resolve(signature) {
+      Some(mapped) => mapped.email,
+      None => email.to_owned(),
+    }
+  }
+
+  /// Whether a changed tree entry of `mode` counts among a commit's changed paths. Trees are
+  /// walked into and submodules have no lines to blame, so neither does; nor do symlinks, whose
+  /// blob is only a link target, unless `GraphConfig::skip_symlinks` is off.
+  fn records_entry(&self, mode: gix::object::tree::EntryMode) -> bool {
+    match mode.kind() {
+      EntryKind::Blob | EntryKind::BlobExecutable => true,
+      EntryKind::Link => !self.config.skip_symlinks,
+      EntryKind::Tree | EntryKind::Commit => false,
+    }
+  }
+
+  fn load_cached_commit(self: &Arc<Self>, commit_sha: &ObjectId) -> anyhow::Result<()> {
+    if self.disk_cache.is_commit_cached(commit_sha)? {
+      Counters::count(&self.counters.commit_cache_hits);
+      return Ok(());
+    }
+    Counters::count(&self.counters.commit_cache_misses);
+    let _pruning = self.pruning.read().unwrap();
+
+    let path_cache = &self.disk_cache;
+    let repo = self.thread_local_repo();
+    let commit = repo.find_commit(*commit_sha)?;
+    let tree = commit.tree()?;
+    let mut parent_trees = Vec::new();
+    for parent in commit.parent_ids() {
+      parent_trees.push(repo.find_commit(parent)?.tree()?);
+      if self.config.first_parent_only {
+        break;
+      }
+    }
+    // a root commit adds everything it contains
+    if parent_trees.is_empty() {
+      parent_trees.push(repo.empty_tree());
+    }
+    // blame attributes a merge's lines by parent, plain ranges from a union of diffs wouldn't match
+    let record_lines = self.config.cache_line_ranges && parent_trees.len() == 1;
+    // (path, renamed from, old blob, new blob), interned in one go once the diffs are done
+    let mut changes: Vec<(BString, Option<BString>, Option<ObjectId>, ObjectId)> = Vec::new();
+
+    for parent_tree in parent_trees {
+      let mut diff = parent_tree.changes()?;
+      diff.for_each_to_obtain_tree(&tree, |change| -> anyhow::Result<Action> {
+        match change {
+          Change::Addition {
+            entry_mode,
+            location,
+            id,
+            ..
+          } => {
+            if self.records_entry(entry_mode) {
+              changes.push((location.to_owned(), None, None, id.detach()));
+            }
+          }
+          Change::Deletion { .. } => {
+            // not interesting
+          }
+          Change::Modification {
+            entry_mode,
+            location,
+            previous_id,
+            id,
+            ..
+          } => {
+            if self.records_entry(entry_mode) {
+              changes.push((location.to_owned(), None, Some(previous_id.detach()), id.detach()));
+            }
+          }
+          Change::Rewrite {
+            source_location,
+            source_id,
+            entry_mode,
+            location,
+            id,
+            copy,
+            ..
+          } => {
+            if self.records_entry(entry_mode) {
+              let renamed_from = (!copy).then(|| source_location.to_owned());
+              changes.push((location.to_owned(), renamed_from, Some(source_id.detach()), id.detach()));
+            }
+          }
+        }
+        Ok(Action::Continue)
+      })?;
+    }
+
+    Counters::count(&self.counters.commits_diffed);
+
+    if self.config.skip_binary_files {
+      let mut kept = Vec::with_capacity(changes.len());
+      for change in changes {
+        if !diff::is_binary(&repo.find_object(change.3)?.data) {
+          kept.push(change);
+        }
+      }
+      changes = kept;
+    }
+
+    let locations: Vec<&BStr> = changes.iter().map(|(location, ..)| location.as_bstr()).collect();
+    let path_ids = path_cache.cache_paths(&locations)?;
+    let mut changed = Vec::with_capacity(changes.len());
+    let mut changed_blobs: Vec<(u32, Option<ObjectId>, ObjectId)> = Vec::with_capacity(changes.len());
+    for ((_, renamed_from, old_id, new_id), path_id) in changes.into_iter().zip(path_ids) {
+      if let Some(renamed_from) = renamed_from {
+        path_cache.cache_rename(renamed_from.as_ref(), path_id)?;
+      }
+      changed.push(path_id);
+      changed_blobs.push((path_id, old_id, new_id));
+    }
+
+    // written before the commit itself, so a cached commit always has its lines
+    if record_lines {
+      for (path_id, old_id, new_id) in changed_blobs {
+        let old_data = match old_id {
+          Some(id) => repo.find_object(id)?.detach().data,
+          None => Vec::new(),
+        };
+        let new_data = repo.find_object(new_id)?.detach().data;
+        let lines: Vec<Range<u32>> = diff::changed_lines(&old_data, &new_data)
+          .into_iter()
+          .filter(|(_, after)| !after.is_empty())
+          .map(|(_, after)| after.start + 1..after.end + 1)
+          .collect();
+        path_cache.update_changed_lines(commit_sha, path_id, &lines)?;
+      }
+    }
+
+    // the commit is decoded already, so its metadata comes for free
+    self.disk_cache.update_commit_meta(commit_sha, &stored_commit_meta(&commit)?)?;
+
+    changed.sort();
+    changed.dedup();
+    self.disk_cache.update_cached_commit(
+      commit_sha,
+      crate::cache::CachedCommit {
+        changed_paths: changed,
+      },
+    )?;
+
+    Ok(())
+  }
+}
+
+pub(crate) struct LocalGitGraph {
+  inner: Arc<InnerGraph>,
+}
+
+impl Clone for LocalGitGraph {
+  fn clone(&self) -> Self {
+    LocalGitGraph {
+      inner: self.inner.clone(),
+    }
+  }
+}
+
+impl LocalGitGraph {
+  /// Opens the repository at `repo`, a work tree, a `.git` directory or a bare repository.
+  pub(crate) fn new(repo: &str, config: GraphConfig) -> Result<Self, GraphError> {
+    Self::open(repo, None, config)
+  }
+
+  /// Opens the repository in `git_dir` with its files checked out at `work_tree`, for a git
+  /// directory kept apart from its checkout.
+  pub(crate) fn with_worktree(git_dir: &str, work_tree: &str, config: GraphConfig) -> Result<Self, GraphError> {
+    Self::open(git_dir, Some(work_tree.into()), config)
+  }
+
+  fn open(repo: &str, work_tree: Option<std::path::PathBuf>, config: GraphConfig) -> Result<Self, GraphError> {
+    let repo = open_repo(repo)?;
+    let disk_cache: Box<dyn crate::cache::Cache> = match config.cache_backend {
+      CacheBackend::Sqlite => match &config.cache_path {
+        Some(path) => Box::new(crate::sqlite::SqliteCache::open(path, repo.object_hash()).map_err(GraphError::Cache)?),
+        None => Box::new(crate::sqlite::SqliteCache::new().map_err(GraphError::Cache)?),
+      },
+      CacheBackend::Memory => Box::new(crate::memory::MemoryCache::new()),
+    };
+    Self::from_repo(repo, work_tree, config, disk_cache)
+  }
+
+  #[cfg(test)]
+  pub(crate) fn with_cache(
+    repo: &str,
+    config: GraphConfig,
+    disk_cache: Box<dyn crate::cache::Cache>,
+  ) -> Result<Self, GraphError> {
+    Self::from_repo(open_repo(repo)?, None, config, disk_cache)
+  }
+
+  fn from_repo(
+    mut repo: gix::Repository,
+    work_tree: Option<std::path::PathBuf>,
+    config: GraphConfig,
+    disk_cache: Box<dyn crate::cache::Cache>,
+  ) -> Result<Self, GraphError> {
+    if config.blame_backend == BlameBackend::Native && !cfg!(feature = "native-blame") {
+      return Err(GraphError::Other(anyhow::anyhow!(
+        "the native blame backend needs the native-blame feature"
+      )));
+    }
+    if config.blame_backend == BlameBackend::Native {
+      // better refused than blamed differently from what was asked for
+      let unsupported: Vec<&str> = [
+        (!config.blame_args.is_empty(), "extra blame arguments"),
+        (config.ignore_whitespace, "ignoring whitespace"),
+        (config.detect_moves, "detecting moved lines"),
+        (config.detect_copies, "detecting copied lines"),
+        (!config.ignore_revs.is_empty() || config.ignore_revs_file.is_some(), "ignoring revisions"),
+      ]
+      .into_iter()
+      .filter_map(|(set, option)| set.then_some(option))
+      .collect();
+      if !unsupported.is_empty() {
+        return Err(GraphError::Other(anyhow::anyhow!(
+          "the native blame backend doesn't support {}",
+          unsupported.join(", ")
+        )));
+      }
+    }
+    repo.object_cache_size(config.object_cache_size);
+    let work_tree = work_tree.or_else(|| repo.workdir().map(ToOwned::to_owned));
+    let git = blame::native_git_blame::Git {
+      binary: config.git_binary.clone(),
+      git_dir: repo.path().to_owned(),
+      blame_args: config.git_blame_args(work_tree.as_deref()),
+      work_tree,
+    };
+
+    let mailmap = repo.open_mailmap();
+    let blame_cache = blame::BlameCache::new(config.blame_cache_capacity);
+    let max_blame_processes = config.max_blame_processes;
+    let max_locating_tasks = config.max_locating_tasks;
+    let inner = Arc::new(InnerGraph {
+      repo: repo.into_sync(),
+      git,
+      repos: std::sync::Mutex::new(Vec::new()),
+      config,
+      disk_cache,
+      blame_cache,
+      blame_pool: Arc::new(blame::BlamePool::new(max_blame_processes)),
+      locating: tokio::sync::Semaphore::new(max_locating_tasks),
+      #[cfg(test)]
+      locating_peak: AtomicUsize::new(0),
+      mailmap,
+      commit_meta: DashMap::new(),
+      history_window: std::sync::Mutex::new(None),
+      watchers: std::sync::Mutex::new(Vec::new()),
+      pruning: std::sync::RwLock::new(()),
+      caching: std::sync::Mutex::new(CachingQueue::default()),
+      closed: AtomicBool::new(false),
+      refreshes: AtomicUsize::new(0),
+      blame_tasks: std::sync::Mutex::new(Vec::new()),
+      counters: Counters::default(),
+      outstanding: Outstanding {
+        count: AtomicUsize::new(0),
+        notify: tokio::sync::Notify::new(),
+      },
+    });
+
+    Ok(LocalGitGraph { inner })
+  }
+
+  /// Stops taking new blame and caching work, waits up to `timeout` for what's already running to
+  /// finish writing, then closes the cache. If tasks are still running at the deadline the cache
+  /// is left open for them and an error is returned.
+  pub(crate) async fn shutdown(&self, timeout: time::Duration) -> anyhow::Result<()> {
+    self.inner.closed.store(true, Ordering::Release);
+    self.inner.watchers.lock().unwrap().clear();
+    if tokio::time::timeout(timeout, self.inner.wait_for_tasks()).await.is_err() {
+      anyhow::bail!(
+        "{} background tasks still running after {:?}",
+        self.inner.outstanding.count.load(Ordering::Acquire),
+        timeout
+      );
+    }
+    self.inner.disk_cache.close()
+  }
+
+  /// Closes the graph right away, unlike `shutdown`: running blames are aborted along with their
+  /// git processes and fail, queued caching work is dropped and the cache is closed. Work already
+  /// caching a commit can't be interrupted; it fails on the closed cache. Every later call fails
+  /// with `Closed`. Closing twice is fine.
+  pub(crate) fn close(&self) -> Result<(), GraphError> {
+    self.inner.closed.store(true, Ordering::Release);
+    self.inner.watchers.lock().unwrap().clear();
+    self.inner.caching.lock().unwrap().pending.clear();
+    for (task, blame) in self.inner.blame_tasks.lock().unwrap().drain(..) {
+      task.abort();
+      if !blame.is_ready() {
+        blame.mark_as_failed("the graph was closed".into());
+      }
+    }
+    self.inner.disk_cache.close().map_err(GraphError::Cache)
+  }
+
+  pub(crate) async fn related_files(
+    &self,
+    blame: &Arc<blame::LazyBlame>,
+    lineno: usize,
+    options: &QueryOptions,
+  ) -> anyhow::Result<Vec<Candidate>> {
+    Ok(self.query_related_files(blame, lineno, options).await?.candidates)
+  }
+
+  /// `related_files` for the lines in `lines`, see `query_related_lines`.
+  pub(crate) async fn related_files_in_range(
+    &self,
+    blame: &Arc<blame::LazyBlame>,
+    lines: RangeInclusive<usize>,
+    options: &QueryOptions,
+  ) -> anyhow::Result<Vec<Candidate>> {
+    Ok(self.query_related_lines(blame, lines, options).await?.candidates)
+  }
+
+  /// `related_files_in_range` over a symbol's lines, `start_line` to `end_line` inclusive in
+  /// either order, as a language server or other tool found them, labeled with `name`.
+  pub(crate) async fn related_files_for_symbol(
+    &self,
+    blame: &Arc<blame::LazyBlame>,
+    name: String,
+    start_line: usize,
+    end_line: usize,
+    options: &QueryOptions,
+  ) -> anyhow::Result<SymbolRelatedFiles> {
+    let lines = start_line.min(end_line)..=start_line.max(end_line);
+    let candidates = self.related_files_in_range(blame, lines, options).await?;
+    Ok(SymbolRelatedFiles { name, candidates })
+  }
+
+  /// Like `related_files`, also reporting which commits it had to skip for not being cached yet.
+  pub(crate) async fn query_related_files(
+    &self,
+    blame: &Arc<blame::LazyBlame>,
+    lineno: usize,
+    options: &QueryOptions,
+  ) -> anyhow::Result<RelatedFiles> {
+    self.query_related_lines(blame, lineno..=lineno, options).await
+  }
+
+  /// Files related to a selection of lines. The window spans every chunk the selection covers,
+  /// each counted once, plus `chunk_range / 2` chunks on either side; weight decays with the
+  /// distance from the selection rather than from a single line.
+  pub(crate) async fn query_related_lines(
+    &self,
+    blame: &Arc<blame::LazyBlame>,
+    lines: RangeInclusive<usize>,
+    options: &QueryOptions,
+  ) -> anyhow::Result<RelatedFiles> {
+    self.stream_related_lines(blame, lines, options, &mut |_| {}).await
+  }
+
+  /// `query_related_lines`, handing each direct candidate to `on_located` as soon as its
+  /// locations are known, in the order they resolve. Those candidates carry their unscaled
+  /// weight and no `normalized_weight`; the returned list is the final ranking.
+  pub(crate) async fn stream_related_lines(
+    &self,
+    blame: &Arc<blame::LazyBlame>,
+    lines: RangeInclusive<usize>,
+    options: &QueryOptions,
+    on_located: &mut (dyn FnMut(&Candidate) + Send),
+  ) -> anyhow::Result<RelatedFiles> {
+    let started = time::Instant::now();
+    let related = self.locate_related_lines(blame, lines, options, None, on_located).await;
+    let counters = &self.inner.counters;
+    counters.query_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    Counters::count(&counters.queries);
+    related
+  }
+
+  /// `familiar` is what `paths_touched_by` found for `QueryOptions::unfamiliar_to`, handed down
+  /// by `expand` so nested queries don't gather it again.
+  async fn locate_related_lines(
+    &self,
+    blame: &Arc<blame::LazyBlame>,
+    lines: RangeInclusive<usize>,
+    options: &QueryOptions,
+    familiar: Option<Arc<HashSet<u32>>>,
+    on_located: &mut (dyn FnMut(&Candidate) + Send),
+  ) -> anyhow::Result<RelatedFiles> {
+    self.inner.ensure_open()?;
+    if options.cancellation.as_ref().is_some_and(|c| c.is_cancelled()) {
+      return Err(GraphError::Cancelled.into());
+    }
+    blame_succeeded(blame)?;
+    check_line_range(blame, &lines)?;
+    anyhow::ensure!(
+      blame.covers(&lines),
+      "lines {}..={} of '{}' weren't blamed, only {:?}",
+      lines.start(),
+      lines.end(),
+      blame.file_path,
+      blame.lines
+    );
+    let blame_lines = blame.lines();
+    let inner = self.inner.clone();
+    let config = &inner.config;
+    let (first, last, window) = query_window(config, &blame_lines, &lines);
+    let mut candidate_files: HashMap<u32, Candidate> = HashMap::new();
+
+    let mut interesting_shas: HashSet<ObjectId> = HashSet::new();
+    let mut uncached_commits: Vec<ObjectId> = Vec::new();
+    let excluded = match &options.exclude_recent {
+      Some(exclude) => self.recent_commits(exclude)?,
+      None => HashSet::new(),
+    };
+    let authors: Option<Vec<BString>> = options
+      .authors
+      .as_ref()
+      .map(|authors| authors.iter().map(|email| inner.canonical_email(email.as_ref())).collect());
+
+    #[cfg(feature = "trace")]
+    let collecting = options.trace.as_ref().map(|t| t.span("cache", "collect commits", 0));
+    let now = time::SystemTime::now()
+      .duration_since(time::UNIX_EPOCH)
+      .map_or(0, |since| since.as_secs() as i64);
+    for rng in window {
+      let blame_root = &blame_lines[rng as usize];
+      let dist_from_search = max(max(first - rng, rng - last), 0) as f32;
+      // there's no commit to look up, and it can never be cached
+      if blame_root.is_uncommitted() || excluded.contains(&blame_root.commit_id) {
+        continue;
+      }
+
+      if let Some(authors) = &authors {
+        let meta = inner.commit_meta(&blame_root.commit_id)?;
+        if !authors.iter().any(|email| email.eq_ignore_ascii_case(&meta.author_email)) {
+          continue;
+        }
+      }
+
+      if let Some(commit) = inner.disk_cache.cached_commit(&blame_root.commit_id)? {
+        interesting_shas.insert(blame_root.commit_id);
+        let size_factor = if options.weight_by_diff_size {
+          inner.diff_size_factor(blame_root)?
+        } else {
+          1.0
+        };
+        let recency = match options.recency_half_life {
+          Some(half_life) => recency_factor(inner.commit_meta(&blame_root.commit_id)?.committed_at, now, half_life),
+          None => 1.0,
+        };
+
+        for path_id in commit.changed_paths.iter() {
+          let candidate_id = if options.merge_renames {
+            inner.disk_cache.resolve_path_id(*path_id)?
+          } else {
+            *path_id
+          };
+
+          let entry = candidate_files.entry(candidate_id).or_insert_with(|| {
+            Candidate{
+              path: None,
+              locations: vec![],
+              touched_lines: 0,
+              weight: 0.0,
+              path_id: candidate_id,
+              commit: blame_root.commit_id,
+              commit_path_id: *path_id,
+              contributions: vec![],
+              explaining_commits: vec![],
+              calibrated_score: None,
+              normalized_weight: 0.0,
+              via: None,
+              attributions: vec![],
+              commit_meta: None,
+              partial: false,
+            }
+          });
+
+          // blaming under the current name needs a commit from after the rename
+          if entry.commit_path_id != candidate_id && *path_id == candidate_id {
+            entry.commit = blame_root.commit_id;
+            entry.commit_path_id = *path_id;
+          }
+
+          let contribution =
+            config.commit_contribution(dist_from_search, options.weight_floor) * size_factor * recency;
+          entry.weight += contribution;
+          match entry.contributions.iter_mut().find(|(c, _)| *c == blame_root.commit_id) {
+            Some((_, w)) => *w += contribution,
+            None => entry.contributions.push((blame_root.commit_id, contribution)),
+          }
+        }
+      } else if !uncached_commits.contains(&blame_root.commit_id)
+        && inner.within_history_bounds(&blame_root.commit_id)?
+      {
+        // commits past the history bounds never get cached, so there's nothing to wait for
+        uncached_commits.push(blame_root.commit_id);
+      }
+    }
+
+    #[cfg(feature = "trace")]
+    drop(collecting);
+
+    if candidate_files.is_empty() {
+      return Ok(RelatedFiles {
+        candidates: Vec::new(),
+        uncached_commits,
+        truncated: false,
+      });
+    }
+
+    // the commits near the line usually touched the file itself too, which is no news. A path
+    // the cache never saw isn't among any commit's changed paths, so there's nothing to drop.
+    let own_path_id = match inner.disk_cache.path_id(blame.file_path.as_ref())? {
+      Some(path_id) => Some(inner.disk_cache.resolve_path_id(path_id)?),
+      None => None,
+    };
+    let mut ranked = Vec::with_capacity(candidate_files.len());
+    for (path_id, candidate) in candidate_files {
+      if Some(inner.disk_cache.resolve_path_id(path_id)?) != own_path_id {
+        ranked.push((path_id, candidate));
+      }
+    }
+    let mut candidate_files = ranked;
+    candidate_files.sort_by(|a, b| heaviest_first(&a.1, &b.1));
+    if let Some(filter) = &options.paths {
+      // before truncating and locating, so filtered files neither take places nor cost a blame
+      let path_ids: Vec<u32> = candidate_files.iter().map(|(path_id, _)| *path_id).collect();
+      let paths = inner.disk_cache.resolve_paths(&path_ids)?;
+      candidate_files.retain(|(path_id, _)| paths.get(path_id).is_some_and(|path| filter.allows(path.as_ref())));
+    }
+    if options.only_existing {
+      // before truncating, so deleted files don't take the places of existing ones
+      let path_ids: Vec<u32> = candidate_files.iter().map(|(path_id, _)| *path_id).collect();
+      let existing = inner.existing_paths(&inner.disk_cache.resolve_paths(&path_ids)?)?;
+      candidate_files.retain(|(path_id, _)| existing.contains(path_id));
+    }
+    let familiar = match (familiar, &options.unfamiliar_to) {
+      (Some(familiar), _) => Some(familiar),
+      (None, Some(email)) => Some(Arc::new(self.paths_touched_by(email.as_ref())?)),
+      (None, None) => None,
+    };
+    if let Some(familiar) = &familiar {
+      // before truncating, so files the author knows don't take the places of ones they don't
+      let mut unfamiliar = Vec::with_capacity(candidate_files.len());
+      for (path_id, candidate) in candidate_files {
+        if !familiar.contains(&inner.disk_cache.resolve_path_id(path_id)?) {
+          unfamiliar.push((path_id, candidate));
+        }
+      }
+      candidate_files = unfamiliar;
+    }
+    let mut truncated = candidate_files.len() > config.max_candidates;
+    candidate_files.truncate(config.max_candidates);
+
+    #[cfg(feature = "trace")]
+    let locating = options.trace.as_ref().map(|t| t.span("query", "locate candidates", 0));
+    let mut joinset = tokio::task::JoinSet::new();
+    let interesting_shas = Arc::new(interesting_shas);
+
+    let path_ids: Vec<u32> = candidate_files.iter().map(|(path_id, _)| *path_id).collect();
+    let mut paths = inner.disk_cache.resolve_paths(&path_ids)?;
+    for (index, (path_id, w)) in candidate_files.iter_mut().enumerate() {
+      if let Some(path) = paths.remove(path_id) {
+        // the file may have had another name at the commit we blame it at
+        let commit_path = inner.disk_cache.path(w.commit_path_id)?;
+        w.path = Some(path);
+
+        let Some(commit_path) = commit_path else {
+          continue;
+        };
+        if let Some(locations) = inner.cached_locations(w)? {
+          if options.attribute_locations {
+            w.attributions = attributions(locations.iter().map(|loc| (loc.clone(), w.commit)).collect());
+          }
+          w.locations = merge_ranges(locations);
+          w.touched_lines = w.locations.iter().map(|loc| loc.end - loc.start).sum();
+          on_located(w);
+          continue;
+        }
+        let inner = inner.clone();
+        let interesting_shas = interesting_shas.clone();
+        let commit_sha = w.commit;
+        #[cfg(feature = "trace")]
+        let trace = options.trace.clone();
+        joinset.spawn(async move {
+          #[cfg(feature = "trace")]
+          let (lane, _span) = match &trace {
+            Some(t) => {
+              let lane = t.lane();
+              (lane, Some(t.span("locate", format!("locate {}", commit_path), lane)))
+            }
+            None => (0, None),
+          };
+          // waiting here rather than before spawning lets the query finish setting up the rest
+          #[cfg(feature = "trace")]
+          let waiting = trace.as_ref().map(|t| t.span("locate", "wait for slot", lane));
+          let _permit = inner.locating.acquire().await.expect("the semaphore is never closed");
+          #[cfg(feature = "trace")]
+          drop(waiting);
+          #[cfg(feature = "trace")]
+          let _blaming = trace.as_ref().map(|t| t.span("blame", format!("blame {}", commit_path), lane));
+          #[cfg(test)]
+          inner
+            .locating_peak
+            .fetch_max(inner.config.max_locating_tasks - inner.locating.available_permits(), Ordering::SeqCst);
+          (
+            index,
+            inner
+              .find_related_locations(Some(commit_sha), commit_path.as_ref(), &interesting_shas)
+              .await,
+          )
+        });
+      }
+    }
+
+    loop {
+      let res = match &options.cancellation {
+        Some(cancellation) => tokio::select! {
+          biased;
+          // dropping the set aborts the candidates still being located
+          () = cancellation.cancelled() => return Err(GraphError::Cancelled.into()),
+          res = joinset.join_next() => res,
+        },
+        None => joinset.join_next().await,
+      };
+      let Some(res) = res else {
+        break;
+      };
+      if let (index, Some((related_locs, partial))) = res? {
+        let w = &mut candidate_files[index].1;
+        w.partial = partial;
+        w.locations = merge_ranges(related_locs.iter().map(|(loc, _)| loc.clone()).collect());
+        if options.attribute_locations {
+          w.attributions = attributions(related_locs);
+        }
+        w.touched_lines = w.locations.iter().map(|loc| loc.end - loc.start).sum();
+        if w.touched_lines > 0 {
+          on_located(w);
+        }
+      }
+    }
+    // a candidate whose blame holds none of the interesting commits' lines, like a file they
+    // only deleted lines from, has nothing to show. Dropped before scaling, so that when no
+    // candidate has lines left there's no zero to divide by.
+    candidate_files.retain(|(_, candidate)| candidate.touched_lines > 0);
+
+    #[cfg(feature = "trace")]
+    drop(locating);
+    #[cfg(feature = "trace")]
+    let ranking = options.trace.as_ref().map(|t| t.span("query", "rank", 0));
+
+    let largest_touched_file = candidate_files
+      .iter()
+      .map(|x| x.1.touched_lines)
+      .max()
+      // no candidates left, like when the only cached commit changed just the blamed file
+      .unwrap_or(1) as f32;
+
+    let half_life = options.recency_half_life.unwrap_or(crate::config::DEFAULT_RECENCY_HALF_LIFE);
+    for (_, candidate) in candidate_files.iter_mut() {
+      candidate.calibrated_score = options.calibration.map(|c| c.score(candidate.weight));
+      let blame = candidate.weight * candidate.touched_lines as f32 / largest_touched_file;
+      let cochange = candidate.contributions.len() as f32;
+      let mut recency = 0.0f32;
+      if config.recency_coefficient != 0.0 {
+        for (commit, _) in &candidate.contributions {
+          recency = recency.max(recency_factor(inner.commit_meta(commit)?.committed_at, now, half_life));
+        }
+      }
+      candidate.weight = config.blame_coefficient * blame
+        + config.cochange_coefficient * cochange
+        + config.recency_coefficient * recency;
+      if let Some(cutoff) = options.explain_cutoff {
+        candidate.explaining_commits = explaining_commits(&candidate.contributions, cutoff);
+      }
+    }
+
+    // scaling by touched lines changed the weights
+    candidate_files.sort_by(|a, b| heaviest_first(&a.1, &b.1));
+    let mut candidates: Vec<Candidate> = candidate_files.into_iter().map(|cand| cand.1).collect();
+
+    #[cfg(feature = "trace")]
+    drop(ranking);
+
+    if options.depth > 1 {
+      #[cfg(feature = "trace")]
+      let _expanding = options.trace.as_ref().map(|t| t.span("query", "expand", 0));
+      truncated |= self
+        .expand(blame.file_path.as_ref(), &mut candidates, &mut uncached_commits, options, familiar)
+        .await?;
+    }
+
+    if options.commit_info {
+      for candidate in candidates.iter_mut().filter(|c| c.commit_meta.is_none()) {
+        candidate.commit_meta = Some(inner.commit_meta(&candidate.commit)?);
+      }
+    }
+
+    let top_weight = candidates.first().map_or(0.0, |c| c.weight);
+    if top_weight > 0.0 {
+      for candidate in candidates.iter_mut() {
+        candidate.normalized_weight = (candidate.weight / top_weight).clamp(0.0, 1.0);
+      }
+    }
+    Ok(RelatedFiles {
+      candidates,
+      uncached_commits,
+      truncated,
+    })
+  }
+
+  /// Adds what's related to the strongest `candidates` at `options.depth - 1`, weighted by
+  /// `GraphConfig::expansion_decay` and the seed's own weight. Files already present keep their
+  /// direct weight, and `queried`, the file the query is about, stays out like it does of the
+  /// direct results. Returns whether `GraphConfig::max_candidates` cut off any related files.
+  async fn expand(
+    &self,
+    queried: &BStr,
+    candidates: &mut Vec<Candidate>,
+    uncached_commits: &mut Vec<ObjectId>,
+    options: &QueryOptions,
+    familiar: Option<Arc<HashSet<u32>>>,
+  ) -> anyhow::Result<bool> {
+    let inner = &self.inner;
+    let nested_options = QueryOptions {
+      depth: options.depth - 1,
+      ..options.clone()
+    };
+
+    let mut expanded: Vec<Candidate> = Vec::new();
+    let mut truncated = false;
+    for seed in candidates.iter().take(inner.config.expansion_seeds) {
+      let (Some(seed_path), Some(location)) = (&seed.path, seed.locations.first()) else {
+        continue;
+      };
+      let Some(commit_path) = inner.disk_cache.path(seed.commit_path_id)? else {
+        continue;
+      };
+      // usually already loaded to find the seed's locations
+      let Ok(blame) = inner.load_blame(Some(seed.commit), commit_path.as_ref(), false).await else {
+        continue;
+      };
+      inner.wait_to_locate(&blame).await;
+
+      let lineno = location.start as usize;
+      let nested = Box::pin(self.locate_related_lines(&blame, lineno..=lineno, &nested_options, familiar.clone(), &mut |_| {})).await?;
+      truncated |= nested.truncated;
+      for commit in nested.uncached_commits {
+        if !uncached_commits.contains(&commit) {
+          uncached_commits.push(commit);
+        }
+      }
+      for mut related in nested.candidates {
+        // a seed is usually related to the queried file right back
+        if related.path.as_ref().is_some_and(|path| path == queried) || candidates.iter().any(|c| c.path == related.path) {
+          continue;
+        }
+        related.weight = seed.weight * inner.config.expansion_decay * related.normalized_weight;
+        related.via = Some(seed_path.clone());
+        // reachable through several seeds: keep the strongest link
+        match expanded.iter_mut().find(|c| c.path == related.path) {
+          Some(existing) if existing.weight < related.weight => *existing = related,
+          Some(_) => {}
+          None => expanded.push(related),
+        }
+      }
+    }
+
+    candidates.extend(expanded);
+    candidates.sort_by(heaviest_first);
+    truncated |= candidates.len() > inner.config.max_candidates;
+    candidates.truncate(inner.config.max_candidates);
+    Ok(truncated)
+  }
+
+  /// A hash of everything a query at `lineno` starts from: which commits sit in the chunks
+  /// around the line, how far away each is, which of them are cached, and the graph's tuning.
+  /// Results cached under an equal signature are still what a query would return, as long as
+  /// the same query options are used. Only reads the blame and the cache; stable across runs.
+  pub(crate) fn neighborhood_signature(&self, blame: &blame::LazyBlame, lineno: usize) -> anyhow::Result<u64> {
+    self.inner.ensure_open()?;
+    check_line_range(blame, &(lineno..=lineno))?;
+    let config = &self.inner.config;
+    let blame_lines = blame.lines();
+    let (first, last, window) = query_window(config, &blame_lines, &(lineno..=lineno));
+    let commits: Vec<ObjectId> = window.clone().map(|rng| blame_lines[rng as usize].commit_id).collect();
+    let cached = self.inner.disk_cache.cached_commits(&commits)?;
+
+    let mut hasher = Fnv1a::new();
+    hasher.write(&config.chunk_range.to_le_bytes());
+    hasher.write(&(config.max_candidates as u64).to_le_bytes());
+    hasher.write(&config.base_weight.to_le_bytes());
+    hasher.write(&config.distance_decay.to_le_bytes());
+    for coefficient in [config.blame_coefficient, config.cochange_coefficient, config.recency_coefficient] {
+      hasher.write(&coefficient.to_le_bytes());
+    }
+    hasher.write(&[
+      config.first_parent_only as u8,
+      config.cache_line_ranges as u8,
+      config.skip_binary_files as u8,
+      config.skip_symlinks as u8,
+    ]);
+    for (rng, commit) in window.zip(commits) {
+      let dist = max(max(first - rng, rng - last), 0);
+      hasher.write(&(dist as u64).to_le_bytes());
+      hasher.write(commit.as_slice());
+      hasher.write(&[cached.contains(&commit) as u8]);
+    }
+    Ok(hasher.0)
+  }
+
+  /// Calls `notify` once every commit in `commits` has finished caching in the background, right
+  /// away if they all already have. Commits nothing is caching keep it waiting until shutdown,
+  /// which drops pending notifications.
+  pub(crate) fn watch_commits(&self, commits: &[ObjectId], notify: impl FnOnce() + Send + 'static) -> anyhow::Result<()> {
+    {
+      let mut watchers = self.inner.watchers.lock().unwrap();
+      let cached = self.inner.disk_cache.cached_commits(commits)?;
+      let pending: HashSet<ObjectId> = commits.iter().filter(|commit| !cached.contains(*commit)).copied().collect();
+      if !pending.is_empty() {
+        watchers.push(CommitWatcher {
+          pending,
+          notify: Box::new(notify),
+        });
+        return Ok(());
+      }
+    }
+    notify();
+    Ok(())
+  }
+
+  /// Blames `filepath` as of `revision`, or HEAD when `None`. HEAD is resolved to its commit up
+  /// front, so the blame and every query on it see the same commit even if HEAD moves meanwhile,
+  /// and a later call after HEAD moved gets a fresh blame.
+  /// Fails with `PathNotTracked` if the file isn't in that commit.
+  pub async fn blame(&self, filepath: &BStr, revision: Option<ObjectId>) -> Result<Arc<blame::LazyBlame>, GraphError> {
+    self.inner.ensure_open()?;
+    let filepath = self.inner.repo_relative_path(filepath)?;
+    let filepath = filepath.as_bstr();
+    let revision = match revision {
+      Some(revision) => revision,
+      None => self.head_commit()?,
+    };
+    if self.inner.blame_key(Some(revision), filepath)?.1.is_none() {
+      return Err(GraphError::PathNotTracked {
+        path: filepath.to_owned(),
+        revision,
+      });
+    }
+    Ok(self.inner.load_blame(Some(revision), filepath, true).await?)
+  }
+
+  /// Files related to `lineno`, 1-based, of `filepath` as of `revision` or HEAD, for a one-off
+  /// query on a file nobody opened. Lines are counted like git blame does, ending at `\n`. Unless the file's full blame is cached, only the
+  /// `GraphConfig::blame_window_lines` lines either side of `lineno` are blamed, and the query's
+  /// window stops where they do. Waits for the blame.
+  pub(crate) async fn related_files_at(
+    &self,
+    filepath: &BStr,
+    revision: Option<ObjectId>,
+    lineno: usize,
+    options: &QueryOptions,
+  ) -> Result<RelatedFiles, GraphError> {
+    self.inner.ensure_open()?;
+    let filepath = self.inner.repo_relative_path(filepath)?;
+    let filepath = filepath.as_bstr();
+    let revision = match revision {
+      Some(revision) => revision,
+      None => self.head_commit()?,
+    };
+    let Some(blob) = self.inner.blame_key(Some(revision), filepath)?.1 else {
+      return Err(GraphError::PathNotTracked {
+        path: filepath.to_owned(),
+        revision,
+      });
+    };
+    let line_count = {
+      let repo = self.inner.thread_local_repo();
+      let blob = repo.find_object(blob).map_err(anyhow::Error::from)?;
+      blob.data.lines_with_terminator().count()
+    };
+    if !(1..=line_count + 1).contains(&lineno) {
+      return Err(GraphError::LineOutOfRange {
+        path: filepath.to_owned(),
+        line: lineno,
+        line_count: line_count as u32,
+      });
+    }
+    // the line after a trailing newline queries the last one
+    let lineno = lineno.min(line_count.max(1));
+    let blame = if line_count == 0 {
+      self.inner.load_blame(Some(revision), filepath, true).await?
+    } else {
+      let window = self.inner.config.blame_window_lines as usize;
+      let lines = lineno.saturating_sub(window).max(1)..(lineno + window).min(line_count) + 1;
+      self
+        .inner
+        .load_blame_window(revision, filepath, lines.start as u32..lines.end as u32)?
+    };
+    blame.wait_for_ready().await;
+    Ok(self.query_related_files(&blame, lineno, options).await?)
+  }
+
+  /// Files related to a set of changed hunks, like a pull request's, in one ranking. Each
+  /// `(path, lines)` is queried like `related_files_in_range` on `path` as of `revision` or HEAD;
+  /// a file several hunks find gets their weights summed and their locations united. Files with
+  /// hunks of their own are left out. Waits for the blames.
+  pub(crate) async fn related_files_for_hunks(
+    &self,
+    hunks: &[(BString, RangeInclusive<usize>)],
+    revision: Option<ObjectId>,
+    options: &QueryOptions,
+  ) -> Result<RelatedFiles, GraphError> {
+    self.inner.ensure_open()?;
+    let revision = match revision {
+      Some(revision) => revision,
+      None => self.head_commit()?,
+    };
+    let mut hunks_by_path = Vec::with_capacity(hunks.len());
+    for (path, lines) in hunks {
+      hunks_by_path.push((self.inner.repo_relative_path(path.as_bstr())?, lines.clone()));
+    }
+    let hunks = hunks_by_path;
+    let changed: HashSet<&BStr> = hunks.iter().map(|(path, _)| path.as_bstr()).collect();
+    let mut candidates: Vec<Candidate> = Vec::new();
+    let mut uncached_commits: Vec<ObjectId> = Vec::new();
+    let mut truncated = false;
+    for (path, lines) in &hunks {
+      let blame = self.blame(path.as_bstr(), Some(revision)).await?;
+      self.ensure_ready(&blame, true).await?;
+      let related = self.query_related_lines(&blame, lines.clone(), options).await?;
+      truncated |= related.truncated;
+      for commit in related.uncached_commits {
+        if !uncached_commits.contains(&commit) {
+          uncached_commits.push(commit);
+        }
+      }
+      for candidate in related.candidates {
+        if candidate.path.as_ref().is_some_and(|path| changed.contains(path.as_bstr())) {
+          continue;
+        }
+        match candidates.iter_mut().find(|existing| existing.path == candidate.path) {
+          Some(existing) => existing.absorb(candidate),
+          None => candidates.push(candidate),
+        }
+      }
+    }
+
+    candidates.sort_by(heaviest_first);
+    truncated |= candidates.len() > self.inner.config.max_candidates;
+    candidates.truncate(self.inner.config.max_candidates);
+    let top_weight = candidates.first().map_or(0.0, |c| c.weight);
+    for candidate in candidates.iter_mut() {
+      if top_weight > 0.0 {
+        candidate.normalized_weight = (candidate.weight / top_weight).clamp(0.0, 1.0);
+      }
+      if let Some(cutoff) = options.explain_cutoff {
+        candidate.explaining_commits = explaining_commits(&candidate.contributions, cutoff);
+      }
+    }
+    Ok(RelatedFiles {
+      candidates,
+      uncached_commits,
+      truncated,
+    })
+  }
+
+  /// `blame` for several files at the same commit, `revision` or HEAD resolved once. Every blame
+  /// is started, or found already cached, before this returns; a file that can't be blamed gets
+  /// its own error without failing the others.
+  pub(crate) async fn blame_all(
+    &self,
+    filepaths: &[BString],
+    revision: Option<ObjectId>,
+  ) -> Result<Vec<Result<Arc<blame::LazyBlame>, GraphError>>, GraphError> {
+    self.inner.ensure_open()?;
+    let revision = match revision {
+      Some(revision) => revision,
+      None => self.head_commit()?,
+    };
+    let mut blames = Vec::with_capacity(filepaths.len());
+    for filepath in filepaths {
+      // only starts the blame, so the next one isn't kept waiting
+      blames.push(self.blame(filepath.as_bstr(), Some(revision)).await);
+    }
+    Ok(blames)
+  }
+
+  /// Starts blaming `filepaths` at HEAD in the background, so opening them later finds their
+  /// blames under way or done. Returns without waiting; a file that can't be blamed is skipped,
+  /// its error left for opening it to report. Files past `GraphConfig::blame_cache_capacity`
+  /// aren't started, as they'd only evict the first ones.
+  pub(crate) fn prefetch(&self, filepaths: Vec<BString>) -> Result<(), GraphError> {
+    self.inner.ensure_open()?;
+    let revision = self.head_commit()?;
+    let graph = self.clone();
+    let capacity = self.inner.config.blame_cache_capacity;
+    tokio::spawn(async move {
+      for filepath in filepaths.into_iter().take(capacity) {
+        let _ = graph.blame(filepath.as_bstr(), Some(revision)).await;
+      }
+    });
+    Ok(())
+  }
+
+  /// `blame` for callers outside any async context, like a plain worker thread. Runs on
+  /// `runtime`, which keeps the blame going in the background after this returns, so it has to
+  /// be a multi-thread runtime: a current-thread one only makes progress inside its own
+  /// `block_on`. Panics if called from within a runtime.
+  pub(crate) fn blame_blocking(
+    &self,
+    runtime: &tokio::runtime::Handle,
+    filepath: &BStr,
+    revision: Option<ObjectId>,
+  ) -> Result<Arc<blame::LazyBlame>, GraphError> {
+    ensure_multi_thread(runtime)?;
+    runtime.block_on(self.blame(filepath, revision))
+  }
+
+  /// `related_files` for callers outside any async context, waiting for the blame first. See
+  /// `blame_blocking` for the requirements on `runtime`.
+  pub(crate) fn find_similar_files_blocking(
+    &self,
+    runtime: &tokio::runtime::Handle,
+    blame: &Arc<blame::LazyBlame>,
+    lineno: usize,
+    options: &QueryOptions,
+  ) -> anyhow::Result<Vec<Candidate>> {
+    ensure_multi_thread(runtime)?;
+    runtime.block_on(async {
+      blame.wait_for_ready().await;
+      self.related_files(blame, lineno, options).await
+    })
+  }
+
+  /// Tells the graph the repository changed, like after a commit or a checkout: cached blames
+  /// that followed HEAD to a file whose content HEAD no longer has are dropped, so the next
+  /// request for one blames anew. Blames made at a given commit stay right whatever HEAD does,
+  /// and so do those of files the new HEAD left alone. Holders of a blame that follows HEAD see
+  /// `refreshes` go up and ask for it again.
+  pub(crate) fn refresh(&self) {
+    let repo = self.inner.thread_local_repo();
+    let head_tree = repo.head_commit().ok().and_then(|commit| commit.tree().ok());
+    self.inner.blame_cache.retain(|(path, blob), blame| {
+      let at_head = || {
+        let entry = head_tree.as_ref()?.lookup_entry(path.split_str("/")).ok()??;
+        entry.mode().is_blob_or_symlink().then(|| entry.object_id())
+      };
+      blame.revision.is_some() || at_head() == *blob
+    });
+    self.inner.refreshes.fetch_add(1, Ordering::AcqRel);
+  }
+
+  /// Cache hit rates and query timings since the graph was opened.
+  pub(crate) fn stats(&self) -> GraphStats {
+    self.inner.counters.snapshot()
+  }
+
+  /// How many times `refresh` was called, to tell whether a blame taken earlier may be stale.
+  pub(crate) fn refreshes(&self) -> usize {
+    self.inner.refreshes.load(Ordering::Acquire)
+  }
+
+  /// The commit HEAD points at, attached to a branch or detached. Fails on a branch without
+  /// commits yet.
+  pub(crate) fn head_commit(&self) -> Result<ObjectId, GraphError> {
+    let repo = self.inner.thread_local_repo();
+    let head = repo.head().map_err(anyhow::Error::from)?;
+    if let gix::head::Kind::Unborn(branch) = &head.kind {
+      return Err(GraphError::RevisionNotFound {
+        spec: "HEAD".into(),
+        reason: format!("HEAD is on branch '{}', which has no commits yet", branch.shorten()),
+      });
+    }
+    let id = repo.head_commit().map_err(anyhow::Error::from)?.id;
+    Ok(id)
+  }
+
+  /// Waits for `blame` to finish loading, or fails with `NotReady` if it hasn't and `wait` is
+  /// false. Queries on a blame still loading only see the lines found so far. Fails with
+  /// `BlameFailed` if git blame did.
+  pub(crate) async fn ensure_ready(&self, blame: &blame::LazyBlame, wait: bool) -> Result<(), GraphError> {
+    if !blame.is_ready() {
+      if !wait {
+        return Err(GraphError::NotReady {
+          path: blame.file_path.clone(),
+        });
+      }
+      blame.wait_for_ready().await;
+    }
+    blame_succeeded(blame)
+  }
+
+  /// The diff `commit` made to the file currently at `path`, for showing why a candidate is
+  /// connected. The file may have had another name at `commit`; renames the cache knows about are
+  /// followed. `None` if the commit didn't touch the file.
+  pub(crate) fn candidate_diff(&self, path: &BStr, commit: ObjectId) -> anyhow::Result<Option<FileDiff>> {
+    self.inner.ensure_open()?;
+    let cache = &self.inner.disk_cache;
+    let repo = self.inner.thread_local_repo();
+    let commit = repo.find_commit(commit)?;
+    let tree = commit.tree()?;
+    let parent_tree = match commit.parent_ids().next() {
+      Some(parent) => repo.find_commit(parent)?.tree()?,
+      None => repo.empty_tree(),
+    };
+
+    let is_candidate = |location: &BStr| -> anyhow::Result<bool> {
+      if location == path {
+        return Ok(true);
+      }
+      // a path the cache never saw can't have been renamed to `path`
+      let Some(path_id) = cache.path_id(location)? else {
+        return Ok(false);
+      };
+      Ok(cache.resolve_path(path_id)?.is_some_and(|p| p == path))
+    };
+
+    let mut found: Option<(Option<BString>, Option<ObjectId>, BString, ObjectId)> = None;
+    parent_tree.changes()?.for_each_to_obtain_tree(&tree, |change| -> anyhow::Result<Action> {
+      let (old_path, old_id, location, new_id) = match change {
+        Change::Addition { location, id, entry_mode, .. } if entry_mode.is_blob() => (None, None, location, id),
+        Change::Modification { location, previous_id, id, entry_mode, .. } if entry_mode.is_blob() => {
+          (None, Some(previous_id.detach()), location, id)
+        }
+        Change::Rewrite { source_location, source_id, location, id, entry_mode, .. } if entry_mode.is_blob() => {
+          (Some(source_location.to_owned()), Some(source_id.detach()), location, id)
+        }
+        _ => return Ok(Action::Continue),
+      };
+
+      // cancelling would surface as an error, so let the rest of the diff run out
+      if found.is_none() && is_candidate(location)? {
+        found = Some((old_path, old_id, location.to_owned(), new_id.detach()));
+      }
+      Ok(Action::Continue)
+    })?;
+
+    let Some((old_path, old_id, new_path, new_id)) = found else {
+      return Ok(None);
+    };
+    let old_data = match old_id {
+      Some(id) => repo.find_object(id)?.detach().data,
+      None => Vec::new(),
+    };
+    let new_data = repo.find_object(new_id)?.detach().data;
+
+    Ok(Some(FileDiff {
+      old_path,
+      new_path,
+      hunks: diff::hunks(&old_data, &new_data, 3),
+    }))
+  }
+
+  /// The files the commit `revision` names changed, under their current names, in the order the
+  /// cache stores them. Caches the commit first if needed, so it's cheap after the first call.
+  pub(crate) fn commit_files(&self, revision: &str) -> Result<Vec<BString>, GraphError> {
+    self.inner.ensure_open()?;
+    let commit = self.resolve_revision(revision)?;
+    self.inner.load_cached_commit(&commit)?;
+    let cache = &self.inner.disk_cache;
+    let Some(cached) = cache.cached_commit(&commit)? else {
+      return Err(anyhow::anyhow!("commit {} was pruned from the cache while listing its files", commit).into());
+    };
+    let mut paths = cache.resolve_paths(&cached.changed_paths)?;
+    Ok(cached.changed_paths.iter().filter_map(|id| paths.remove(id)).collect())
+  }
+
+  /// Caches up to `max_commits` commits reachable from HEAD, newest first, so the first queries
+  /// don't have to wait for them. Already cached commits are skipped and don't count. Runs
+  /// `GraphConfig::max_caching_tasks` commits at a time and calls `on_progress(done, total)`
+  /// after each; returns how many commits it cached.
+  pub(crate) async fn prebuild_cache(
+    &self,
+    max_commits: Option<usize>,
+    on_progress: impl Fn(usize, usize),
+  ) -> anyhow::Result<usize> {
+    let inner = &self.inner;
+    inner.ensure_open()?;
+    let mut commits = Vec::new();
+    {
+      let repo = inner.thread_local_repo();
+      let walk = repo.head_id()?.ancestors().all()?;
+      for info in walk.take(max_commits.unwrap_or(usize::MAX)) {
+        commits.push(info?.id);
+      }
+    }
+    let cached = inner.disk_cache.cached_commits(&commits)?;
+    commits.retain(|commit| !cached.contains(commit));
+
+    let total = commits.len();
+    let mut pending = commits.into_iter();
+    let mut joinset = tokio::task::JoinSet::new();
+    let mut done = 0;
+    loop {
+      while joinset.len() < inner.config.max_caching_tasks.max(1) {
+        let Some(commit) = pending.next() else {
+          break;
+        };
+        let Some(task) = inner.start_task() else {
+          return Err(GraphError::Closed.into());
+        };
+        let inner = inner.clone();
+        joinset.spawn_blocking(move || {
+          let _task = task;
+          let result = inner.load_cached_commit(&commit);
+          inner.commit_settled(&commit);
+          result
+        });
+      }
+      let Some(result) = joinset.join_next().await else {
+        break;
+      };
+      result??;
+      done += 1;
+      on_progress(done, total);
+    }
+    Ok(total)
+  }
+
+  /// Every path the cache knows about, in the order they were first seen, with its current name
+  /// and whether that is still in HEAD.
+  pub(crate) fn list_paths(&self) -> anyhow::Result<Vec<PathInfo>> {
+    self.inner.ensure_open()?;
+    let cache = &self.inner.disk_cache;
+    let paths = cache.paths()?;
+    let ids: Vec<u32> = paths.iter().map(|(id, _)| *id).collect();
+    let mut current_paths = cache.resolve_paths(&ids)?;
+
+    let repo = self.inner.thread_local_repo();
+    let head_tree = repo.head_commit()?.tree()?;
+    let mut infos = Vec::with_capacity(paths.len());
+    for (id, path) in paths {
+      let current_path = current_paths.remove(&id).unwrap_or_else(|| path.clone());
+      let exists = head_tree
+        .lookup_entry(current_path.split_str("/"))?
+        .is_some_and(|entry| !entry.mode().is_tree());
+      infos.push(PathInfo {
+        id,
+        path,
+        current_path,
+        exists,
+      });
+    }
+    Ok(infos)
+  }
+
+  /// How the cache resolves `path_id`, for working out why a candidate dropped out of results.
+  pub(crate) fn debug_resolve_path(&self, path_id: u32) -> anyhow::Result<ResolvedPathId> {
+    self.inner.ensure_open()?;
+    let cache = &self.inner.disk_cache;
+    Ok(ResolvedPathId {
+      path: cache.path(path_id)?,
+      current_id: cache.resolve_path_id(path_id)?,
+      current_path: cache.resolve_path(path_id)?,
+    })
+  }
+
+  /// How many paths the cache has interned.
+  pub(crate) fn interned_path_count(&self) -> anyhow::Result<usize> {
+    self.inner.ensure_open()?;
+    Ok(self.inner.disk_cache.paths()?.len())
+  }
+
+  /// Drops cached commits no ref reaches anymore, like those of deleted branches, and the paths
+  /// only they referred to, then compacts the cache. With `keep_recent`, only that many of the
+  /// newest reachable commits stay. Caching waits meanwhile.
+  pub(crate) fn prune_cache(&self, keep_recent: Option<usize>) -> anyhow::Result<PrunedCache> {
+    self.inner.ensure_open()?;
+    let repo = self.inner.thread_local_repo();
+    let mut tips: Vec<ObjectId> = repo.head_id().map(|id| id.detach()).into_iter().collect();
+    for reference in repo.references()?.all()? {
+      let mut reference = reference.map_err(|e| anyhow::anyhow!(e))?;
+      // refs to trees or blobs lead to no commits
+      if let Ok(commit) = reference.peel_to_commit() {
+        tips.push(commit.id);
+      }
+    }
+    let newest_first = gix::revision::walk::Sorting::ByCommitTime(Default::default());
+    let mut keep = HashSet::new();
+    for info in repo.rev_walk(tips).sorting(newest_first).all()?.take(keep_recent.unwrap_or(usize::MAX)) {
+      keep.insert(info?.id);
+    }
+    let _pruning = self.inner.pruning.write().unwrap();
+    self.inner.disk_cache.prune(&keep)
+  }
+
+  /// The commits `exclude` describes. `Since` prunes the walk at the base and anything older
+  /// than it, like `git log base..HEAD` on a mostly linear history.
+  fn recent_commits(&self, exclude: &ExcludeRecent) -> anyhow::Result<HashSet<ObjectId>> {
+    let repo = self.inner.thread_local_repo();
+    let newest_first = gix::revision::walk::Sorting::ByCommitTime(Default::default());
+    let walk = repo.head_id()?.ancestors().sorting(newest_first);
+    let (walk, limit) = match exclude {
+      ExcludeRecent::Count(n) => (walk, *n),
+      ExcludeRecent::Since(base) => (walk.with_boundary([self.resolve_revision(base)?]), usize::MAX),
+    };
+    let mut commits = HashSet::new();
+    for info in walk.all()?.take(limit) {
+      commits.insert(info?.id);
+    }
+    Ok(commits)
+  }
+
+  /// Current path ids of every file a cached commit by `email` changed. Only reads the cache's
+  /// commit metadata and changed paths, nothing of the repository.
+  fn paths_touched_by(&self, email: &BStr) -> anyhow::Result<HashSet<u32>> {
+    let inner = &self.inner;
+    let email = inner.canonical_email(email);
+    let mut paths = HashSet::new();
+    for (id, stored) in inner.disk_cache.commit_metas()? {
+      let author = inner.mailmap.resolve(gix::actor::SignatureRef {
+        name: stored.author_name.as_ref(),
+        email: stored.author_email.as_ref(),
+        time: Default::default(),
+      });
+      if !author.email.eq_ignore_ascii_case(&email) {
+        continue;
+      }
+      // metadata is also kept for commits looked up without being cached
+      let Some(cached) = inner.disk_cache.cached_commit(&id)? else {
+        continue;
+      };
+      for path_id in cached.changed_paths {
+        paths.insert(inner.disk_cache.resolve_path_id(path_id)?);
+      }
+    }
+    Ok(paths)
+  }
+
+  /// Resolves a revision spec like `main`, `v1.2^` or `HEAD~3` to the commit it names.
+  pub(crate) fn resolve_revision(&self, spec: &str) -> Result<ObjectId, GraphError> {
+    let not_found = |reason: String| GraphError::RevisionNotFound {
+      spec: spec.to_owned(),
+      reason,
+    };
+    let repo = self.inner.thread_local_repo();
+    let commit = repo
+      .rev_parse_single(spec)
+      .map_err(|e| not_found(e.to_string()))?
+      .object()
+      .map_err(|e| not_found(e.to_string()))?
+      .peel_to_commit()
+      .map_err(|e| not_found(e.to_string()))?;
+    Ok(commit.id)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Mutex;
+  use std::time;
+
+  use super::*;
+  use crate::cache::{Cache, CachedCommit};
+  use crate::memory::MemoryCache;
+  use crate::testing::TestRepo;
+
+  /// Records commit writes and closing, with commit writes slowed down so they're still in flight
+  /// when the test shuts down.
+  struct RecordingCache {
+    inner: MemoryCache,
+    events: Arc<Mutex<Vec<String>>>,
+    writing: AtomicUsize,
+    max_writing: Arc<AtomicUsize>,
+  }
+
+  impl RecordingCache {
+    fn new(events: Arc<Mutex<Vec<String>>>, max_writing: Arc<AtomicUsize>) -> anyhow::Result<Self> {
+      Ok(RecordingCache {
+        inner: MemoryCache::new(),
+        events,
+        writing: AtomicUsize::new(0),
+        max_writing,
+      })
+    }
+  }
+
+  impl Cache for RecordingCache {
+    fn cache_path(&self, path: &BStr) -> anyhow::Result<u32> {
+      self.inner.cache_path(path)
+    }
+    fn path_id(&self, path: &BStr) -> anyhow::Result<Option<u32>> {
+      self.inner.path_id(path)
+    }
+    fn cache_paths(&self, paths: &[&BStr]) -> anyhow::Result<Vec<u32>> {
+      self.inner.cache_paths(paths)
+    }
+    fn cache_rename(&self, old_path: &BStr, new_path: u32) -> anyhow::Result<()> {
+      self.inner.cache_rename(old_path, new_path)
+    }
+    fn resolve_path(&self, path_id: u32) -> anyhow::Result<Option<BString>> {
+      self.inner.resolve_path(path_id)
+    }
+    fn resolve_path_id(&self, path_id: u32) -> anyhow::Result<u32> {
+      self.inner.resolve_path_id(path_id)
+    }
+    fn path(&self, path_id: u32) -> anyhow::Result<Option<BString>> {
+      self.inner.path(path_id)
+    }
+    fn paths(&self) -> anyhow::Result<Vec<(u32, BString)>> {
+      self.inner.paths()
+    }
+    fn cached_commit(&self, id: &ObjectId) -> anyhow::Result<Option<CachedCommit>> {
+      self.inner.cached_commit(id)
+    }
+    fn update_cached_commit(&self, id: &ObjectId, commit: CachedCommit) -> anyhow::Result<()> {
+      let writing = self.writing.fetch_add(1, Ordering::SeqCst) + 1;
+      self.max_writing.fetch_max(writing, Ordering::SeqCst);
+      std::thread::sleep(time::Duration::from_millis(50));
+      self.writing.fetch_sub(1, Ordering::SeqCst);
+      self.inner.update_cached_commit(id, commit)?;
+      self.events.lock().unwrap().push(id.to_string());
+      Ok(())
+    }
+    fn is_commit_cached(&self, id: &ObjectId) -> anyhow::Result<bool> {
+      self.inner.is_commit_cached(id)
+    }
+    fn cached_commits(&self, ids: &[ObjectId]) -> anyhow::Result<HashSet<ObjectId>> {
+      self.inner.cached_commits(ids)
+    }
+    fn update_changed_lines(&self, id: &ObjectId, path_id: u32, lines: &[Range<u32>]) -> anyhow::Result<()> {
+      self.inner.update_changed_lines(id, path_id, lines)
+    }
+    fn changed_lines(&self, id: &ObjectId, path_id: u32) -> anyhow::Result<Option<Vec<Range<u32>>>> {
+      self.inner.changed_lines(id, path_id)
+    }
+    fn update_commit_meta(&self, id: &ObjectId, meta: &CachedCommitMeta) -> anyhow::Result<()> {
+      self.inner.update_commit_meta(id, meta)
+    }
+    fn commit_meta(&self, id: &ObjectId) -> anyhow::Result<Option<CachedCommitMeta>> {
+      self.inner.commit_meta(id)
+    }
+
+    fn commit_metas(&self) -> anyhow::Result<Vec<(ObjectId, CachedCommitMeta)>> {
+      self.inner.commit_metas()
+    }
+    fn update_blame(&self, path_id: u32, id: &ObjectId, blame: &CachedBlame) -> anyhow::Result<()> {
+      self.inner.update_blame(path_id, id, blame)
+    }
+    fn blame(&self, path_id: u32, id: &ObjectId) -> anyhow::Result<Option<CachedBlame>> {
+      self.inner.blame(path_id, id)
+    }
+    fn prune(&self, keep: &HashSet<ObjectId>) -> anyhow::Result<PrunedCache> {
+      self.inner.prune(keep)
+    }
+    fn close(&self) -> anyhow::Result<()> {
+      self.inner.close()?;
+      self.events.lock().unwrap().push("close".into());
+      Ok(())
+    }
+  }
+
+  #[tokio::test]
+  async fn test_basic() -> anyhow::Result<()> {
+    // use the local everysphere monorepo for testing
+    let gg = LocalGitGraph::new("../../../../../", GraphConfig::default())?;
+
+    let blame = gg
+      .blame("vscode/src/vs/editor/browser/coreCommands.ts".into(), None)
+      .await?;
+
+    tokio::time::sleep(time::Duration::from_secs(1)).await;
+
+    for _ in 1..10 {
+      let now = time::Instant::now();
+      let related_files = gg.related_files(&blame, 43, &QueryOptions::default()).await?;
+      assert!(!related_files.is_empty());
+      assert!(related_files.iter().all(|c| c.path.as_ref() != Some(&blame.file_path)));
+      println!("Related files: {:?}", &related_files);
+      println!("Took {}ms", now.elapsed().as_millis());
+    }
+
+    Ok(())
+  }
+
+  fn oid(byte: u8) -> ObjectId {
+    ObjectId::from_bytes_or_panic(&[byte; 20])
+  }
+
+  #[test]
+  fn test_explaining_commits_cutoff() {
+    let contributions = vec![(oid(1), 0.2), (oid(2), 8.0), (oid(3), 0.3), (oid(4), 0.1), (oid(5), 0.4)];
+
+    assert_eq!(explaining_commits(&contributions, 0.8), vec![oid(2)]);
+    assert_eq!(explaining_commits(&contributions, 1.0).len(), 5);
+    assert_eq!(explaining_commits(&contributions, 0.9), vec![oid(2), oid(5)]);
+  }
+
+  #[tokio::test]
+  async fn test_linking_commits_list_every_commit_strongest_first() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "a\n");
+    let far = repo.commit("add src.rs and other.rs");
+    repo.write("src.rs", "one\ntwo\n");
+    repo.write("other.rs", "a\nb\n");
+    let near = repo.commit("change both");
+
+    let gg = repo.graph();
+    for commit in [far, near] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let candidates = gg.related_files(&blame, 2, &QueryOptions::default()).await?;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].linking_commits(), vec![near, far]);
+    Ok(())
+  }
+
+  async fn commit_for_last_lines(contents: [&str; 2]) -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("file.txt", contents[0]);
+    repo.commit("add file.txt");
+    repo.write("file.txt", contents[1]);
+    let last_line_commit = repo.commit("change the last line");
+
+    let gg = repo.graph();
+    let blame = gg.blame("file.txt".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let lines = blame.lines();
+    assert_eq!(lines.last().unwrap().range_in_blamed_file.end, 4);
+    // the last line itself, and the phantom line an editor shows after a final newline
+    for lineno in [3, 4] {
+      let chunk = &lines[blame::chunk_index_for_line(&lines, lineno)];
+      assert_eq!(chunk.commit_id, last_line_commit, "line {}", lineno);
+    }
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_last_line_without_trailing_newline() -> anyhow::Result<()> {
+    commit_for_last_lines(["one\ntwo\nthree\n", "one\ntwo\nTHREE"]).await
+  }
+
+  #[tokio::test]
+  async fn test_last_line_with_crlf() -> anyhow::Result<()> {
+    commit_for_last_lines(["one\r\ntwo\r\nthree\r\n", "one\r\ntwo\r\nTHREE\r\n"]).await
+  }
+
+  #[tokio::test]
+  async fn test_shutdown_waits_for_cached_commits() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    let mut contents = String::new();
+    let mut commits = Vec::new();
+    for i in 0..4 {
+      contents.push_str(&format!("line {}\n", i));
+      repo.write("file.txt", &contents);
+      commits.push(repo.commit(&format!("add line {}", i)).to_string());
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let cache = RecordingCache::new(events.clone(), Arc::new(AtomicUsize::new(0)))?;
+    let gg = LocalGitGraph::with_cache(repo.path().to_str().unwrap(), GraphConfig::default(), Box::new(cache))?;
+    let blame = gg.blame("file.txt".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    gg.shutdown(time::Duration::from_secs(10)).await?;
+    let events = events.lock().unwrap().clone();
+    assert_eq!(events.last().map(String::as_str), Some("close"));
+    let mut written = events[..events.len() - 1].to_vec();
+    written.sort();
+    commits.sort();
+    assert_eq!(written, commits);
+
+    assert!(gg.blame("README".into(), None).await.is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_thread_local_repos_keep_object_cache() {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+
+    // gix doesn't carry the object cache over to thread-local repos by itself
+    let gg = repo.graph();
+    assert!(!gg.inner.repo.to_thread_local().objects.has_object_cache());
+
+    let first = gg.inner.thread_local_repo();
+    assert!(first.objects.has_object_cache());
+    let second = gg.inner.thread_local_repo();
+    drop(first);
+    drop(second);
+    // given back for reuse rather than rebuilt cold
+    assert_eq!(gg.inner.repos.lock().unwrap().len(), 2);
+    let _reused = gg.inner.thread_local_repo();
+    assert_eq!(gg.inner.repos.lock().unwrap().len(), 1);
+
+    let disabled = repo.graph_with(GraphConfig {
+      object_cache_size: 0,
+      ..Default::default()
+    });
+    assert!(!disabled.inner.thread_local_repo().objects.has_object_cache());
+  }
+
+  #[test]
+  fn test_root_commit_adds_all_paths() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.write("src/lib.rs", "lib\n");
+    let root = repo.commit("initial");
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&root)?;
+    let cache = &gg.inner.disk_cache;
+    let commit = cache.cached_commit(&root)?.unwrap();
+    let mut paths = commit
+      .changed_paths
+      .iter()
+      .map(|id| cache.path(*id).map(Option::unwrap))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    paths.sort();
+    assert_eq!(paths, vec![BString::from("README"), BString::from("src/lib.rs")]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_authors_restrict_candidates_to_team() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write(".mailmap", "Alice <alice@example.com> <alice@old.example.com>\n");
+    repo.commit("initial");
+    let mut src = String::new();
+    let mut commits = Vec::new();
+    for (author, file) in [
+      ("Alice <alice@old.example.com>", "alice.rs"),
+      ("Bob <bob@example.com>", "bob.rs"),
+      ("Carol <carol@example.com>", "carol.rs"),
+    ] {
+      src.push_str(file);
+      src.push('\n');
+      repo.write("src.rs", &src);
+      repo.write(file, file);
+      repo.git(&["add", "-A"]);
+      repo.git(&["commit", "-q", "--author", author, "-m", file]);
+      commits.push(ObjectId::from_hex(repo.git(&["rev-parse", "HEAD"]).as_bytes())?);
+    }
+
+    let gg = repo.graph();
+    for commit in &commits {
+      gg.inner.load_cached_commit(commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let paths = |candidates: Vec<Candidate>| {
+      let mut paths: Vec<_> = candidates.into_iter().filter_map(|c| c.path).collect();
+      paths.sort();
+      paths
+    };
+
+    let everyone = gg.related_files(&blame, 2, &QueryOptions::default()).await?;
+    assert_eq!(paths(everyone), vec!["alice.rs", "bob.rs", "carol.rs"]);
+
+    let options = QueryOptions {
+      authors: Some(vec!["ALICE@example.com".into(), "bob@example.com".into()]),
+      ..Default::default()
+    };
+    let team = gg.related_files(&blame, 2, &options).await?;
+    assert_eq!(paths(team), vec!["alice.rs", "bob.rs"]);
+
+    let options = QueryOptions {
+      authors: Some(vec!["alice@old.example.com".into()]),
+      ..Default::default()
+    };
+    let alias = gg.related_files(&blame, 2, &options).await?;
+    assert_eq!(paths(alias), vec!["alice.rs"]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_blocking_calls_from_a_plain_thread() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "other\n");
+    let commit = repo.commit("add src.rs and other.rs");
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&commit)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build()?;
+    let candidates = std::thread::scope(|scope| {
+      scope
+        .spawn(|| {
+          let blame = gg.blame_blocking(runtime.handle(), "src.rs".into(), None)?;
+          gg.find_similar_files_blocking(runtime.handle(), &blame, 1, &QueryOptions::default())
+        })
+        .join()
+        .unwrap()
+    })?;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].path.as_ref().unwrap(), "other.rs");
+
+    let current_thread = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    assert!(gg.blame_blocking(current_thread.handle(), "src.rs".into(), None).is_err());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_only_existing_drops_deleted_files() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("kept.rs", "kept\n");
+    repo.write("deleted.rs", "deleted\n");
+    let coupling = repo.commit("add src.rs with two related files");
+    repo.git(&["rm", "-q", "deleted.rs"]);
+    let deletion = repo.commit("delete deleted.rs");
+
+    let gg = repo.graph();
+    for commit in [coupling, deletion] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let paths = |candidates: Vec<Candidate>| {
+      let mut paths: Vec<_> = candidates.into_iter().filter_map(|c| c.path).collect();
+      paths.sort();
+      paths
+    };
+    let all = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    assert_eq!(paths(all), vec!["deleted.rs", "kept.rs"]);
+    let options = QueryOptions {
+      only_existing: true,
+      ..Default::default()
+    };
+    let existing = gg.related_files(&blame, 1, &options).await?;
+    assert_eq!(paths(existing), vec!["kept.rs"]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_unfamiliar_to_drops_files_the_author_touched() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("touched.rs", "touched\n");
+    repo.write("untouched.rs", "untouched\n");
+    let coupling = repo.commit("add src.rs with two related files");
+    repo.write("touched.rs", "touched by bob\n");
+    repo.git(&["add", "-A"]);
+    repo.git(&["commit", "-q", "--author", "Bob <bob@example.com>", "-m", "bob edits touched.rs"]);
+    let bobs = repo.git(&["rev-parse", "HEAD"]);
+    let bobs = ObjectId::from_hex(bobs.trim().as_bytes())?;
+
+    let config = GraphConfig {
+      max_candidates: 1,
+      ..Default::default()
+    };
+    let gg = repo.graph_with(config);
+    gg.inner.load_cached_commit(&coupling)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let paths = |candidates: Vec<Candidate>| {
+      let mut paths: Vec<_> = candidates.into_iter().filter_map(|c| c.path).collect();
+      paths.sort();
+      paths
+    };
+    let options = QueryOptions {
+      unfamiliar_to: Some("BOB@example.com".into()),
+      ..Default::default()
+    };
+    // bob's commit isn't cached yet, so nothing rules touched.rs out
+    let uncached = gg.query_related_files(&blame, 1, &options).await?;
+    assert!(uncached.truncated);
+    assert_eq!(uncached.candidates.len(), 1);
+
+    gg.inner.load_cached_commit(&bobs)?;
+    // filtered before truncating, so touched.rs can't take untouched.rs's only place
+    let unfamiliar = gg.query_related_files(&blame, 1, &options).await?;
+    assert!(!unfamiliar.truncated);
+    assert_eq!(paths(unfamiliar.candidates), vec!["untouched.rs"]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_recency_factor_halves_per_half_life() {
+    let day = time::Duration::from_secs(86400);
+    assert_eq!(recency_factor(1000, 1000, day), 1.0);
+    assert_eq!(recency_factor(1000 + 86400, 1000, day), 1.0);
+    assert!((recency_factor(0, 2 * 86400, day) - 0.25).abs() < 1e-6);
+  }
+
+  #[tokio::test]
+  async fn test_score_coefficients_pick_the_signal() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?.as_secs() as i64;
+    let mut src = String::new();
+    let mut commits = Vec::new();
+    // a.rs is at the queried line, c.rs is next to it but recent, b.rs shares two farther commits
+    for (days_ago, file, contents) in [(400, "a.rs", "a\n"), (1, "c.rs", "c\n"), (300, "b.rs", "b\n"), (200, "b.rs", "bb\n")] {
+      src.push_str(&format!("{}\n", days_ago));
+      repo.write("src.rs", &src);
+      repo.write(file, contents);
+      commits.push(repo.commit_at(&format!("{} days ago", days_ago), now - days_ago * 86400));
+    }
+
+    let ranking = |blame_coefficient, cochange_coefficient, recency_coefficient| {
+      let gg = repo.graph_with(GraphConfig {
+        chunk_range: 10,
+        distance_decay: 0.6,
+        blame_coefficient,
+        cochange_coefficient,
+        recency_coefficient,
+        ..Default::default()
+      });
+      let commits = commits.clone();
+      async move {
+        for commit in &commits {
+          gg.inner.load_cached_commit(commit)?;
+        }
+        let blame = gg.blame("src.rs".into(), None).await?;
+        blame.wait_for_ready().await;
+        let candidates = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+        anyhow::Ok(candidates.into_iter().map(|c| (c.path.unwrap(), c.weight)).collect::<Vec<_>>())
+      }
+    };
+
+    let blame_only = ranking(1.0, 0.0, 0.0).await?;
+    assert_eq!(blame_only.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>(), vec!["a.rs", "c.rs", "b.rs"]);
+    assert_eq!(ranking(0.0, 1.0, 0.0).await?[0], ("b.rs".into(), 2.0));
+    assert_eq!(ranking(0.0, 0.0, 1.0).await?[0].0, "c.rs");
+    // a little co-change on top of blame lifts b.rs past c.rs, but not past a.rs
+    let blended = ranking(1.0, 0.5, 0.0).await?;
+    assert_eq!(blended.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>(), vec!["a.rs", "b.rs", "c.rs"]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_recency_half_life_favors_recent_commits() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?.as_secs() as i64;
+    repo.write("src.rs", "one\n");
+    repo.write("old.rs", "old\n");
+    let old = repo.commit_at("add src.rs and old.rs", now - 365 * 86400);
+    repo.write("src.rs", "one\ntwo\n");
+    repo.write("new.rs", "new\n");
+    let new = repo.commit_at("add line two and new.rs", now - 86400);
+
+    let gg = repo.graph();
+    for commit in [old, new] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let ranked = |candidates: Vec<Candidate>| candidates.into_iter().filter_map(|c| c.path).collect::<Vec<_>>();
+    // the old commit owns the queried line
+    let plain = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    assert_eq!(ranked(plain), vec!["old.rs", "new.rs"]);
+    let options = QueryOptions {
+      recency_half_life: Some(time::Duration::from_secs(30 * 86400)),
+      ..Default::default()
+    };
+    let recent = gg.related_files(&blame, 1, &options).await?;
+    assert_eq!(ranked(recent), vec!["new.rs", "old.rs"]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_merge_commit_unions_changes_from_all_parents() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.git(&["checkout", "-q", "-b", "feature"]);
+    repo.write("feature.rs", "feature\n");
+    repo.commit("add feature.rs");
+    repo.git(&["checkout", "-q", "main"]);
+    repo.write("main.rs", "main\n");
+    repo.commit("add main.rs");
+    repo.git(&["merge", "-q", "--no-ff", "-m", "merge feature", "feature"]);
+    let merge = ObjectId::from_hex(repo.git(&["rev-parse", "HEAD"]).as_bytes())?;
+
+    let changed_paths = |gg: LocalGitGraph| -> anyhow::Result<Vec<BString>> {
+      gg.inner.load_cached_commit(&merge)?;
+      let cache = &gg.inner.disk_cache;
+      let mut paths = cache
+        .cached_commit(&merge)?
+        .unwrap()
+        .changed_paths
+        .iter()
+        .map(|id| cache.path(*id).map(Option::unwrap))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+      paths.sort();
+      Ok(paths)
+    };
+
+    assert_eq!(changed_paths(repo.graph())?, vec!["feature.rs", "main.rs"]);
+    let config = GraphConfig {
+      first_parent_only: true,
+      ..Default::default()
+    };
+    assert_eq!(changed_paths(repo.graph_with(config))?, vec!["feature.rs"]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_cached_line_ranges_match_blame() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.write("other.rs", "a\nb\nc\nd\ne\nf\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "a\nB\nb2\nc\nd\ne\nF\n");
+    let commit = repo.commit("change src.rs and other.rs");
+
+    let mut located = Vec::new();
+    for cache_line_ranges in [false, true] {
+      let gg = repo.graph_with(GraphConfig {
+        cache_line_ranges,
+        ..Default::default()
+      });
+      gg.inner.load_cached_commit(&commit)?;
+      let blame = gg.blame("src.rs".into(), None).await?;
+      blame.wait_for_ready().await;
+
+      let other = gg
+        .related_files(&blame, 1, &QueryOptions::default())
+        .await?
+        .into_iter()
+        .find(|c| c.path.as_ref().is_some_and(|p| p == "other.rs"))
+        .unwrap();
+      let blamed = gg.inner.blame_cache.contains(&gg.inner.blame_key(Some(commit), "other.rs".into())?);
+      assert_eq!(blamed, !cache_line_ranges);
+      located.push((other.locations, other.touched_lines));
+    }
+    assert_eq!(located[0], (vec![2..4, 7..8], 3));
+    assert_eq!(located[0], located[1]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_blame_follows_rename_only_commit() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("old näme.rs", "one\ntwo\n");
+    repo.write("other.rs", "other\n");
+    let before_rename = repo.commit("add old näme.rs and other.rs");
+    repo.git(&["mv", "old näme.rs", "new.rs"]);
+    repo.commit("rename old näme.rs to new.rs");
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&before_rename)?;
+    let blame = gg.blame("new.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let lines = blame.lines();
+    assert!(lines.iter().all(|l| l.commit_id == before_rename && l.original_path == "old näme.rs"));
+
+    let interned = gg.interned_path_count()?;
+    let paths: Vec<_> = gg
+      .related_files(&blame, 1, &QueryOptions::default())
+      .await?
+      .into_iter()
+      .filter_map(|c| c.path)
+      .collect();
+    // the file itself under its old name, excluded from its own results
+    assert_eq!(paths, vec!["other.rs"]);
+    // found by looking up the queried path, not by adding it
+    assert_eq!(gg.interned_path_count()?, interned);
+    let old_path = gg.inner.disk_cache.cache_path("old näme.rs".into())?;
+    assert_eq!(gg.inner.disk_cache.resolve_path(old_path)?, Some("new.rs".into()));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_detached_head_is_resolved_once() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("old.rs", "old\n");
+    let detached = repo.commit("add src.rs and old.rs");
+    repo.write("src.rs", "ONE\n");
+    repo.write("new.rs", "new\n");
+    let newer = repo.commit("rewrite src.rs, add new.rs");
+    repo.git(&["checkout", "-q", "--detach", &detached.to_string()]);
+
+    let gg = repo.graph();
+    assert_eq!(gg.head_commit()?, detached);
+    for commit in [detached, newer] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    // moving HEAD after opening doesn't change what the open file looks at
+    repo.git(&["checkout", "-q", "main"]);
+    blame.wait_for_ready().await;
+    assert!(blame.lines().iter().all(|line| line.commit_id == detached));
+    let paths: Vec<_> = gg
+      .related_files(&blame, 1, &QueryOptions::default())
+      .await?
+      .into_iter()
+      .filter_map(|c| c.path)
+      .collect();
+    assert_eq!(paths, vec!["old.rs"]);
+
+    let reopened = gg.blame("src.rs".into(), None).await?;
+    reopened.wait_for_ready().await;
+    assert!(reopened.lines().iter().all(|line| line.commit_id == newer));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_blame_is_reused_while_file_is_unchanged() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    repo.commit("add src.rs");
+    let gg = repo.graph();
+    let first = gg.blame("src.rs".into(), None).await?;
+    first.wait_for_ready().await;
+
+    repo.write("other.rs", "other\n");
+    repo.commit("add other.rs");
+    let unchanged = gg.blame("src.rs".into(), None).await?;
+    assert!(Arc::ptr_eq(&first, &unchanged));
+
+    repo.write("src.rs", "one\ntwo\n");
+    let changed = repo.commit("add a line to src.rs");
+    let reblamed = gg.blame("src.rs".into(), None).await?;
+    assert!(!Arc::ptr_eq(&first, &reblamed));
+    reblamed.wait_for_ready().await;
+    assert_eq!(reblamed.lines().last().unwrap().commit_id, changed);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_blame_on_unborn_branch_fails_clearly() {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    let gg = repo.graph();
+    let Err(err) = gg.blame("src.rs".into(), None).await else {
+      panic!("blamed a file on a branch without commits");
+    };
+    assert!(matches!(err, GraphError::RevisionNotFound { .. }), "{}", err);
+    assert!(err.to_string().contains("branch 'main', which has no commits yet"), "{}", err);
+  }
+
+  #[tokio::test]
+  async fn test_errors_name_what_went_wrong() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    let head = repo.commit("initial");
+    let gg = repo.graph();
+
+    let Err(err) = gg.blame("missing.rs".into(), None).await else {
+      panic!("blamed a file that isn't in HEAD");
+    };
+    assert!(matches!(&err, GraphError::PathNotTracked { revision, .. } if *revision == head), "{}", err);
+    assert!(err.to_string().starts_with("PathNotTracked: "), "{}", err);
+
+    let err = gg.resolve_revision("no-such-branch").unwrap_err();
+    assert!(matches!(err, GraphError::RevisionNotFound { .. }), "{}", err);
+
+    let Err(err) = LocalGitGraph::new(repo.path().join("nope").to_str().unwrap(), GraphConfig::default()) else {
+      panic!("opened a repository that doesn't exist");
+    };
+    assert!(err.to_string().starts_with("RepoOpen: "), "{}", err);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_failed_blame_fails_queries_but_an_empty_file_does_not() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("empty.rs", "");
+    repo.write("src.rs", "one\n");
+    repo.commit("initial");
+    let gg = repo.graph();
+
+    let empty = gg.blame("empty.rs".into(), None).await?;
+    gg.ensure_ready(&empty, true).await?;
+    assert!(gg.related_files(&empty, 1, &QueryOptions::default()).await?.is_empty());
+
+    // git blame can't read the file's contents once its blob is gone
+    let blob = repo.git(&["rev-parse", "HEAD:src.rs"]);
+    std::fs::remove_file(repo.path().join(".git/objects").join(&blob[..2]).join(&blob[2..]))?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    let err = gg.ensure_ready(&blame, true).await.unwrap_err();
+    assert!(matches!(err, GraphError::BlameFailed { .. }), "{}", err);
+    assert!(err.to_string().contains("git-blame: fatal: "), "{}", err);
+    let err = gg.related_files(&blame, 1, &QueryOptions::default()).await.unwrap_err();
+    assert!(matches!(GraphError::from(err), GraphError::BlameFailed { .. }));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_bare_repository_and_separate_work_tree() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "other\n");
+    let commit = repo.commit("add src.rs and other.rs");
+    let bare = tempfile::tempdir()?;
+    let bare_path = bare.path().join("repo.git");
+    repo.git(&["clone", "-q", "--bare", ".", bare_path.to_str().unwrap()]);
+
+    let config = || GraphConfig {
+      cache_backend: CacheBackend::Memory,
+      ..GraphConfig::default()
+    };
+    let git_dir = repo.path().join(".git");
+    for gg in [
+      LocalGitGraph::new(bare_path.to_str().unwrap(), config())?,
+      LocalGitGraph::with_worktree(git_dir.to_str().unwrap(), repo.path().to_str().unwrap(), config())?,
+    ] {
+      gg.inner.load_cached_commit(&commit)?;
+      let blame = gg.blame("src.rs".into(), None).await?;
+      gg.ensure_ready(&blame, true).await?;
+      let candidates = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+      assert_eq!(candidates.into_iter().map(|c| c.path).collect::<Vec<_>>(), vec![Some("other.rs".into())]);
+    }
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_blame_can_look_through_formatting_commits() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "fn a() {\nx\n}\n");
+    repo.write("helper.rs", "h\n");
+    let added = repo.commit("add src.rs and helper.rs");
+    repo.write("src.rs", "fn a() {\n  x\n}\n");
+    repo.write("unrelated.rs", "u\n");
+    let formatted = repo.commit("reformat everything");
+
+    let repo = &repo;
+    let related = |config: GraphConfig| async move {
+      let gg = repo.graph_with(config);
+      gg.inner.load_cached_commit(&added)?;
+      gg.inner.load_cached_commit(&formatted)?;
+      let blame = gg.blame("src.rs".into(), None).await?;
+      gg.ensure_ready(&blame, true).await?;
+      let mut paths: Vec<_> = gg
+        .related_files(&blame, 2, &QueryOptions::default())
+        .await?
+        .into_iter()
+        .map(|c| c.path.unwrap())
+        .collect();
+      paths.sort();
+      anyhow::Ok(paths)
+    };
+    assert_eq!(related(GraphConfig::default()).await?, vec!["helper.rs", "unrelated.rs"]);
+    let ignoring_whitespace = GraphConfig {
+      ignore_whitespace: true,
+      ..GraphConfig::default()
+    };
+    assert_eq!(related(ignoring_whitespace).await?, vec!["helper.rs"]);
+
+    repo.write(".git-blame-ignore-revs", format!("{}\n", formatted));
+    let ignoring_revs_file = GraphConfig {
+      ignore_revs_file: Some(".git-blame-ignore-revs".into()),
+      ..GraphConfig::default()
+    };
+    assert_eq!(related(ignoring_revs_file).await?, vec!["helper.rs"]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_ignored_revs_blame_lines_to_the_commit_before() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "fn a() { x }\nfn b() { y }\n");
+    let added = repo.commit("add src.rs");
+    repo.write("src.rs", "fn a() {\n  x\n}\nfn b() {\n  y\n}\n");
+    let reformatted = repo.commit("reformat");
+
+    let blamed_commits = |gg: LocalGitGraph| async move {
+      let blame = gg.blame("src.rs".into(), None).await?;
+      gg.ensure_ready(&blame, true).await?;
+      anyhow::Ok(blame.lines().iter().map(|entry| entry.commit_id).collect::<HashSet<_>>())
+    };
+    assert!(blamed_commits(repo.graph()).await?.contains(&reformatted));
+    let ignoring = GraphConfig {
+      ignore_revs: vec![reformatted.to_string()],
+      ..GraphConfig::default()
+    };
+    assert_eq!(blamed_commits(repo.graph_with(ignoring)).await?, HashSet::from([added]));
+
+    // the repository's own setting, without any graph config
+    repo.write(".git-blame-ignore-revs", format!("# formatting\n{}\n", reformatted));
+    repo.git(&["config", "blame.ignoreRevsFile", ".git-blame-ignore-revs"]);
+    assert_eq!(blamed_commits(repo.graph()).await?, HashSet::from([added]));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_refresh_drops_blames_made_before_head_moved() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    repo.write("followed.rs", "one\n");
+    let first = repo.commit("add src.rs and followed.rs");
+    let gg = repo.graph();
+    let pinned = gg.blame("src.rs".into(), Some(first)).await?;
+    pinned.wait_for_ready().await;
+    let key = gg.inner.blame_key(Some(first), "src.rs".into())?;
+    let followed = gg.inner.load_blame(None, "followed.rs".into(), false).await?;
+    followed.wait_for_ready().await;
+    let followed_key = gg.inner.blame_key(None, "followed.rs".into())?;
+
+    // HEAD hasn't moved: nothing is stale
+    gg.refresh();
+    assert!(gg.inner.blame_cache.contains(&key));
+    assert!(gg.inner.blame_cache.contains(&followed_key));
+    assert!(Arc::ptr_eq(&pinned, &gg.blame("src.rs".into(), None).await?));
+
+    repo.write("other.rs", "other\n");
+    repo.commit("add other.rs");
+    gg.refresh();
+    assert_eq!(gg.refreshes(), 2);
+    // neither file changed, so their blames still hold
+    assert!(gg.inner.blame_cache.contains(&key));
+    assert!(gg.inner.blame_cache.contains(&followed_key));
+    assert!(Arc::ptr_eq(&pinned, &gg.blame("src.rs".into(), None).await?));
+
+    repo.write("src.rs", "two\n");
+    repo.write("followed.rs", "two\n");
+    let third = repo.commit("change src.rs and followed.rs");
+    gg.refresh();
+    // a blame at a given commit stays, the one that followed HEAD to the old content goes
+    assert!(gg.inner.blame_cache.contains(&key));
+    assert!(!gg.inner.blame_cache.contains(&followed_key));
+    let fresh = gg.blame("src.rs".into(), None).await?;
+    assert!(!Arc::ptr_eq(&pinned, &fresh));
+    assert_eq!(fresh.revision, Some(third));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_close_aborts_blames_and_fails_later_calls() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    repo.commit("add src.rs");
+    let gg = repo.graph();
+
+    // the blame only starts running once the test yields
+    let blame = gg.blame("src.rs".into(), None).await?;
+    gg.close()?;
+    let err = gg.ensure_ready(&blame, true).await.unwrap_err();
+    assert!(err.to_string().contains("the graph was closed"), "{}", err);
+
+    let Err(err) = gg.blame("src.rs".into(), None).await else {
+      panic!("blamed a file after closing");
+    };
+    assert!(matches!(err, GraphError::Closed), "{}", err);
+    let err = gg.related_files(&blame, 1, &QueryOptions::default()).await.unwrap_err();
+    assert!(matches!(GraphError::from(err), GraphError::Closed));
+    assert!(matches!(GraphError::from(gg.list_paths().unwrap_err()), GraphError::Closed));
+    gg.close()?;
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_watch_notifies_when_skipped_commits_are_cached() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "other\n");
+    let commit = repo.commit("add src.rs and other.rs");
+
+    let gg = repo.graph();
+    // blame without caching anything, like a query racing the background caching
+    let cold = gg.inner.load_blame(Some(commit), "src.rs".into(), false).await?;
+    cold.wait_for_ready().await;
+    let result = gg.query_related_files(&cold, 1, &QueryOptions::default()).await?;
+    assert!(result.candidates.is_empty());
+    assert_eq!(result.uncached_commits, vec![commit]);
+    assert_eq!(
+      result.incomplete_reason().as_deref(),
+      Some("not all commits near the line are cached yet (1 missing)")
+    );
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    gg.watch_commits(&result.uncached_commits, move || {
+      let _ = tx.send(());
+    })?;
+    gg.blame("src.rs".into(), None).await?;
+    tokio::time::timeout(time::Duration::from_secs(10), rx).await??;
+
+    let result = gg.query_related_files(&cold, 1, &QueryOptions::default()).await?;
+    assert!(result.uncached_commits.is_empty());
+    assert!(result.candidates.iter().any(|c| c.path.as_ref().is_some_and(|p| p == "other.rs")));
+    assert_eq!(result.incomplete_reason(), None);
+
+    // nothing left to wait for
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    gg.watch_commits(&[commit], move || {
+      let _ = tx.send(());
+    })?;
+    rx.await?;
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_background_caching_is_bounded() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    let mut contents = String::new();
+    for i in 0..8 {
+      contents.push_str(&format!("line {}\n", i));
+      repo.write("file.txt", &contents);
+      repo.commit(&format!("add line {}", i));
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let max_writing = Arc::new(AtomicUsize::new(0));
+    let cache = RecordingCache::new(events.clone(), max_writing.clone())?;
+    let config = GraphConfig {
+      max_caching_tasks: 2,
+      ..Default::default()
+    };
+    let gg = LocalGitGraph::with_cache(repo.path().to_str().unwrap(), config, Box::new(cache))?;
+    let blame = gg.blame("file.txt".into(), None).await?;
+    blame.wait_for_ready().await;
+    gg.shutdown(time::Duration::from_secs(10)).await?;
+
+    // every commit still got cached, never more than two at a time
+    assert_eq!(events.lock().unwrap().len(), 8 + 1);
+    assert!((1..=2).contains(&max_writing.load(Ordering::SeqCst)));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_normalized_weight_is_relative_to_top_candidate() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("a.rs", "a\n");
+    let first = repo.commit("add src.rs and a.rs");
+    repo.write("src.rs", "one\ntwo\n");
+    repo.write("a.rs", "a\nb\n");
+    repo.write("b.rs", "b\n");
+    let second = repo.commit("change src.rs and a.rs, add b.rs");
+
+    let gg = repo.graph();
+    for commit in [first, second] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let candidates = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    assert!(candidates.len() > 1);
+    assert_eq!(candidates[0].normalized_weight, 1.0);
+    for candidate in &candidates {
+      assert!((0.0..=1.0).contains(&candidate.normalized_weight));
+      let expected = candidate.weight / candidates[0].weight;
+      assert!((candidate.normalized_weight - expected).abs() < 1e-6);
+    }
+    assert!(candidates.iter().all(|c| c.path.as_ref().is_some_and(|p| p != "src.rs")));
+    let b_rs = candidates.iter().find(|c| c.path.as_ref().is_some_and(|p| p == "b.rs")).unwrap();
+    assert!(b_rs.normalized_weight < 1.0);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_depth_two_reaches_files_related_to_candidates() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("a.rs", "a\n");
+    repo.write("far.rs", "far\n");
+    let a_and_far = repo.commit("add a.rs and far.rs");
+    repo.write("src.rs", "one\n");
+    repo.write("a.rs", "a\nb\n");
+    let src_and_a = repo.commit("add src.rs, change a.rs");
+
+    let gg = repo.graph();
+    for commit in [a_and_far, src_and_a] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let find = |candidates: &[Candidate], path: &str| {
+      candidates.iter().position(|c| c.path.as_ref().is_some_and(|p| p == path))
+    };
+
+    let direct = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    assert!(find(&direct, "a.rs").is_some());
+    assert!(find(&direct, "far.rs").is_none());
+
+    let options = QueryOptions {
+      depth: 2,
+      ..Default::default()
+    };
+    let expanded = gg.related_files(&blame, 1, &options).await?;
+    let a_rs = &expanded[find(&expanded, "a.rs").unwrap()];
+    let far_rs = &expanded[find(&expanded, "far.rs").expect("far.rs is related through a.rs")];
+    assert_eq!(a_rs.via, None);
+    assert_eq!(far_rs.via, Some("a.rs".into()));
+    // a.rs is related to src.rs right back, which still isn't its own result
+    assert!(find(&expanded, "src.rs").is_none());
+
+    // decayed from a.rs's weight by how related far.rs is to a.rs
+    let a_blame = gg.inner.load_blame(Some(src_and_a), "a.rs".into(), false).await?;
+    a_blame.wait_for_ready().await;
+    let from_a = gg.related_files(&a_blame, a_rs.locations[0].start as usize, &QueryOptions::default()).await?;
+    let far_from_a = &from_a[find(&from_a, "far.rs").unwrap()];
+    let expected = a_rs.weight * GraphConfig::default().expansion_decay * far_from_a.normalized_weight;
+    assert!((far_rs.weight - expected).abs() < 1e-6);
+    assert!(far_rs.weight < a_rs.weight);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_second_hop_decays_by_expansion_decay() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("a.rs", "a\n");
+    repo.write("far.rs", "far\n");
+    let a_and_far = repo.commit("add a.rs and far.rs");
+    repo.write("src.rs", "one\n");
+    repo.write("a.rs", "a\nb\n");
+    let src_and_a = repo.commit("add src.rs, change a.rs");
+
+    let options = QueryOptions {
+      depth: 2,
+      ..Default::default()
+    };
+    let mut weights = Vec::new();
+    for expansion_decay in [GraphConfig::default().expansion_decay, 0.25] {
+      let gg = repo.graph_with(GraphConfig {
+        expansion_decay,
+        ..Default::default()
+      });
+      for commit in [a_and_far, src_and_a] {
+        gg.inner.load_cached_commit(&commit)?;
+      }
+      let blame = gg.blame("src.rs".into(), None).await?;
+      blame.wait_for_ready().await;
+      let expanded = gg.related_files(&blame, 1, &options).await?;
+      let [a_rs, far_rs] = ["a.rs", "far.rs"].map(|path| {
+        expanded.iter().find(|c| c.path.as_ref().is_some_and(|p| p == path)).unwrap()
+      });
+      assert_eq!(far_rs.via, Some("a.rs".into()));
+      assert!(expanded.iter().all(|c| c.path.as_ref().is_some_and(|p| p != "src.rs")));
+      // at most the decayed weight of the seed, scaled by how related far.rs is to it
+      assert!(far_rs.weight > 0.0 && far_rs.weight <= a_rs.weight * expansion_decay + 1e-6);
+      weights.push((a_rs.weight, far_rs.weight));
+    }
+    assert_eq!(GraphConfig::default().expansion_decay, 0.5);
+    // the direct result doesn't depend on the decay; halving the decay halves the second hop
+    assert!((weights[0].0 - weights[1].0).abs() < 1e-6);
+    assert!((weights[1].1 - weights[0].1 / 2.0).abs() < 1e-6);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_queried_line_commit_always_weighs_most() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    let mut src = String::new();
+    let mut commits = Vec::new();
+    for i in 0..5 {
+      src.push_str(&format!("line {}\n", i));
+      repo.write("src.rs", &src);
+      repo.write(&format!("f{}.rs", i), "f\n");
+      commits.push(repo.commit(&format!("add line {} and f{}.rs", i, i)));
+    }
+
+    for chunk_range in [0, 1, 2, 3, 6, 20] {
+      let gg = repo.graph_with(GraphConfig {
+        chunk_range,
+        ..Default::default()
+      });
+      for commit in &commits {
+        gg.inner.load_cached_commit(commit)?;
+      }
+      let blame = gg.blame("src.rs".into(), None).await?;
+      blame.wait_for_ready().await;
+      for lineno in 1..=5 {
+        let candidates = gg.related_files(&blame, lineno, &QueryOptions::default()).await?;
+        let own = format!("f{}.rs", lineno - 1);
+        let top = &candidates[0];
+        assert_eq!(top.path.as_ref().unwrap(), &own, "line {} with chunk_range {}", lineno, chunk_range);
+        assert!(candidates[1..].iter().all(|c| c.weight < top.weight));
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_merge_ranges_coalesces_adjacent_and_overlapping() {
+    assert_eq!(merge_ranges(vec![12..15, 10..12, 20..21]), vec![10..15, 20..21]);
+    assert_eq!(merge_ranges(vec![1..5, 2..3, 4..8, 9..10]), vec![1..8, 9..10]);
+    assert_eq!(merge_ranges(vec![]), vec![]);
+  }
+
+  #[test]
+  fn test_equal_weights_rank_by_path_id_and_nan_does_not_panic() {
+    let candidate = |path_id: u32, weight: f32| Candidate {
+      path: None,
+      locations: vec![],
+      touched_lines: 1,
+      weight,
+      path_id,
+      commit: oid(1),
+      commit_path_id: path_id,
+      contributions: vec![],
+      explaining_commits: vec![],
+      calibrated_score: None,
+      normalized_weight: 0.0,
+      via: None,
+      attributions: vec![],
+      commit_meta: None,
+      partial: false,
+    };
+    let ranked = |order: &[(u32, f32)]| {
+      let mut candidates: Vec<_> = order.iter().map(|&(path_id, weight)| candidate(path_id, weight)).collect();
+      candidates.sort_by(heaviest_first);
+      candidates.into_iter().map(|candidate| candidate.path_id).collect::<Vec<_>>()
+    };
+    let expected = vec![4, 3, 1, 5, 7, 2];
+    assert_eq!(ranked(&[(7, 1.0), (3, 2.0), (5, 1.0), (2, 0.5), (1, 1.0), (4, f32::NAN)]), expected);
+    assert_eq!(ranked(&[(1, 1.0), (2, 0.5), (4, f32::NAN), (5, 1.0), (3, 2.0), (7, 1.0)]), expected);
+  }
+
+  #[tokio::test]
+  async fn test_equal_weights_rank_by_path_id_after_expansion() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("a.rs", "a\n");
+    for name in ["x.rs", "y.rs", "z.rs"] {
+      repo.write(name, name);
+    }
+    let a_and_friends = repo.commit("add a.rs with x.rs, y.rs and z.rs");
+    repo.write("src.rs", "one\n");
+    repo.write("a.rs", "a\nb\n");
+    let src_and_a = repo.commit("add src.rs, change a.rs");
+
+    let gg = repo.graph();
+    // ids in the opposite order of the paths, so ranking by path would show
+    for name in ["z.rs", "y.rs", "x.rs"] {
+      gg.inner.disk_cache.cache_path(name.into())?;
+    }
+    for commit in [a_and_friends, src_and_a] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let options = QueryOptions {
+      depth: 2,
+      ..Default::default()
+    };
+    let expanded = gg.related_files(&blame, 1, &options).await?;
+    let through_a: Vec<_> = expanded.iter().filter(|c| c.via.is_some()).collect();
+    assert!(through_a.windows(2).all(|pair| pair[0].weight == pair[1].weight));
+    let paths: Vec<_> = through_a.iter().filter_map(|c| c.path.clone()).collect();
+    assert_eq!(paths, vec!["z.rs", "y.rs", "x.rs"]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_related_dirs_sum_weights_per_prefix() {
+    let candidate = |path: &str, weight: f32| Candidate {
+      path: Some(path.into()),
+      locations: vec![],
+      touched_lines: 1,
+      weight,
+      path_id: 0,
+      commit: oid(1),
+      commit_path_id: 0,
+      contributions: vec![],
+      explaining_commits: vec![],
+      calibrated_score: None,
+      normalized_weight: 0.0,
+      via: None,
+      attributions: vec![],
+      commit_meta: None,
+      partial: false,
+    };
+    let candidates = [
+      candidate("src/editor/browser/a.ts", 1.0),
+      candidate("src/editor/common/b.ts", 0.5),
+      candidate("src/editor/browser/c.ts", 1.0),
+      candidate("src/main.ts", 3.0),
+      candidate("README.md", 0.25),
+    ];
+
+    let summary = |depth| {
+      related_dirs(&candidates, depth)
+        .into_iter()
+        .map(|d| (d.dir.to_string(), d.file_count, d.total_weight))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      summary(3),
+      vec![
+        ("src/".to_owned(), 1, 3.0),
+        ("src/editor/browser/".to_owned(), 2, 2.0),
+        ("src/editor/common/".to_owned(), 1, 0.5),
+        ("".to_owned(), 1, 0.25),
+      ]
+    );
+    assert_eq!(summary(1), vec![("src/".to_owned(), 4, 5.5), ("".to_owned(), 1, 0.25)]);
+  }
+
+  #[tokio::test]
+  async fn test_range_query_spans_selection() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    let mut src = String::new();
+    let mut commits = Vec::new();
+    for i in 0..5 {
+      src.push_str(&format!("line {}\n", i));
+      repo.write("src.rs", &src);
+      repo.write(&format!("f{}.rs", i), "f\n");
+      commits.push(repo.commit(&format!("add line {} and f{}.rs", i, i)));
+    }
+
+    let gg = repo.graph_with(GraphConfig {
+      chunk_range: 2,
+      ..Default::default()
+    });
+    for commit in &commits {
+      gg.inner.load_cached_commit(commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let options = QueryOptions::default();
+    let weights = |candidates: Vec<Candidate>| {
+      let mut weights: Vec<_> = candidates.into_iter().map(|c| (c.path.unwrap(), c.weight)).collect();
+      weights.sort_by(|a, b| a.0.cmp(&b.0));
+      weights
+    };
+
+    // a one-line selection is the single-line query
+    assert_eq!(
+      weights(gg.related_files_in_range(&blame, 3..=3, &options).await?),
+      weights(gg.related_files(&blame, 3, &options).await?),
+    );
+
+    // lines 2..=4 are chunks 1..=3, plus one chunk of context before them
+    let selection = weights(gg.related_files_in_range(&blame, 2..=4, &options).await?);
+    let paths: Vec<_> = selection.iter().map(|(path, _)| path.clone()).collect();
+    assert_eq!(paths, vec!["f0.rs", "f1.rs", "f2.rs", "f3.rs"]);
+    assert_eq!(selection[1].1, selection[2].1);
+    assert_eq!(selection[2].1, selection[3].1);
+    assert!(selection[0].1 < selection[1].1);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_symbol_query_covers_its_lines_and_echoes_the_name() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    let mut src = String::new();
+    let mut commits = Vec::new();
+    for i in 0..5 {
+      src.push_str(&format!("line {}\n", i));
+      repo.write("src.rs", &src);
+      repo.write(&format!("f{}.rs", i), "f\n");
+      commits.push(repo.commit(&format!("add line {} and f{}.rs", i, i)));
+    }
+
+    // no context chunks, so only the symbol's own lines count
+    let gg = repo.graph_with(GraphConfig {
+      chunk_range: 0,
+      ..Default::default()
+    });
+    for commit in &commits {
+      gg.inner.load_cached_commit(commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let options = QueryOptions::default();
+    let paths = |candidates: Vec<Candidate>| {
+      let mut paths: Vec<_> = candidates.into_iter().filter_map(|c| c.path).collect();
+      paths.sort();
+      paths
+    };
+
+    let symbol = gg.related_files_for_symbol(&blame, "fn foo".into(), 2, 4, &options).await?;
+    assert_eq!(symbol.name, "fn foo");
+    // line 4, the symbol's last, brings in f3.rs
+    assert_eq!(paths(symbol.candidates), vec!["f1.rs", "f2.rs", "f3.rs"]);
+    let reversed = gg.related_files_for_symbol(&blame, "fn foo".into(), 4, 2, &options).await?;
+    assert_eq!(paths(reversed.candidates), vec!["f1.rs", "f2.rs", "f3.rs"]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_path_globs_filter_candidates() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("Cargo.lock", "lock\n");
+    repo.write("sub/Cargo.lock", "lock\n");
+    repo.write("lib.rs", "lib\n");
+    repo.write("tests/fixtures/data.json", "{}\n");
+    let commit = repo.commit("add files together");
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let query = |include: Option<&[&str]>, exclude: &[&str]| {
+      let options = QueryOptions {
+        paths: Some(PathFilter::new(
+          include.map(|include| include.iter().map(BStr::new)),
+          exclude.iter().map(BStr::new),
+        )),
+        ..QueryOptions::default()
+      };
+      let gg = gg.clone();
+      let blame = blame.clone();
+      async move {
+        let mut paths: Vec<BString> = gg
+          .related_files(&blame, 1, &options)
+          .await?
+          .into_iter()
+          .filter_map(|candidate| candidate.path)
+          .collect();
+        paths.sort();
+        anyhow::Ok(paths)
+      }
+    };
+
+    assert_eq!(
+      query(None, &["*.lock"]).await?,
+      vec![BString::from("lib.rs"), "tests/fixtures/data.json".into()]
+    );
+    assert_eq!(
+      query(None, &["*.lock", "tests/fixtures/**"]).await?,
+      vec![BString::from("lib.rs")]
+    );
+    assert_eq!(
+      query(Some(&["*.lock"]), &["sub/*"]).await?,
+      vec![BString::from("Cargo.lock")]
+    );
+    assert_eq!(query(None, &[]).await?.len(), 4);
+    // an include list without a usable glob matches nothing instead of everything
+    assert!(query(Some(&[""]), &[]).await?.is_empty());
+    assert!(query(Some(&[]), &[]).await?.is_empty());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_max_candidates_truncates_results() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    for name in ["a.rs", "b.rs", "c.rs"] {
+      repo.write(name, name);
+    }
+    let commit = repo.commit("add files together");
+
+    let config = GraphConfig {
+      max_candidates: 2,
+      ..Default::default()
+    };
+    for (gg, expected) in [(repo.graph(), 3), (repo.graph_with(config), 2)] {
+      gg.inner.load_cached_commit(&commit)?;
+      let blame = gg.blame("src.rs".into(), None).await?;
+      blame.wait_for_ready().await;
+      let result = gg.query_related_files(&blame, 1, &QueryOptions::default()).await?;
+      assert_eq!(result.candidates.len(), expected);
+      assert_eq!(result.truncated, expected < 3);
+      if result.truncated {
+        assert!(result.incomplete_reason().is_some_and(|reason| reason.contains("maxCandidates")));
+      }
+    }
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_ensure_ready_waits_or_reports_not_ready() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "other\n");
+    let commit = repo.commit("add src.rs and other.rs");
+
+    let query = |gg: LocalGitGraph, wait: Option<bool>| async move {
+      gg.inner.load_cached_commit(&commit)?;
+      let blame = gg.blame("src.rs".into(), None).await?;
+      match wait {
+        Some(wait) => gg.ensure_ready(&blame, wait).await?,
+        None => {
+          blame.wait_for_ready().await;
+          gg.ensure_ready(&blame, false).await?;
+        }
+      }
+      let candidates = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+      anyhow::Ok(candidates.into_iter().map(|c| (c.path, c.weight)).collect::<Vec<_>>())
+    };
+
+    // the blame only starts running once the test yields
+    let err = query(repo.graph(), Some(false)).await.unwrap_err();
+    assert!(matches!(GraphError::from(err), GraphError::NotReady { .. }));
+
+    let awaited = query(repo.graph(), None).await?;
+    let auto_waited = query(repo.graph(), Some(true)).await?;
+    assert_eq!(awaited, vec![(Some("other.rs".into()), 2.0)]);
+    assert_eq!(awaited, auto_waited);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_attributions_split_locations_by_commit() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "a\nb\n");
+    repo.write("other.rs", "1\n2\n3\n4\n");
+    let first = repo.commit("add src.rs and other.rs");
+    repo.write("src.rs", "A\nb\n");
+    repo.write("other.rs", "1\n2\n3\nfour\n");
+    let second = repo.commit("change line 1 of src.rs and line 4 of other.rs");
+
+    let gg = repo.graph();
+    for commit in [first, second] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let options = QueryOptions {
+      attribute_locations: true,
+      ..Default::default()
+    };
+    let mut queried = Vec::new();
+    for _ in 0..2 {
+      let other = gg
+        .related_files(&blame, 1, &options)
+        .await?
+        .into_iter()
+        .find(|c| c.path.as_ref().is_some_and(|p| p == "other.rs"))
+        .unwrap();
+      let ranges: Vec<_> = other.attributions.iter().map(|a| (a.range.clone(), a.commit)).collect();
+      assert_eq!(ranges, vec![(1..4, first), (4..5, second)]);
+      assert_eq!(other.locations, vec![1..5]);
+      queried.push(other.attributions);
+    }
+    assert_eq!(queried[0], queried[1]);
+    assert_ne!(queried[0][0].color_seed, queried[0][1].color_seed);
+
+    let plain = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    assert!(plain.iter().all(|c| c.attributions.is_empty()));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_streamed_candidates_match_final_ranking() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "a\nb\n");
+    repo.write("small.rs", "1\n");
+    repo.write("large.rs", "1\n2\n3\n");
+    let commit = repo.commit("add files");
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let mut streamed = Vec::new();
+    let options = QueryOptions::default();
+    let result = gg
+      .stream_related_lines(&blame, 1..=1, &options, &mut |c| {
+        streamed.push((c.path.clone().unwrap(), c.touched_lines))
+      })
+      .await?;
+    let ranked = gg.related_files(&blame, 1, &options).await?;
+
+    streamed.sort();
+    let mut final_paths: Vec<_> = result
+      .candidates
+      .iter()
+      .map(|c| (c.path.clone().unwrap(), c.touched_lines))
+      .collect();
+    final_paths.sort();
+    assert_eq!(streamed, final_paths);
+    let ranked: Vec<_> = ranked.into_iter().map(|c| c.path.unwrap()).collect();
+    assert_eq!(ranked, vec!["large.rs", "small.rs"]);
+    assert_eq!(result.candidates[0].normalized_weight, 1.0);
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_concurrent_queries_share_locating_cap() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    for i in 0..6 {
+      repo.write(&format!("other{}.rs", i), "other\n");
+    }
+    let commit = repo.commit("add src.rs with six related files");
+
+    let gg = repo.graph_with(GraphConfig {
+      max_locating_tasks: 2,
+      ..Default::default()
+    });
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let mut queries = tokio::task::JoinSet::new();
+    for _ in 0..8 {
+      let gg = gg.clone();
+      let blame = blame.clone();
+      queries.spawn(async move { gg.related_files(&blame, 1, &QueryOptions::default()).await });
+    }
+    while let Some(result) = queries.join_next().await {
+      assert_eq!(result??.len(), 6);
+    }
+    assert_eq!(gg.inner.locating_peak.load(Ordering::SeqCst), 2);
+    Ok(())
+  }
+
+  #[cfg(feature = "trace")]
+  #[tokio::test]
+  async fn test_trace_records_query_phases() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "other\n");
+    let commit = repo.commit("add src.rs and other.rs");
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let trace = Arc::new(crate::trace::QueryTrace::new());
+    let options = QueryOptions {
+      trace: Some(trace.clone()),
+      ..Default::default()
+    };
+    assert_eq!(gg.related_files(&blame, 1, &options).await?.len(), 1);
+
+    let json: serde_json::Value = serde_json::from_str(&trace.to_chrome_json())?;
+    let mut names: Vec<_> = json["traceEvents"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|e| e["name"].as_str().unwrap().to_owned())
+      .collect();
+    names.sort();
+    assert_eq!(
+      names,
+      ["blame other.rs", "collect commits", "locate candidates", "locate other.rs", "rank", "wait for slot"]
+    );
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_commit_info_describes_linking_commit() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "other\n");
+    let commit = repo.commit("add src.rs and other.rs\n\nwith a body");
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let plain = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    assert!(plain[0].commit_meta.is_none());
+    let options = QueryOptions {
+      commit_info: true,
+      ..Default::default()
+    };
+    let candidates = gg.related_files(&blame, 1, &options).await?;
+    assert_eq!(candidates[0].commit, commit);
+    let meta = candidates[0].commit_meta.as_ref().unwrap();
+    assert_eq!(meta.author_name, "Test");
+    assert_eq!(meta.summary, "add src.rs and other.rs");
+    let committed_at: i64 = repo.git(&["log", "-1", "--format=%ct"]).parse()?;
+    assert_eq!(meta.committed_at, committed_at);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_commit_info_names_the_canonical_author() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write(".mailmap", "Ann Author <ann@example.com> <test@example.com>\n");
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "other\n");
+    let commit = repo.commit("add src.rs and other.rs");
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let options = QueryOptions {
+      commit_info: true,
+      ..Default::default()
+    };
+    let candidates = gg.related_files(&blame, 1, &options).await?;
+    let meta = candidates.iter().find_map(|c| c.commit_meta.as_ref()).unwrap();
+    assert_eq!((meta.author_name.as_bstr(), meta.author_email.as_bstr()), ("Ann Author".into(), "ann@example.com".into()));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_neighborhood_signature_tracks_relevant_commits() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("other.rs", "other\n");
+    let first = repo.commit("add src.rs and other.rs");
+
+    let signature = |gg: &LocalGitGraph| {
+      let gg = gg.clone();
+      async move {
+        let blame = gg.blame("src.rs".into(), None).await?;
+        blame.wait_for_ready().await;
+        gg.neighborhood_signature(&blame, 1)
+      }
+    };
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&first)?;
+    let before = signature(&gg).await?;
+    gg.related_files(&gg.blame("src.rs".into(), None).await?, 1, &QueryOptions::default()).await?;
+    assert_eq!(signature(&gg).await?, before);
+    // another graph over the same history agrees
+    let other = repo.graph();
+    other.inner.load_cached_commit(&first)?;
+    assert_eq!(signature(&other).await?, before);
+
+    repo.write("src.rs", "one\ntwo\n");
+    repo.write("third.rs", "third\n");
+    let second = repo.commit("add line 2 and third.rs");
+    let gg = repo.graph();
+    for commit in [first, second] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    assert_ne!(signature(&gg).await?, before);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_exclude_recent_drops_fresh_coupling() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("old.rs", "old\n");
+    let historical = repo.commit("add src.rs and old.rs");
+    repo.write("src.rs", "one\ntwo\n");
+    repo.write("fresh.rs", "fresh\n");
+    let recent = repo.commit("add fresh.rs with line 2 of src.rs");
+
+    let gg = repo.graph();
+    for commit in [historical, recent] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let mut results = Vec::new();
+    for exclude_recent in [None, Some(ExcludeRecent::Count(1)), Some(ExcludeRecent::Since(historical.to_string()))] {
+      let options = QueryOptions {
+        exclude_recent,
+        ..Default::default()
+      };
+      let mut paths: Vec<_> = gg
+        .related_files(&blame, 1, &options)
+        .await?
+        .into_iter()
+        .map(|c| c.path.unwrap())
+        .collect();
+      paths.sort();
+      results.push(paths);
+    }
+    assert_eq!(results[0], vec!["fresh.rs", "old.rs"]);
+    assert_eq!(results[1], vec!["old.rs"]);
+    assert_eq!(results[2], vec!["old.rs"]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_prebuild_cache_caches_history_from_head() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    let mut commits = Vec::new();
+    for i in 0..5 {
+      repo.write("src.rs", format!("{}\n", i));
+      commits.push(repo.commit(&format!("commit {}", i)));
+    }
+
+    let gg = repo.graph_with(GraphConfig {
+      max_caching_tasks: 2,
+      ..Default::default()
+    });
+    let progress = Mutex::new(Vec::new());
+    let cached = gg
+      .prebuild_cache(Some(3), |done, total| progress.lock().unwrap().push((done, total)))
+      .await?;
+    assert_eq!(cached, 3);
+    assert_eq!(*progress.lock().unwrap(), vec![(1, 3), (2, 3), (3, 3)]);
+    for (i, commit) in commits.iter().enumerate() {
+      assert_eq!(gg.inner.disk_cache.is_commit_cached(commit)?, i >= 2, "commit {}", i);
+    }
+
+    // the newest three are skipped now
+    let cached = gg.prebuild_cache(None, |_, _| {}).await?;
+    assert_eq!(cached, 2);
+    for commit in &commits {
+      assert!(gg.inner.disk_cache.is_commit_cached(commit)?);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_list_paths_reports_renames_and_deletions() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.write("gone.rs", "gone\n");
+    repo.write("old.rs", "old\n");
+    let initial = repo.commit("initial");
+    repo.git(&["mv", "old.rs", "new.rs"]);
+    repo.git(&["rm", "-q", "gone.rs"]);
+    let change = repo.commit("rename old.rs, remove gone.rs");
+
+    let gg = repo.graph();
+    for commit in [initial, change] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let paths = gg.list_paths()?;
+    let info = |path: &str| paths.iter().find(|info| info.path == path).unwrap();
+
+    assert!(info("README").exists);
+    assert!(!info("gone.rs").exists);
+    assert_eq!(info("gone.rs").current_path, "gone.rs");
+    assert_eq!(info("old.rs").current_path, "new.rs");
+    assert!(info("old.rs").exists);
+    assert!(info("new.rs").exists);
+    assert!(paths.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+    assert_eq!(gg.interned_path_count()?, paths.len());
+    let old = info("old.rs").id;
+    let new = info("new.rs").id;
+    assert_eq!(
+      gg.debug_resolve_path(old)?,
+      ResolvedPathId {
+        path: Some("old.rs".into()),
+        current_id: new,
+        current_path: Some("new.rs".into()),
+      }
+    );
+    let unknown = paths.iter().map(|info| info.id).max().unwrap() + 1;
+    let resolved = gg.debug_resolve_path(unknown)?;
+    assert_eq!((resolved.path, resolved.current_path), (None, None));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_merge_renames_combines_weight_under_current_path() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("src.rs", "one\n");
+    repo.write("old.rs", "old\n");
+    let before_rename = repo.commit("add src.rs and old.rs");
+    repo.git(&["mv", "old.rs", "new.rs"]);
+    let rename = repo.commit("rename old.rs to new.rs");
+    repo.write("src.rs", "one\ntwo\n");
+    repo.write("new.rs", "old\nnew\n");
+    let after_rename = repo.commit("change src.rs and new.rs");
+
+    let gg = repo.graph();
+    for commit in [before_rename, rename, after_rename] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let is_new_rs = |c: &&Candidate| c.path.as_ref().is_some_and(|p| p == "new.rs");
+
+    let split = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    assert_eq!(split.iter().filter(is_new_rs).count(), 2);
+
+    let options = QueryOptions {
+      merge_renames: true,
+      ..Default::default()
+    };
+    let merged = gg.related_files(&blame, 1, &options).await?;
+    let new_rs: Vec<_> = merged.iter().filter(is_new_rs).collect();
+    assert_eq!(new_rs.len(), 1);
+
+    let mut commits: Vec<_> = new_rs[0].contributions.iter().map(|(c, _)| *c).collect();
+    commits.sort();
+    let mut expected = vec![before_rename, after_rename];
+    expected.sort();
+    assert_eq!(commits, expected);
+
+    // line 1 is the chunk from before the rename, line 2 the one right after it
+    let total: f32 = new_rs[0].contributions.iter().map(|(_, w)| w).sum();
+    assert!((total - (2.0 + 1.8)).abs() < 1e-5);
+    Ok(())
+  }
+
+  #[test]
+  fn test_calibration_is_monotonic_and_bounded() {
+    for calibration in [
+      Calibration::default(),
+      Calibration {
+        slope: 0.3,
+        intercept: 1.5,
+      },
+    ] {
+      let mut last = 0.0f32;
+      for weight in [-1000.0f32, -10.0, 0.0, 0.5, 2.0, 10.0, 100.0, 1000.0] {
+        let score = calibration.score(weight);
+        assert!((0.0..=1.0).contains(&score), "{} -> {}", weight, score);
+        assert!(score >= last, "{} -> {} after {}", weight, score, last);
+        last = score;
+      }
+    }
+    assert_eq!(Calibration::default().score(2.0), 0.5);
+  }
+
+  #[test]
+  fn test_commit_contribution_never_negative() {
+    let config = GraphConfig::default();
+    for dist in 0..64 {
+      assert!(config.commit_contribution(dist as f32, 0.0) >= 0.0, "dist {}", dist);
+    }
+    assert_eq!(config.commit_contribution(0.0, 0.0), 2.0);
+    assert_eq!(config.commit_contribution(3.0, 0.0), 2.0 - 3.0 * 0.2);
+    assert_eq!(config.commit_contribution(30.0, 0.0), 0.0);
+    assert_eq!(config.commit_contribution(30.0, 0.25), 0.25);
+    assert_eq!(config.commit_contribution(30.0, -1.0), 0.0);
+    let steep = GraphConfig {
+      distance_decay: 5.0,
+      ..GraphConfig::default()
+    };
+    assert_eq!(steep.commit_contribution(1.0, -0.5), 0.0);
+  }
+
+  #[tokio::test]
+  async fn test_diff_size_weights_rewrites_over_small_edits() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "1\n2\n3\n4\n5\n6\n7\n");
+    let mut commits = vec![repo.commit("initial")];
+    repo.write("src.rs", "one\ntwo\nthree\n4\n5\n6\n7\n");
+    repo.write("rewrite.rs", "rewrite\n");
+    commits.push(repo.commit("rewrite the top"));
+    repo.write("src.rs", "one\ntwo\nthree\n4\n5\n6\nseven\n");
+    repo.write("tweak.rs", "tweak\n");
+    commits.push(repo.commit("tweak the last line"));
+    repo.write("src.rs", "one\ntwo\nthree\n4\nfive\n6\nseven\n");
+    commits.push(repo.commit("change the queried line"));
+
+    let gg = repo.graph_with(GraphConfig {
+      cache_line_ranges: true,
+      ..Default::default()
+    });
+    for commit in &commits {
+      gg.inner.load_cached_commit(commit)?;
+    }
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+
+    let mut weights = Vec::new();
+    for weight_by_diff_size in [false, true] {
+      let options = QueryOptions {
+        weight_by_diff_size,
+        ..Default::default()
+      };
+      let candidates = gg.related_files(&blame, 5, &options).await?;
+      let weight = |path: &str| {
+        candidates
+          .iter()
+          .find(|c| c.path.as_ref().is_some_and(|p| p == path))
+          .map(|c| c.weight)
+          .unwrap()
+      };
+      weights.push((weight("rewrite.rs"), weight("tweak.rs")));
+    }
+
+    // both commits are two chunks away from line 5
+    assert_eq!(weights[0].0, weights[0].1);
+    assert!(weights[1].0 > weights[1].1, "{:?}", weights[1]);
+    // a one-line edit keeps its plain weight
+    assert_eq!(weights[1].1, weights[0].1);
+    Ok(())
+  }
+
+  /// Parses the hunks of `git show` output for a single file.
+  fn git_show_hunks(repo: &TestRepo, commit: ObjectId, path: &str) -> Vec<diff::Hunk> {
+    let output = repo.git(&["show", "--format=", "--no-color", &commit.to_string(), "--", path]);
+    let range = |spec: &str| -> (u32, u32) {
+      match spec[1..].split_once(',') {
+        Some((start, count)) => (start.parse().unwrap(), count.parse().unwrap()),
+        None => (spec[1..].parse().unwrap(), 1),
+      }
+    };
 
-        Ok(blame.clone())
+    let mut hunks: Vec<diff::Hunk> = vec![];
+    for line in output.lines().skip_while(|l| !l.starts_with("@@")) {
+      if let Some(header) = line.strip_prefix("@@ ") {
+        let mut specs = header.split(' ');
+        let (old_start, old_lines) = range(specs.next().unwrap());
+        let (new_start, new_lines) = range(specs.next().unwrap());
+        hunks.push(diff::Hunk { old_start, old_lines, new_start, new_lines, lines: vec![] });
+      } else {
+        hunks.last_mut().unwrap().lines.push(line.into());
       }
     }
+    hunks
   }
 
-
-  async fn find_related_locations(
-    self: &Arc<Self>,
-    revision: Option<ObjectId>,
-    path: &BStr,
-    interesting_shas: &HashSet<ObjectId>,
-  ) -> Option<Vec<Range<u32>>> {
-    let blame = match self.load_blame(revision, path.as_bstr(), false).await {
-      Ok(blame) => blame,
-      Err(_) => return None,
+  #[test]
+  fn test_candidate_diff_matches_git_show() -> anyhow::Result<()> {
+    let numbered = |changed: &[usize]| -> String {
+      (1..=20)
+        .map(|i| if changed.contains(&i) { format!("  changed {}\n", i) } else { format!("  line {}\n", i) })
+        .collect()
     };
 
-    if !blame.is_ready() {
-      let _ = tokio::time::timeout(time::Duration::from_millis(250), blame.wait_for_ready()).await;
-    }
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("old.txt", numbered(&[]));
+    let added = repo.commit("add old.txt");
+    repo.write("old.txt", numbered(&[2, 15]));
+    repo.write("other.txt", "other\n");
+    let modified = repo.commit("change two distant lines, add other.txt");
+    repo.git(&["mv", "old.txt", "new.txt"]);
+    let renamed = repo.commit("rename old.txt to new.txt");
 
-    let related_locations: Vec<Range<u32>> = blame.lines().into_iter().filter_map(|chunk| {
-      if interesting_shas.contains(&chunk.commit_id) {
-        Some(chunk.range_in_blamed_file)
-      } else {
-        None
-      }
-    }).collect();
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&renamed)?;
+    let interned = gg.interned_path_count()?;
 
-    if related_locations.is_empty() {
-      None
-    } else {
-      Some(related_locations)
-    }
+    let diff = gg.candidate_diff("new.txt".into(), modified)?.unwrap();
+    assert_eq!(diff.new_path, "old.txt");
+    assert_eq!(diff.hunks.len(), 2);
+    assert_eq!(diff.hunks, git_show_hunks(&repo, modified, "old.txt"));
+
+    let diff = gg.candidate_diff("new.txt".into(), added)?.unwrap();
+    assert_eq!(diff.hunks, git_show_hunks(&repo, added, "old.txt"));
+
+    let diff = gg.candidate_diff("new.txt".into(), renamed)?.unwrap();
+    assert_eq!(diff.old_path, Some("old.txt".into()));
+    assert!(diff.hunks.is_empty());
+
+    assert!(gg.candidate_diff("README".into(), modified)?.is_none());
+    // looking at diffs leaves the cache's paths alone, like other.txt's
+    assert_eq!(gg.interned_path_count()?, interned);
+    assert_eq!(gg.inner.disk_cache.path_id("other.txt".into())?, None);
+    Ok(())
   }
 
-  fn load_cached_commit(self: &Arc<Self>, commit_sha: &ObjectId) -> anyhow::Result<()> {
-    if self.disk_cache.is_commit_cached(commit_sha)? {
+  #[test]
+  fn test_resolve_revision() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    let first = repo.commit("first");
+    let second = repo.commit("second");
+    let third = repo.commit("third");
+    repo.git(&["tag", "v1.0", &second.to_string()]);
+    repo.git(&["tag", "-a", "-m", "release", "v1.1", &third.to_string()]);
+
+    let gg = repo.graph();
+    assert_eq!(gg.resolve_revision("v1.0")?, second);
+    assert_eq!(gg.resolve_revision("v1.1")?, third);
+    assert_eq!(gg.resolve_revision("HEAD~2")?, first);
+    assert_eq!(gg.resolve_revision("v1.0^")?, first);
+    assert_eq!(gg.resolve_revision(&third.to_string())?, third);
+    assert!(gg.resolve_revision("no-such-branch").is_err());
+    assert!(gg.resolve_revision("HEAD~5").is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_renamed_path_resolves_forward() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("README", "readme\n");
+    repo.commit("initial");
+    repo.write("old.rs", "fn a() {}\n");
+    let added = repo.commit("add old.rs");
+    repo.git(&["mv", "old.rs", "new.rs"]);
+    let renamed = repo.commit("rename old.rs to new.rs");
+
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&added)?;
+    gg.inner.load_cached_commit(&renamed)?;
+
+    let commit = gg.inner.disk_cache.cached_commit(&added)?.unwrap();
+    assert_eq!(commit.changed_paths.len(), 1);
+    assert_eq!(gg.inner.disk_cache.resolve_path(commit.changed_paths[0])?, Some("new.rs".into()));
+    Ok(())
+  }
+
+  #[test]
+  fn test_commit_meta_is_stored_with_the_commit() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write(".mailmap", "Ann <ann@example.com> <test@example.com>\n");
+    let commit = repo.commit_at("add mailmap\n\nwith a body", 1_700_000_000);
+    let gg = repo.graph();
+
+    gg.inner.load_cached_commit(&commit)?;
+    let stored = gg.inner.disk_cache.commit_meta(&commit)?.unwrap();
+    assert_eq!(stored.author_email, "test@example.com");
+    assert_eq!(stored.committed_at, 1_700_000_000);
+    assert_eq!(stored.summary, "add mailmap");
+
+    // read back mapped through the mailmap
+    let meta = gg.inner.commit_meta(&commit)?;
+    assert_eq!((meta.author_name.as_bstr(), meta.author_email.as_bstr()), ("Ann".into(), "ann@example.com".into()));
+    assert_eq!(meta.committed_at, 1_700_000_000);
+
+    // a commit cached without metadata gets it on first use
+    repo.write("file.txt", "one\n");
+    let later = repo.commit("add file.txt");
+    gg.inner.disk_cache.update_cached_commit(&later, crate::cache::CachedCommit { changed_paths: vec![] })?;
+    assert!(gg.inner.disk_cache.commit_meta(&later)?.is_none());
+    assert_eq!(gg.inner.commit_meta(&later)?.summary, "add file.txt");
+    assert!(gg.inner.disk_cache.commit_meta(&later)?.is_some());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_stats_count_cache_hits_and_queries() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    repo.write("a.rs", "a\n");
+    let commit = repo.commit("add src.rs and a.rs");
+
+    let gg = repo.graph();
+    assert_eq!(gg.stats(), GraphStats::default());
+    gg.inner.load_cached_commit(&commit)?;
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    gg.blame("src.rs".into(), None).await?;
+    let stats = gg.stats();
+    assert_eq!((stats.blame_cache_hits, stats.blame_cache_misses), (1, 1));
+
+    // queries blame their candidates too, so those counters move on from here
+    gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    let stats = gg.stats();
+    // the blame queues its commit for caching too, which may have run by now
+    assert!(stats.commit_cache_hits >= 1);
+    assert_eq!((stats.commit_cache_misses, stats.commits_diffed), (1, 1));
+    assert_eq!(stats.queries, 2);
+    assert!(stats.average_query_latency > time::Duration::ZERO);
+    Ok(())
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_uncommitted_lines_are_flagged_and_skipped() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\ntwo\nthree\n");
+    let commit = repo.commit("add src.rs");
+    // git reports lines only in the working tree under the all-zero id; stand in for a git that
+    // blames them, since this one always blames a commit
+    let fake_git = repo.path().join("fake-git");
+    std::fs::write(
+      &fake_git,
+      format!(
+        "#!/bin/sh\nprintf '{} 1 1 2\\nfilename src.rs\\n{} 3 3 1\\nfilename src.rs\\n'\n",
+        ObjectId::null(gix::hash::Kind::Sha1),
+        commit
+      ),
+    )?;
+    std::fs::set_permissions(&fake_git, std::fs::Permissions::from_mode(0o755))?;
+    let gg = repo.graph_with(GraphConfig {
+      git_binary: fake_git,
+      ..GraphConfig::default()
+    });
+
+    let blame = gg.blame("src.rs".into(), None).await?;
+    gg.ensure_ready(&blame, true).await?;
+    let uncommitted: Vec<bool> = blame.lines().iter().map(|line| line.is_uncommitted()).collect();
+    assert_eq!(uncommitted, vec![true, false]);
+
+    let options = QueryOptions {
+      authors: Some(vec!["test@example.com".into()]),
+      ..QueryOptions::default()
+    };
+    let related = gg.query_related_files(&blame, 1, &options).await?;
+    assert!(related.uncached_commits.iter().all(|commit| !commit.is_null()));
+    // only the real commit was queued for caching
+    gg.shutdown(time::Duration::from_secs(10)).await?;
+    assert_eq!(gg.stats().commit_cache_misses, 1);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_native_blame_backend_needs_no_git_binary() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    repo.write("a.rs", "a\n");
+    let commit = repo.commit("add src.rs and a.rs");
+    let config = GraphConfig {
+      blame_backend: BlameBackend::Native,
+      git_binary: repo.path().join("no-such-git"),
+      cache_backend: CacheBackend::Memory,
+      ..GraphConfig::default()
+    };
+    if !cfg!(feature = "native-blame") {
+      let Err(err) = LocalGitGraph::new(repo.path().to_str().unwrap(), config) else {
+        panic!("opened a graph with a blame backend that isn't built in");
+      };
+      assert!(err.to_string().contains("native-blame feature"), "{}", err);
       return Ok(());
     }
 
-    let path_cache = &self.disk_cache;
-    let repo = self.repo.to_thread_local();
-    let commit = repo.find_commit(*commit_sha)?;
-    let tree = commit.tree()?;
-    let ancestors = commit.parent_ids().next().unwrap();
-
-    let parent_tree = repo.find_commit(ancestors)?.tree()?;
-    let mut changed = Vec::new();
-
-    let mut diff = parent_tree.changes()?;
-    diff.for_each_to_obtain_tree(&tree, |change| -> anyhow::Result<Action> {
-      match change {
-        Change::Addition {
-          entry_mode,
-          location,
-          ..
-        } => {
-          if entry_mode.is_blob_or_symlink() {
-            changed.push(path_cache.cache_path(location)?);
-          }
-        }
-        Change::Deletion { .. } => {
-          // not interesting
-        }
-        Change::Modification {
-          entry_mode,
-          location,
-          ..
-        } => {
-          if entry_mode.is_blob_or_symlink() {
-            changed.push(path_cache.cache_path(location)?);
-          }
-        }
-        Change::Rewrite {
-          entry_mode,
-          location,
-          ..
-        } => {
-          if entry_mode.is_blob_or_symlink() {
-            changed.push(path_cache.cache_path(location)?);
-          }
-        }
-      }
-      Ok(Action::Continue)
-    })?;
+    // options gix can't honor are refused rather than ignored
+    let unsupported = GraphConfig {
+      ignore_whitespace: true,
+      ignore_revs: vec!["HEAD".into()],
+      ..config.clone()
+    };
+    let Err(err) = LocalGitGraph::new(repo.path().to_str().unwrap(), unsupported) else {
+      panic!("opened a native blame graph with options it can't honor");
+    };
+    assert!(err.to_string().contains("ignoring whitespace, ignoring revisions"), "{}", err);
 
-    changed.sort();
-    self.disk_cache.update_cached_commit(
-      commit_sha,
-      crate::cache::CachedCommit {
-        changed_paths: changed,
-      },
-    )?;
+    let gg = LocalGitGraph::new(repo.path().to_str().unwrap(), config)?;
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    gg.ensure_ready(&blame, true).await?;
+    assert!(blame.lines().iter().all(|line| line.commit_id == commit));
+    let candidates = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+    assert_eq!(candidates.iter().map(|c| c.path.clone()).collect::<Vec<_>>(), vec![Some("a.rs".into())]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_blame_all_reports_each_file_and_reuses_open_blames() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("a.rs", "a\n");
+    repo.write("b.rs", "b\n");
+    let head = repo.commit("add a.rs and b.rs");
+    let gg = repo.graph();
 
+    let open = gg.blame("a.rs".into(), None).await?;
+    let paths: Vec<BString> = vec!["a.rs".into(), "missing.rs".into(), "b.rs".into()];
+    let blames = gg.blame_all(&paths, None).await?;
+    assert_eq!(blames.len(), 3);
+    assert!(Arc::ptr_eq(blames[0].as_ref().unwrap(), &open));
+    assert!(matches!(&blames[1], Err(GraphError::PathNotTracked { revision, .. }) if *revision == head));
+    let b = blames[2].as_ref().unwrap();
+    gg.ensure_ready(b, true).await?;
+    assert!(b.lines().iter().all(|line| line.commit_id == head));
     Ok(())
   }
-}
 
-pub(crate) struct LocalGitGraph {
-  inner: Arc<InnerGraph>,
-}
+  #[tokio::test]
+  async fn test_path_spellings_share_one_blame_and_escapes_are_rejected() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src/foo.rs", "fn foo() {}\n");
+    repo.commit("add src/foo.rs");
+    let gg = repo.graph();
 
-impl Clone for LocalGitGraph {
-  fn clone(&self) -> Self {
-    LocalGitGraph {
-      inner: self.inner.clone(),
+    let blame = gg.blame("src/foo.rs".into(), None).await?;
+    let absolute = repo.path().join("src/foo.rs");
+    let absolute = absolute.to_str().unwrap();
+    for spelling in ["./src/foo.rs", "src//foo.rs/", "src/./../src/foo.rs", absolute] {
+      let same = gg.blame(spelling.into(), None).await?;
+      assert!(Arc::ptr_eq(&same, &blame), "{}", spelling);
     }
-  }
-}
+    assert_eq!(blame.file_path, "src/foo.rs");
 
-impl LocalGitGraph {
-  pub(crate) fn new(repo: &str) -> anyhow::Result<Self> {
-    let mut repo = gix::open(repo)?;
-    repo.object_cache_size(Some(16 * 1024 * 1024));
+    let elsewhere = repo.path().parent().unwrap().join("foo.rs");
+    for escaping in ["../foo.rs", "src/../../foo.rs", elsewhere.to_str().unwrap()] {
+      assert!(
+        matches!(gg.blame(escaping.into(), None).await, Err(GraphError::PathOutsideRepo { .. })),
+        "{}",
+        escaping
+      );
+    }
+    // a directory, however it's spelled, has no blame
+    assert!(matches!(
+      gg.blame("./src/".into(), None).await,
+      Err(GraphError::PathNotTracked { .. })
+    ));
+    Ok(())
+  }
 
-    let inner = Arc::new(InnerGraph {
-      repo: repo.into_sync(),
-      disk_cache: Box::new(crate::sqlite::SqliteCache::new()?),
-      blame_cache: DashMap::new(),
+  #[tokio::test]
+  async fn test_prefetched_blames_are_found_when_opening_the_files() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    for name in ["a.rs", "b.rs", "c.rs"] {
+      repo.write(name, "fn f() {}\n");
+    }
+    repo.commit("add a.rs, b.rs and c.rs");
+    let gg = repo.graph_with(GraphConfig {
+      blame_cache_capacity: 2,
+      ..GraphConfig::default()
     });
 
-    Ok(LocalGitGraph { inner })
+    let paths: Vec<BString> = vec!["a.rs".into(), "b.rs".into(), "c.rs".into()];
+    gg.prefetch(paths)?;
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    while gg.stats().blame_cache_misses < 2 {
+      assert!(time::Instant::now() < deadline, "prefetching never started");
+      tokio::time::sleep(time::Duration::from_millis(10)).await;
+    }
+
+    for name in ["a.rs", "b.rs"] {
+      gg.blame(name.into(), None).await?;
+    }
+    assert_eq!(gg.stats().blame_cache_misses, 2);
+    assert_eq!(gg.stats().blame_cache_hits, 2);
+    // past the capacity, so left for opening it
+    gg.blame("c.rs".into(), None).await?;
+    assert_eq!(gg.stats().blame_cache_misses, 3);
+    Ok(())
   }
 
-  pub(crate) async fn related_files(
-    &self,
-    blame: &Arc<blame::LazyBlame>,
-    lineno: usize,
-  ) -> anyhow::Result<Vec<Candidate>> {
-    let blame_lines = blame.lines();
+  #[tokio::test]
+  async fn test_query_of_an_unopened_file_blames_only_around_the_line() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    let mut contents: Vec<String> = (1..=300).map(|i| format!("line {}\n", i)).collect();
+    repo.write("src.rs", contents.concat());
+    repo.write("a.rs", "a\n");
+    let top = repo.commit("add src.rs and a.rs");
+    contents[299] = "changed\n".into();
+    repo.write("src.rs", contents.concat());
+    repo.write("b.rs", "b\n");
+    let bottom = repo.commit("change the last line, add b.rs");
 
-    let search = blame_lines
-      .binary_search_by(|x| {
-        return x.range_in_blamed_file.start.cmp(&(lineno as u32));
-      })
-      .unwrap_or_else(|x| x) as isize;
+    let gg = repo.graph_with(GraphConfig {
+      blame_window_lines: 10,
+      // wide enough that the full blame reaches the last line from the first
+      chunk_range: 10,
+      ..GraphConfig::default()
+    });
+    for commit in [top, bottom] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let paths = |related: RelatedFiles| {
+      let mut paths: Vec<BString> = related.candidates.into_iter().filter_map(|c| c.path).collect();
+      paths.sort();
+      paths
+    };
 
-    const BLAME_CHUNK_RANGE: isize = 6;
-    let inner = self.inner.clone();
-    let mut candidate_files: HashMap<u32, Candidate> = HashMap::new();
+    let windowed = gg.related_files_at("src.rs".into(), None, 1, &QueryOptions::default()).await?;
+    assert_eq!(paths(windowed), vec![BString::from("a.rs")]);
+    let head = gg.head_commit()?;
+    assert!(!gg.inner.blame_cache.contains(&gg.inner.blame_key(Some(head), "src.rs".into())?));
+    let window = gg.inner.load_blame_window(head, "src.rs".into(), 1..11)?;
+    window.wait_for_ready().await;
+    assert_eq!(window.lines().last().unwrap().range_in_blamed_file.end, 11);
+    let err = gg.related_files(&window, 200, &QueryOptions::default()).await.unwrap_err();
+    assert!(err.to_string().contains("weren't blamed"), "{}", err);
 
-    let start_ofs = max(0, search - BLAME_CHUNK_RANGE / 2);
-    let end_ofs = min(search + BLAME_CHUNK_RANGE / 2, blame_lines.len() as isize);
-    let mut interesting_shas: HashSet<ObjectId> = HashSet::new();
+    // an open file's full blame is used as it is
+    let open = gg.blame("src.rs".into(), None).await?;
+    gg.ensure_ready(&open, true).await?;
+    let full = gg.related_files_at("src.rs".into(), None, 1, &QueryOptions::default()).await?;
+    assert_eq!(paths(full), vec![BString::from("a.rs"), BString::from("b.rs")]);
+    Ok(())
+  }
 
-    for rng in start_ofs..end_ofs {
-      let blame_root = &blame_lines[rng as usize];
-      let dist_from_search = (rng - search).abs() as f32;
+  #[tokio::test]
+  async fn test_crlf_lines_are_numbered_like_git_blame_does() -> anyhow::Result<()> {
+    // committed with CRLF endings, and checked out with CRLF from LF blobs
+    for autocrlf in ["false", "true"] {
+      let repo = TestRepo::new();
+      repo.git(&["config", "core.autocrlf", autocrlf]);
+      let mut src = String::new();
+      let mut commits = Vec::new();
+      for i in 0..4 {
+        // a lone carriage return inside a line doesn't end it
+        src.push_str(&format!("line\r{}\r\n", i));
+        repo.write("src.rs", &src);
+        repo.write(&format!("f{}.rs", i), "f\r\n");
+        commits.push(repo.commit(&format!("add line {} and f{}.rs", i, i)));
+      }
 
-      if let Some(commit) = inner.disk_cache.cached_commit(&blame_root.commit_id)? {
-        interesting_shas.insert(blame_root.commit_id);
+      let gg = repo.graph_with(GraphConfig {
+        chunk_range: 0,
+        ..GraphConfig::default()
+      });
+      for commit in &commits {
+        gg.inner.load_cached_commit(commit)?;
+      }
+      let blame = gg.blame("src.rs".into(), None).await?;
+      blame.wait_for_ready().await;
+      let ranges: Vec<_> = blame.lines().into_iter().map(|entry| entry.range_in_blamed_file).collect();
+      assert_eq!(ranges, vec![1..2, 2..3, 3..4, 4..5], "autocrlf={}", autocrlf);
+      for lineno in 1..=4 {
+        let own = BString::from(format!("f{}.rs", lineno - 1));
+        let open = gg.related_files(&blame, lineno, &QueryOptions::default()).await?;
+        assert_eq!(open[0].path.as_ref(), Some(&own), "line {} with autocrlf={}", lineno, autocrlf);
+        let unopened = gg.related_files_at("src.rs".into(), None, lineno, &QueryOptions::default()).await?;
+        assert_eq!(unopened.candidates[0].path.as_ref(), Some(&own));
+      }
+    }
+    Ok(())
+  }
 
-        for path_id in commit.changed_paths.iter() {
-          let entry = candidate_files.entry(*path_id).or_insert_with(|| {
-            Candidate{
-              path: None,
-              locations: vec![],
-              touched_lines: 0,
-              weight: 0.0,
-              commit: blame_root.commit_id,
-            }
-          });
+  #[tokio::test]
+  async fn test_binary_files_can_be_left_out_of_changed_paths() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "fn main() {}\n");
+    repo.write("logo.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+    repo.write("notes.txt", "notes\n");
+    let commit = repo.commit("add src.rs with its assets");
 
-          entry.weight += 2.0f32 - dist_from_search * 0.2;
-        }
+    for skip_binary_files in [false, true] {
+      let gg = repo.graph_with(GraphConfig {
+        skip_binary_files,
+        ..GraphConfig::default()
+      });
+      gg.inner.load_cached_commit(&commit)?;
+      let blame = gg.blame("src.rs".into(), None).await?;
+      blame.wait_for_ready().await;
+      let mut paths: Vec<BString> = gg
+        .related_files(&blame, 1, &QueryOptions::default())
+        .await?
+        .into_iter()
+        .filter_map(|candidate| candidate.path)
+        .collect();
+      paths.sort();
+      let mut expected = vec![BString::from("notes.txt")];
+      if !skip_binary_files {
+        expected.insert(0, "logo.png".into());
       }
+      assert_eq!(paths, expected, "skip_binary_files={}", skip_binary_files);
     }
+    Ok(())
+  }
 
-    if candidate_files.is_empty() {
-      return Ok(Vec::new());
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_symlinks_and_submodules_stay_out_of_changed_paths() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    let vendored = repo.commit("vendored history");
+    repo.write("src.rs", "fn main() {}\n");
+    repo.write("notes.txt", "notes\n");
+    std::os::unix::fs::symlink("notes.txt", repo.path().join("link"))?;
+    repo.git(&["add", "-A"]);
+    let gitlink = format!("160000,{},vendor/lib", vendored);
+    repo.git(&["update-index", "--add", "--cacheinfo", &gitlink]);
+    repo.git(&["commit", "-q", "-m", "add src.rs with a symlink and a submodule"]);
+    let commit = ObjectId::from_hex(repo.git(&["rev-parse", "HEAD"]).as_bytes())?;
+    assert!(repo.git(&["ls-tree", "-r", "HEAD"]).contains("160000 commit"));
+
+    for skip_symlinks in [true, false] {
+      let gg = repo.graph_with(GraphConfig {
+        skip_symlinks,
+        ..GraphConfig::default()
+      });
+      gg.inner.load_cached_commit(&commit)?;
+      let mut changed: Vec<BString> = gg.commit_files("HEAD")?;
+      changed.sort();
+      let mut expected = vec![BString::from("notes.txt"), "src.rs".into()];
+      if !skip_symlinks {
+        expected.insert(0, "link".into());
+      }
+      assert_eq!(changed, expected, "skip_symlinks={}", skip_symlinks);
+
+      let blame = gg.blame("src.rs".into(), None).await?;
+      blame.wait_for_ready().await;
+      let paths: Vec<BString> = gg
+        .related_files(&blame, 1, &QueryOptions::default())
+        .await?
+        .into_iter()
+        .filter_map(|candidate| candidate.path)
+        .collect();
+      assert!(!paths.contains(&BString::from("vendor/lib")));
+      assert_eq!(paths.contains(&BString::from("link")), !skip_symlinks);
     }
+    Ok(())
+  }
 
-    let mut candidate_files: Vec<_> = candidate_files.into_iter().collect();
-    candidate_files.sort_by(|a, b| a.0.cmp(&b.0));
-    candidate_files.sort_by(|a, b| b.1.weight.partial_cmp(&a.1.weight).unwrap());
-    candidate_files.truncate(20);
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_candidates_located_from_a_loading_blame_are_partial() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
 
-    let mut joinset = tokio::task::JoinSet::new();
-    let interesting_shas = Arc::new(interesting_shas);
+    let repo = TestRepo::new();
+    repo.write("src.rs", "fn main() {}\n");
+    repo.write("slow.rs", "fn slow() {}\n");
+    let commit = repo.commit("add src.rs and slow.rs");
+    // blames slow.rs right away but takes a while to exit, like a big file's blame
+    let slow_git = repo.path().join("slow-git");
+    std::fs::write(
+      &slow_git,
+      "#!/bin/sh\ncase \"$*\" in\n*slow.rs*) git \"$@\"; status=$?; sleep 1; exit $status;;\n*) exec git \"$@\";;\nesac\n",
+    )?;
+    std::fs::set_permissions(&slow_git, std::fs::Permissions::from_mode(0o755))?;
 
-    for (index, (path_id, w)) in candidate_files.iter_mut().enumerate() {
-      if let Some(path) = inner.disk_cache.resolve_path(*path_id)? {
-        w.path = Some(path.clone());
+    for locate_timeout in [Some(time::Duration::from_millis(300)), None] {
+      let gg = repo.graph_with(GraphConfig {
+        git_binary: slow_git.clone(),
+        locate_timeout,
+        ..GraphConfig::default()
+      });
+      gg.inner.load_cached_commit(&commit)?;
+      let blame = gg.blame("src.rs".into(), None).await?;
+      gg.ensure_ready(&blame, true).await?;
+      let candidates = gg.related_files(&blame, 1, &QueryOptions::default()).await?;
+      assert_eq!(candidates.len(), 1);
+      assert_eq!(candidates[0].locations, vec![1..2]);
+      assert_eq!(candidates[0].partial, locate_timeout.is_some());
+      gg.shutdown(time::Duration::from_secs(10)).await?;
+    }
+    Ok(())
+  }
 
-        let inner = inner.clone();
-        let interesting_shas = interesting_shas.clone();
-        let commit_sha = w.commit.clone();
-        joinset.spawn(async move {
-          (
-            index,
-            inner
-              .find_related_locations(Some(commit_sha), path.as_ref(), &interesting_shas)
-              .await,
-          )
-        });
+  #[tokio::test]
+  async fn test_copy_detection_follows_code_moved_between_files() -> anyhow::Result<()> {
+    let moved = "fn parse_configuration(input: &str) -> Configuration {\n  Configuration::from_source(input)\n}\n";
+    let repo = TestRepo::new();
+    repo.write("a.rs", format!("fn stays() {{}}\n{}", moved));
+    repo.write("c.rs", "fn written_with_parse_configuration() {}\n");
+    let written = repo.commit("add parse_configuration to a.rs, with c.rs");
+    repo.write("b.rs", "fn other() {}\n");
+    let other = repo.commit("add b.rs");
+    repo.write("a.rs", "fn stays() {}\n");
+    repo.write("b.rs", format!("fn other() {{}}\n{}", moved));
+    let moving = repo.commit("move parse_configuration to b.rs");
+
+    for detect_copies in [false, true] {
+      let gg = repo.graph_with(GraphConfig {
+        detect_copies,
+        ..GraphConfig::default()
+      });
+      for commit in [written, other, moving] {
+        gg.inner.load_cached_commit(&commit)?;
+      }
+      let blame = gg.blame("b.rs".into(), None).await?;
+      gg.ensure_ready(&blame, true).await?;
+      let moved_line = &blame.lines()[blame::chunk_index_for_line(&blame.lines(), 2)];
+      let mut paths: Vec<BString> = gg
+        .related_files(&blame, 2, &QueryOptions::default())
+        .await?
+        .into_iter()
+        .filter_map(|candidate| candidate.path)
+        .collect();
+      paths.sort();
+      if detect_copies {
+        assert_eq!((moved_line.commit_id, moved_line.original_path.as_ref()), (written, BStr::new("a.rs")));
+        assert!(paths.contains(&"c.rs".into()), "{:?}", paths);
+      } else {
+        assert_eq!(moved_line.commit_id, moving);
+        assert!(!paths.contains(&"c.rs".into()), "{:?}", paths);
       }
+      // a.rs is still around, the lines coming from it don't make it a rename of b.rs
+      let a = gg.inner.disk_cache.cache_path("a.rs".into())?;
+      assert_eq!(gg.inner.disk_cache.resolve_path(a)?, Some("a.rs".into()));
     }
+    Ok(())
+  }
 
-    while let Some(res) = joinset.join_next().await {
-      if let (index, Some(related_locs)) = res? {
-        let w = &mut candidate_files[index].1;
-        w.locations = related_locs;
-        w.touched_lines = w.locations.iter().map(|loc| loc.end - loc.start).sum();
-        assert_ne!(w.touched_lines, 0);
-      }
+  #[tokio::test]
+  async fn test_lines_outside_the_file_are_an_error() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\ntwo\nthree\n");
+    let commit = repo.commit("add src.rs alone");
+    let gg = repo.graph();
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    gg.ensure_ready(&blame, true).await?;
+    let options = QueryOptions::default();
+
+    // an isolated line has no related files, which is no error
+    for lineno in [1, 3, 4] {
+      assert!(gg.related_files(&blame, lineno, &options).await?.is_empty());
+    }
+    assert!(gg.related_files_at("src.rs".into(), None, 4, &options).await?.candidates.is_empty());
+    for lineno in [0, 5, 1000] {
+      let e = GraphError::from(gg.related_files(&blame, lineno, &options).await.unwrap_err());
+      assert!(matches!(e, GraphError::LineOutOfRange { line, line_count: 3, .. } if line == lineno), "{}", e);
+      let e = gg.related_files_at("src.rs".into(), None, lineno, &options).await.unwrap_err();
+      assert!(matches!(e, GraphError::LineOutOfRange { .. }), "{}", e);
     }
+    let e = GraphError::from(gg.related_files_in_range(&blame, 2..=7, &options).await.unwrap_err());
+    assert!(e.to_string().starts_with("LineOutOfRange: line 7 is outside 'src.rs'"), "{}", e);
+    assert!(gg.neighborhood_signature(&blame, 9).is_err());
+    Ok(())
+  }
 
-    let largest_touched_file = candidate_files
-      .iter()
-      .max_by(|a, b| a.1.touched_lines.cmp(&b.1.touched_lines))
-      .map(|x| x.1.touched_lines)
-      .unwrap() as f32;
+  #[tokio::test]
+  async fn test_history_bounds_leave_old_commits_uncached() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?.as_secs() as i64;
+    repo.write("src.rs", "one\n");
+    repo.write("old.rs", "old\n");
+    let ancient = repo.commit_at("add src.rs with old.rs", now - 400 * 24 * 60 * 60);
+    repo.write("src.rs", "one\ntwo\n");
+    repo.write("mid.rs", "mid\n");
+    let middle = repo.commit_at("extend src.rs with mid.rs", now - 10 * 24 * 60 * 60);
+    repo.write("src.rs", "one\ntwo\nthree\n");
+    repo.write("new.rs", "new\n");
+    let newest = repo.commit("extend src.rs with new.rs");
 
-    for (_, candidate) in candidate_files.iter_mut() {
-      candidate.weight *= candidate.touched_lines as f32 / largest_touched_file;
+    let bounds = [
+      (Some(time::Duration::from_secs(30 * 24 * 60 * 60)), None, vec![middle, newest]),
+      (None, Some(1), vec![newest]),
+    ];
+    for (max_commit_age, max_commit_depth, expected) in bounds {
+      let gg = repo.graph_with(GraphConfig {
+        max_commit_age,
+        max_commit_depth,
+        ..GraphConfig::default()
+      });
+      let blame = gg.blame("src.rs".into(), None).await?;
+      blame.wait_for_ready().await;
+      gg.inner.wait_for_tasks().await;
+      for commit in [ancient, middle, newest] {
+        let cached = gg.inner.disk_cache.is_commit_cached(&commit)?;
+        assert_eq!(cached, expected.contains(&commit), "{} with {:?}", commit, max_commit_depth);
+      }
+
+      // nothing will cache the bounded commits, so they aren't reported as pending either
+      let related = gg.query_related_files(&blame, 1, &QueryOptions::default()).await?;
+      assert!(related.uncached_commits.is_empty());
+      assert!(related.candidates.iter().all(|candidate| candidate.path != Some("old.rs".into())));
     }
+    Ok(())
+  }
 
-    candidate_files.sort_by(|a, b| b.1.weight.partial_cmp(&a.1.weight).unwrap());
-    Ok(
-      candidate_files
-        .into_iter()
-        .filter_map(|cand| {
-          if cand.1.touched_lines > 0 {
-            Some(cand.1)
-          } else {
-            None
-          }
-        })
-        .collect(),
-    )
+  #[tokio::test]
+  async fn test_candidates_with_no_lines_left_are_dropped_without_nan() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "fn main() {}\n");
+    repo.write("a.rs", "fn a() {}\nfn gone() {}\n");
+    repo.write("b.rs", "fn b() {}\nfn gone() {}\n");
+    repo.commit("add files");
+    // the commit owns a line of src.rs but none of a.rs or b.rs, it only took lines out
+    repo.write("src.rs", "fn main() {}\nfn added() {}\n");
+    repo.write("a.rs", "fn a() {}\n");
+    repo.write("b.rs", "fn b() {}\n");
+    let commit = repo.commit("move gone() out of a.rs and b.rs");
+
+    // only the queried line's chunk, so the commit adding the files can't weigh in
+    let gg = repo.graph_with(GraphConfig {
+      chunk_range: 0,
+      ..GraphConfig::default()
+    });
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let related = gg.related_files(&blame, 2, &QueryOptions::default()).await?;
+    assert!(related.is_empty(), "{:?}", related);
+    Ok(())
   }
 
-  pub async fn blame(&self, filepath: &BStr) -> anyhow::Result<Arc<blame::LazyBlame>> {
-    self.inner.load_blame(None, filepath, true).await
+  #[tokio::test]
+  async fn test_persisted_blames_are_reused_by_a_later_session() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "one\n");
+    repo.commit("add src.rs");
+    repo.write("src.rs", "one\ntwo\n");
+    repo.commit("extend src.rs");
+    let dir = tempfile::tempdir()?;
+    let config = GraphConfig {
+      persist_blames: true,
+      cache_backend: CacheBackend::Sqlite,
+      cache_path: Some(dir.path().join("cache.db")),
+      ..GraphConfig::default()
+    };
+
+    let gg = LocalGitGraph::new(repo.path().to_str().unwrap(), config.clone())?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    let blamed = blame.lines();
+    gg.shutdown(time::Duration::from_secs(10)).await?;
+
+    // a git that can't run proves the blame comes from the cache
+    let no_git = GraphConfig {
+      git_binary: repo.path().join("no-such-git"),
+      ..config.clone()
+    };
+    let gg = LocalGitGraph::new(repo.path().to_str().unwrap(), no_git.clone())?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    assert!(blame.is_ready());
+    assert_eq!(blame.error(), None);
+    assert_eq!(blame.lines(), blamed);
+    gg.shutdown(time::Duration::from_secs(10)).await?;
+
+    // other blame options, or the file at another commit, need a fresh blame
+    let ignoring_whitespace = GraphConfig {
+      ignore_whitespace: true,
+      ..no_git.clone()
+    };
+    let gg = LocalGitGraph::new(repo.path().to_str().unwrap(), ignoring_whitespace)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    assert!(blame.error().is_some());
+    gg.shutdown(time::Duration::from_secs(10)).await?;
+
+    repo.write("other.rs", "other\n");
+    repo.commit("add other.rs");
+    let gg = LocalGitGraph::new(repo.path().to_str().unwrap(), no_git)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    blame.wait_for_ready().await;
+    assert!(blame.error().is_some());
+    Ok(())
   }
-}
 
-#[cfg(test)]
-mod test {
-  use std::time;
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_cancelled_query_stops_waiting_for_candidates() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
 
-  use super::*;
+    let repo = TestRepo::new();
+    repo.write("src.rs", "fn main() {}\n");
+    repo.write("slow.rs", "fn slow() {}\n");
+    let commit = repo.commit("add src.rs and slow.rs");
+    let slow_git = repo.path().join("slow-git");
+    std::fs::write(
+      &slow_git,
+      "#!/bin/sh\ncase \"$*\" in\n*slow.rs*) sleep 5; exec git \"$@\";;\n*) exec git \"$@\";;\nesac\n",
+    )?;
+    std::fs::set_permissions(&slow_git, std::fs::Permissions::from_mode(0o755))?;
+    let gg = repo.graph_with(GraphConfig {
+      git_binary: slow_git,
+      locate_timeout: None,
+      ..GraphConfig::default()
+    });
+    gg.inner.load_cached_commit(&commit)?;
+    let blame = gg.blame("src.rs".into(), None).await?;
+    gg.ensure_ready(&blame, true).await?;
+
+    let cancellation = Arc::new(Cancellation::default());
+    let options = QueryOptions {
+      cancellation: Some(cancellation.clone()),
+      ..QueryOptions::default()
+    };
+    let started = time::Instant::now();
+    let (related, ()) = tokio::join!(gg.related_files(&blame, 1, &options), async {
+      tokio::time::sleep(time::Duration::from_millis(200)).await;
+      cancellation.cancel();
+    });
+    let e = GraphError::from(related.unwrap_err());
+    assert!(matches!(e, GraphError::Cancelled), "{}", e);
+    assert!(started.elapsed() < time::Duration::from_secs(4));
+
+    // a cancelled query fails up front
+    assert!(gg.related_files(&blame, 1, &options).await.is_err());
+    Ok(())
+  }
 
   #[tokio::test]
-  async fn test_basic() -> anyhow::Result<()> {
-    // use the local everysphere monorepo for testing
-    let gg = LocalGitGraph::new("../../../../../")?;
+  async fn test_hunks_rank_related_files_together() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("src.rs", "s1\n");
+    repo.write("a.rs", "a\n");
+    repo.write("shared.rs", "shared\n");
+    let first = repo.commit("add src.rs with a.rs and shared.rs");
+    repo.write("lib.rs", "l1\n");
+    repo.write("b.rs", "b\n");
+    repo.write("shared.rs", "shared\nmore\n");
+    let second = repo.commit("add lib.rs with b.rs, extend shared.rs");
+    repo.write("src.rs", "s1\ns2\n");
+    repo.write("lib.rs", "l1\nl2\n");
+    let third = repo.commit("extend src.rs and lib.rs");
 
-    let blame = gg
-      .blame("vscode/src/vs/editor/browser/coreCommands.ts".into())
-      .await?;
+    let gg = repo.graph();
+    for commit in [first, second, third] {
+      gg.inner.load_cached_commit(&commit)?;
+    }
+    let hunks = [("src.rs".into(), 1..=2), ("lib.rs".into(), 1..=2)];
+    let related = gg.related_files_for_hunks(&hunks, None, &QueryOptions::default()).await?;
+    let paths: Vec<BString> = related.candidates.iter().filter_map(|c| c.path.clone()).collect();
+    // lib.rs and src.rs changed together, but they're part of the change already
+    assert_eq!(paths, vec![BString::from("shared.rs"), "a.rs".into(), "b.rs".into()]);
+    let shared = &related.candidates[0];
+    assert_eq!(shared.linking_commits().len(), 2);
+    assert_eq!(shared.normalized_weight, 1.0);
+    assert!(related.candidates[1].weight < shared.weight);
+    Ok(())
+  }
 
-    tokio::time::sleep(time::Duration::from_secs(1)).await;
+  #[test]
+  fn test_prune_drops_unreachable_commits_and_their_paths() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("kept.rs", "kept\n");
+    let kept = repo.commit("add kept.rs");
+    repo.git(&["checkout", "-q", "-b", "topic"]);
+    repo.write("topic.rs", "topic\n");
+    let topic = repo.commit("add topic.rs");
+    repo.git(&["checkout", "-q", "main"]);
+    repo.write("newer.rs", "newer\n");
+    let newer = repo.commit("add newer.rs");
 
-    for _ in 1..10 {
-      let now = time::Instant::now();
-      let related_files = gg.related_files(&blame, 43).await?;
-      assert!(related_files.len() > 0);
-      println!("Related files: {:?}", &related_files);
-      println!("Took {}ms", now.elapsed().as_millis());
+    for backend in [CacheBackend::Memory, CacheBackend::Sqlite] {
+      let gg = LocalGitGraph::new(
+        repo.path().to_str().unwrap(),
+        GraphConfig {
+          cache_backend: backend,
+          ..GraphConfig::default()
+        },
+      )?;
+      for commit in [kept, topic, newer] {
+        gg.inner.load_cached_commit(&commit)?;
+      }
+      // every commit is reachable from some ref
+      assert_eq!(gg.prune_cache(None)?, PrunedCache::default());
+
+      repo.git(&["branch", "-q", "-D", "topic"]);
+      assert_eq!(gg.prune_cache(None)?, PrunedCache { commits: 1, paths: 1 });
+      assert!(!gg.inner.disk_cache.is_commit_cached(&topic)?);
+      let mut paths: Vec<BString> = gg.list_paths()?.into_iter().map(|info| info.path).collect();
+      paths.sort();
+      assert_eq!(paths, vec![BString::from("kept.rs"), "newer.rs".into()]);
+
+      assert_eq!(gg.prune_cache(Some(1))?, PrunedCache { commits: 1, paths: 1 });
+      assert!(gg.inner.disk_cache.is_commit_cached(&newer)?);
+      let newer_id = gg.inner.disk_cache.cache_path("newer.rs".into())?;
+      assert_eq!(gg.inner.disk_cache.cached_commit(&newer)?.unwrap().changed_paths, vec![newer_id]);
+      repo.git(&["branch", "-q", "topic", &topic.to_string()]);
     }
+    Ok(())
+  }
 
+  #[test]
+  fn test_commit_files_lists_what_a_commit_changed() -> anyhow::Result<()> {
+    let repo = TestRepo::new();
+    repo.write("a.rs", "a\n");
+    repo.write("b.rs", "b\n");
+    let first = repo.commit("add a.rs and b.rs");
+    repo.write("b.rs", "b\nb\n");
+    repo.write("c.rs", "c\n");
+    repo.commit("change b.rs, add c.rs");
+    repo.git(&["mv", "a.rs", "renamed.rs"]);
+    repo.commit("rename a.rs");
+
+    let gg = repo.graph();
+    let sorted = |mut paths: Vec<BString>| {
+      paths.sort();
+      paths
+    };
+    assert_eq!(sorted(gg.commit_files("HEAD~1")?), vec![BString::from("b.rs"), "c.rs".into()]);
+    assert!(gg.commit_files("HEAD")?.contains(&BString::from("renamed.rs")));
+    // caching the rename lets the first commit know a.rs under the name it has now
+    assert_eq!(
+      sorted(gg.commit_files(&first.to_string())?),
+      vec![BString::from("b.rs"), "renamed.rs".into()]
+    );
+    assert!(matches!(
+      gg.commit_files("0123456789abcdef0123456789abcdef01234567"),
+      Err(GraphError::RevisionNotFound { .. })
+    ));
     Ok(())
   }
 }
@@ -1,11 +1,22 @@
 use std::sync::Arc;
+use std::time::Duration;
 use gix::bstr::{BString, ByteSlice};
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
 use crate::blame::LazyBlame;
+use crate::error::GraphError;
 
 mod blame;
 mod cache;
+mod config;
+mod diff;
+mod error;
 mod gitgraph;
+mod memory;
 mod sqlite;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "trace")]
+mod trace;
 
 #[macro_use]
 extern crate napi_derive;
@@ -14,36 +25,972 @@ extern crate napi_derive;
 pub struct Candidate {
   pub path: String,
   pub locations: Vec<u32>,
+  /// Raw relatedness; its scale depends on how many commits were near the line.
   pub weight: f64,
+  /// `weight` as a fraction of the best candidate's, so the top result is 1.0. A UI can drop,
+  /// say, everything below 0.2 to show only files about as related as the best one.
+  pub normalized_weight: f64,
+  /// Set on files found through `depth` expansion: the directly related file they're related to.
+  pub via: Option<String>,
+  /// Commits accounting for `explainCutoff` of the weight, strongest first. Only set when requested.
+  pub explaining_commits: Option<Vec<String>>,
+  /// Every commit that linked this file to the query, strongest first, for showing "linked via
+  /// 3 commits". For files found through `depth` expansion, the commits linking them to `via`.
+  pub linking_commits: Vec<String>,
+  /// Probability-like score in `[0, 1]` comparable across queries. Only set when `calibrate` is on.
+  pub calibrated_score: Option<f64>,
+  /// `locations` as ranges tagged with the commit connecting each, sorted by line. Only set when
+  /// `attributeLocations` is on.
+  pub attributions: Option<Vec<Attribution>>,
+  /// The commit connecting this file to the queried line.
+  pub commit_id: String,
+  /// Author of `commitId` after mailmapping. Only set when `commitInfo` is on, like the two below.
+  pub author_name: Option<String>,
+  /// Author email of `commitId`, the canonical one if the repo's `.mailmap` lists it as an alias.
+  pub author_email: Option<String>,
+  /// Commit time of `commitId` in milliseconds since the epoch, for `new Date(committedAt)`.
+  pub committed_at: Option<i64>,
+  /// First line of `commitId`'s message.
+  pub summary: Option<String>,
+  /// The file's blame was still loading after `locateTimeoutMs`, so `locations` may be missing
+  /// some lines.
+  pub partial: bool,
 }
 
+/// Lines of a candidate connected to the query by one commit.
+#[napi(object)]
+pub struct Attribution {
+  /// First line, 1-based.
+  pub line_start: u32,
+  /// One past the last line.
+  pub line_end: u32,
+  pub commit_sha: String,
+  /// Derived from the sha only, for picking the same color for a commit in every query.
+  pub commit_color_seed: u32,
+}
+
+#[napi(object)]
+pub struct BlameLine {
+  /// First line of the chunk, 1-based.
+  pub line_start: u32,
+  /// One past the last line of the chunk.
+  pub line_end: u32,
+  pub commit_id: String,
+  /// The file's path in `commitId`; differs from the opened path if the file was renamed since.
+  pub original_path: String,
+  /// The lines aren't committed yet, `commitId` is all zeros; related files can't take them
+  /// into account.
+  pub uncommitted: bool,
+}
+
+#[napi(object)]
+pub struct DiffHunk {
+  pub old_start: u32,
+  pub old_lines: u32,
+  pub new_start: u32,
+  pub new_lines: u32,
+  /// Hunk body lines, prefixed with ` `, `-` or `+`.
+  pub lines: Vec<String>,
+}
+
+#[napi(object)]
+pub struct FileDiff {
+  /// Set when the commit renamed the file from this path.
+  pub old_path: Option<String>,
+  /// The file's path as of the commit, which may predate later renames.
+  pub new_path: String,
+  pub hunks: Vec<DiffHunk>,
+}
+
+#[napi(object)]
+pub struct PathInfo {
+  pub id: u32,
+  /// The path as the graph first saw it, possibly a name the file no longer has.
+  pub path: String,
+  /// The file's name now, following the renames the graph has seen.
+  pub current_path: String,
+  /// Whether `currentPath` exists in HEAD.
+  pub exists: bool,
+}
+
+/// How the cache resolves a path id, see `debugResolvePath`.
+#[napi(object)]
+pub struct ResolvedPath {
+  pub id: u32,
+  /// The path interned under `id`, unset for an id the cache never handed out.
+  pub path: Option<String>,
+  /// The id at the end of `id`'s rename chain.
+  pub current_id: u32,
+  /// The path candidates with `id` are reported under; unset means they drop out of results.
+  pub current_path: Option<String>,
+}
+
+/// Size of the cache's path table, see `debugInternStats`.
+#[napi(object)]
+pub struct InternStats {
+  /// Paths interned so far, old names of renamed files included.
+  pub paths: u32,
+}
+
+#[napi(object)]
+pub struct PrebuildProgress {
+  /// Commits cached so far.
+  pub done: u32,
+  /// Commits that weren't cached when the prebuild started.
+  pub total: u32,
+}
+
+/// Counters since the graph was opened, see `stats`.
+#[napi(object)]
+pub struct GraphStats {
+  pub blame_cache_hits: u32,
+  pub blame_cache_misses: u32,
+  pub commit_cache_hits: u32,
+  pub commit_cache_misses: u32,
+  /// Commits diffed against their parents to be cached.
+  pub commits_diffed: u32,
+  /// `findSimilarFiles` calls and their variants.
+  pub find_similar_files_calls: u32,
+  /// Mean time those calls took, in milliseconds.
+  pub average_latency_ms: f64,
+}
+
+/// Related files rolled up by directory, see `findRelatedDirs`.
+#[napi(object)]
+pub struct RelatedDir {
+  /// Directory prefix with a trailing slash, like `src/editor/`; empty for top-level files.
+  pub dir: String,
+  pub file_count: u32,
+  /// Sum of the files' `weight`.
+  pub total_weight: f64,
+}
+
+/// Where the graph caches paths and commits.
+#[napi(string_enum)]
+pub enum CacheBackend {
+  /// SQLite, the default.
+  Sqlite,
+  /// Plain in-process maps, for short-lived graphs.
+  Memory,
+}
+
+/// What runs blame.
+#[napi(string_enum)]
+pub enum BlameBackend {
+  /// The `git` executable, the default.
+  Git,
+  /// Built in, for when there's no git to run. It follows renames and merges like git but none
+  /// of the blame options: creating a graph with it fails if `blameArgs`, `ignoreWhitespace`,
+  /// `detectMoves`, `detectCopies`, `ignoreRevs` or `ignoreRevsFile` is set, and the repository's
+  /// `blame.ignoreRevsFile` is not read. It also fails unless the addon was built with the
+  /// `native-blame` feature.
+  Native,
+}
+
+/// Graph-wide tuning; every field falls back to the built-in default when left out.
+#[napi(object)]
+pub struct GraphConfig {
+  /// Number of blame chunks around the queried line whose commits are considered. Default 6.
+  pub chunk_range: Option<u32>,
+  /// Maximum number of candidates returned. Default 20.
+  pub max_candidates: Option<u32>,
+  /// Weight a commit contributes from the queried line's own chunk. Default 2.0.
+  pub base_weight: Option<f64>,
+  /// Weight lost per chunk of distance from the queried line. Default 0.2.
+  pub distance_decay: Option<f64>,
+  /// Count only what a merge changed relative to its first parent. Default false: files changed
+  /// relative to any parent, including ones from the merged branch, count.
+  pub first_parent_only: Option<bool>,
+  /// Cache the lines each commit changed, so fewer related files need a blame. Default false.
+  pub cache_line_ranges: Option<bool>,
+  /// Never offer files with binary content, like images or fonts, as related. Default false.
+  /// Commits cached earlier, like in a `cachePath` database, keep their binary files.
+  pub skip_binary_files: Option<bool>,
+  /// Never offer symlinks as related. Default true; submodules never are.
+  pub skip_symlinks: Option<bool>,
+  /// Only cache commits from the last this many days when a file is opened, trading older
+  /// history's related files for speed. Default: no limit.
+  pub max_commit_age_days: Option<f64>,
+  /// Only cache commits among the newest this many reachable from HEAD when a file is opened.
+  /// Default: no limit.
+  pub max_commit_depth: Option<u32>,
+  /// Keep finished blames in the cache, so opening a file again at the same commit doesn't rerun
+  /// `git blame`; with `cachePath` that holds across restarts. Default false.
+  pub persist_blames: Option<bool>,
+  /// Number of file blames kept in memory. Default 256.
+  pub blame_cache_capacity: Option<u32>,
+  /// Commits cached in the background at once. Defaults to the number of CPUs.
+  pub max_caching_tasks: Option<u32>,
+  /// `git blame` processes run at once; opened files go ahead of candidate lookups. Defaults to
+  /// the number of CPUs.
+  pub max_blame_processes: Option<u32>,
+  /// Milliseconds locating a candidate waits for its blame before returning the lines found so
+  /// far and marking the candidate `partial`. Default 250; negative waits for the whole blame.
+  pub locate_timeout_ms: Option<i64>,
+  /// Candidates located at once across all queries; more wait their turn. Defaults to twice the
+  /// number of CPUs.
+  pub max_locating_tasks: Option<u32>,
+  /// Bytes of git objects cached per worker thread for diffing; 0 disables. Default 16 MiB.
+  pub object_cache_size: Option<u32>,
+  /// Number of top results a `depth` query expands from. Default 3.
+  pub expansion_seeds: Option<u32>,
+  /// Weight factor for files found through expansion, per level. Default 0.5.
+  pub expansion_decay: Option<f64>,
+  /// Coefficients of a candidate's score, `blameCoefficient * blame + cochangeCoefficient *
+  /// cochange + recencyCoefficient * recency`. `blame` is the usual distance-and-lines weight,
+  /// `cochange` the number of commits near the line that changed the candidate, and `recency`
+  /// 0..1, halving every `recencyHalfLifeDays` (default 30) since the newest of them. Defaults
+  /// 1, 0 and 0: blame alone.
+  pub blame_coefficient: Option<f64>,
+  pub cochange_coefficient: Option<f64>,
+  pub recency_coefficient: Option<f64>,
+  pub blame_backend: Option<BlameBackend>,
+  /// Lines blamed on either side of the line `findSimilarFilesAt` asks about. Default 100.
+  pub blame_window_lines: Option<u32>,
+  /// The git executable to run blame with, for when `git` isn't on `PATH`. Default `git`.
+  pub git_binary: Option<String>,
+  /// Extra `git blame` arguments, like `-w` to ignore whitespace changes or `-M`/`-C` to follow
+  /// moved and copied lines. Arguments changing the output format break parsing.
+  pub blame_args: Option<Vec<String>>,
+  /// Blame through whitespace-only changes like reindentation, so formatting commits don't
+  /// connect unrelated files. Default false.
+  pub ignore_whitespace: Option<bool>,
+  /// Blame lines moved within a file to the commit that wrote them (`-M`). Default false.
+  pub detect_moves: Option<bool>,
+  /// Alphanumeric characters a move needs to count for `detectMoves`. Default 20.
+  pub move_threshold: Option<u32>,
+  /// Follow lines moved or copied from other files changed in the same commit (`-C`), so code
+  /// relocated between files still leads to where it came from. Default false: blames can get
+  /// several times slower.
+  pub detect_copies: Option<bool>,
+  /// Alphanumeric characters a copy needs to count for `detectCopies`. Default 40.
+  pub copy_threshold: Option<u32>,
+  /// Revisions blame looks through, like a repo-wide prettier or rustfmt commit.
+  pub ignore_revs: Option<Vec<String>>,
+  /// A file listing revisions to look through, e.g. `.git-blame-ignore-revs`, relative to the
+  /// work tree. A `blame.ignoreRevsFile` set in the repository's git config applies regardless.
+  pub ignore_revs_file: Option<String>,
+  pub cache_backend: Option<CacheBackend>,
+  /// Keep the SQLite cache in this file instead of in memory, so it's reused across restarts.
+  pub cache_path: Option<String>,
+}
+
+impl From<GraphConfig> for config::GraphConfig {
+  fn from(config: GraphConfig) -> Self {
+    let default = config::GraphConfig::default();
+    config::GraphConfig {
+      chunk_range: config.chunk_range.unwrap_or(default.chunk_range),
+      max_candidates: config.max_candidates.map_or(default.max_candidates, |n| n as usize),
+      base_weight: config.base_weight.map_or(default.base_weight, |w| w as f32),
+      distance_decay: config.distance_decay.map_or(default.distance_decay, |d| d as f32),
+      first_parent_only: config.first_parent_only.unwrap_or(default.first_parent_only),
+      cache_line_ranges: config.cache_line_ranges.unwrap_or(default.cache_line_ranges),
+      skip_binary_files: config.skip_binary_files.unwrap_or(default.skip_binary_files),
+      skip_symlinks: config.skip_symlinks.unwrap_or(default.skip_symlinks),
+      max_commit_age: config
+        .max_commit_age_days
+        .map_or(default.max_commit_age, |days| Some(Duration::from_secs_f64(days.max(0.0) * 86400.0))),
+      max_commit_depth: config.max_commit_depth.map_or(default.max_commit_depth, |n| Some(n as usize)),
+      persist_blames: config.persist_blames.unwrap_or(default.persist_blames),
+      blame_cache_capacity: config
+        .blame_cache_capacity
+        .map_or(default.blame_cache_capacity, |n| n as usize),
+      max_caching_tasks: config.max_caching_tasks.map_or(default.max_caching_tasks, |n| n as usize),
+      max_blame_processes: config.max_blame_processes.map_or(default.max_blame_processes, |n| n as usize),
+      locate_timeout: match config.locate_timeout_ms {
+        Some(ms) if ms < 0 => None,
+        Some(ms) => Some(Duration::from_millis(ms as u64)),
+        None => default.locate_timeout,
+      },
+      max_locating_tasks: config.max_locating_tasks.map_or(default.max_locating_tasks, |n| n as usize),
+      object_cache_size: config.object_cache_size.map_or(default.object_cache_size, |n| n as usize),
+      expansion_seeds: config.expansion_seeds.map_or(default.expansion_seeds, |n| n as usize),
+      expansion_decay: config.expansion_decay.map_or(default.expansion_decay, |d| d as f32),
+      blame_coefficient: config.blame_coefficient.map_or(default.blame_coefficient, |c| c as f32),
+      cochange_coefficient: config.cochange_coefficient.map_or(default.cochange_coefficient, |c| c as f32),
+      recency_coefficient: config.recency_coefficient.map_or(default.recency_coefficient, |c| c as f32),
+      blame_backend: match config.blame_backend {
+        Some(BlameBackend::Git) => config::BlameBackend::Git,
+        Some(BlameBackend::Native) => config::BlameBackend::Native,
+        None => default.blame_backend,
+      },
+      blame_window_lines: config.blame_window_lines.unwrap_or(default.blame_window_lines),
+      git_binary: config.git_binary.map_or(default.git_binary, Into::into),
+      blame_args: config.blame_args.unwrap_or(default.blame_args),
+      ignore_whitespace: config.ignore_whitespace.unwrap_or(default.ignore_whitespace),
+      detect_moves: config.detect_moves.unwrap_or(default.detect_moves),
+      move_threshold: config.move_threshold.or(default.move_threshold),
+      detect_copies: config.detect_copies.unwrap_or(default.detect_copies),
+      copy_threshold: config.copy_threshold.or(default.copy_threshold),
+      ignore_revs: config.ignore_revs.unwrap_or(default.ignore_revs),
+      ignore_revs_file: config.ignore_revs_file.map(Into::into),
+      cache_backend: match config.cache_backend {
+        Some(CacheBackend::Sqlite) => config::CacheBackend::Sqlite,
+        Some(CacheBackend::Memory) => config::CacheBackend::Memory,
+        None => default.cache_backend,
+      },
+      cache_path: config.cache_path.map(Into::into),
+    }
+  }
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct QueryOptions {
+  /// Fraction (0..1) of each candidate's weight its `explainingCommits` should cover, e.g. `0.8`.
+  pub explain_cutoff: Option<f64>,
+  /// Report a renamed file once under its current path, summing the weight of its old names.
+  pub merge_renames: Option<bool>,
+  /// Minimum weight a commit contributes however far it is from the line. Defaults to 0; a
+  /// negative floor acts as 0.
+  pub weight_floor: Option<f64>,
+  /// Only count commits authored by one of these emails, after applying the repo's mailmap.
+  pub authors: Option<Vec<String>>,
+  /// Add a `calibratedScore` per candidate: `1 / (1 + exp(-(a * w + b)))` of its unnormalized
+  /// weight `w`.
+  pub calibrate: Option<bool>,
+  /// `a` in the calibration. Default 1.
+  pub calibration_slope: Option<f64>,
+  /// `b` in the calibration. Default -2, so one commit right at the line scores 0.5.
+  pub calibration_intercept: Option<f64>,
+  /// 2 adds files related to the top results (see `via`) with decayed weight; higher values go
+  /// further. Default 1, direct relations only.
+  pub depth: Option<u32>,
+  /// Weigh commits by how many lines they changed around the queried line, so rewrites count
+  /// more than small edits. Needs `cacheLineRanges` on the graph.
+  pub weight_by_diff_size: Option<bool>,
+  /// Halve a commit's weight for every this many days since it was committed, so recent work
+  /// ranks higher. Off by default.
+  pub recency_half_life_days: Option<f64>,
+  /// Wait for the file's blame to finish loading before querying. Default true; with false a
+  /// query on a file that is still loading rejects with an error whose message starts with
+  /// `NotReady`.
+  pub wait: Option<bool>,
+  /// Ignore the newest N commits reachable from HEAD, to see coupling as it was before recent
+  /// work. The file's lines stay as in HEAD.
+  pub exclude_recent: Option<u32>,
+  /// Ignore commits made since this revision (`base..HEAD`). Takes precedence over
+  /// `excludeRecent`.
+  pub exclude_since: Option<String>,
+  /// Add `attributions` to each candidate, for coloring its lines by commit.
+  pub attribute_locations: Option<bool>,
+  /// Add `authorName`, `committedAt` and `summary` of each candidate's commit. Off by default,
+  /// since it reads every commit from the object database.
+  pub commit_info: Option<bool>,
+  /// Only return files this author (an email, mailmapped) never changed in a cached commit, to
+  /// find related code that is new to them.
+  pub unfamiliar_to: Option<String>,
+  /// Leave out files that aren't in HEAD anymore, like deleted ones.
+  pub only_existing: Option<bool>,
+  /// Only return files matching one of these globs, in `.gitignore` syntax, e.g. `["*.ts"]`.
+  /// A list without a non-empty glob returns nothing.
+  pub include_paths: Option<Vec<String>>,
+  /// Never return files matching one of these globs, e.g. `["*.lock", "test/fixtures/**"]`.
+  pub exclude_paths: Option<Vec<String>>,
+}
+
+impl From<QueryOptions> for gitgraph::QueryOptions {
+  fn from(options: QueryOptions) -> Self {
+    gitgraph::QueryOptions {
+      explain_cutoff: options.explain_cutoff.map(|c| c as f32),
+      merge_renames: options.merge_renames.unwrap_or(false),
+      weight_floor: options.weight_floor.unwrap_or(0.0) as f32,
+      authors: options.authors.map(|authors| authors.into_iter().map(Into::into).collect()),
+      depth: options.depth.map_or(1, |d| d.min(u8::MAX as u32) as u8),
+      weight_by_diff_size: options.weight_by_diff_size.unwrap_or(false),
+      recency_half_life: options
+        .recency_half_life_days
+        .filter(|days| *days > 0.0)
+        .and_then(|days| Duration::try_from_secs_f64(days * 86400.0).ok()),
+      attribute_locations: options.attribute_locations.unwrap_or(false),
+      commit_info: options.commit_info.unwrap_or(false),
+      unfamiliar_to: options.unfamiliar_to.map(Into::into),
+      only_existing: options.only_existing.unwrap_or(false),
+      paths: (options.include_paths.is_some() || options.exclude_paths.is_some()).then(|| {
+        gitgraph::PathFilter::new(
+          options.include_paths.as_ref().map(|globs| globs.iter().map(|glob| glob.as_str().into())),
+          options.exclude_paths.iter().flatten().map(|glob| glob.as_str().into()),
+        )
+      }),
+      #[cfg(feature = "trace")]
+      trace: None,
+      cancellation: None,
+      exclude_recent: match (options.exclude_since, options.exclude_recent) {
+        (Some(base), _) => Some(gitgraph::ExcludeRecent::Since(base)),
+        (None, Some(n)) => Some(gitgraph::ExcludeRecent::Count(n as usize)),
+        (None, None) => None,
+      },
+      calibration: options.calibrate.unwrap_or(false).then(|| {
+        let default = gitgraph::Calibration::default();
+        gitgraph::Calibration {
+          slope: options.calibration_slope.map_or(default.slope, |a| a as f32),
+          intercept: options.calibration_intercept.map_or(default.intercept, |b| b as f32),
+        }
+      }),
+    }
+  }
+}
+
+/// Errors reach JavaScript with messages starting with the `GraphError` variant, `RepoOpen`,
+/// `RevisionNotFound`, `PathOutsideRepo`, `PathNotTracked`, `BlameFailed`, `LineOutOfRange`, `NotReady`,
+/// `Cancelled`, `Closed` or `Cache`, for callers to tell them apart. Bad arguments are also
+/// flagged with the `InvalidArg` code, cancelled queries with `Cancelled`.
+fn to_napi_error(e: impl Into<GraphError>) -> napi::Error {
+  let e = e.into();
+  let status = match e {
+    GraphError::RevisionNotFound { .. }
+    | GraphError::PathOutsideRepo { .. }
+    | GraphError::PathNotTracked { .. }
+    | GraphError::LineOutOfRange { .. } => napi::Status::InvalidArg,
+    GraphError::Cancelled => napi::Status::Cancelled,
+    _ => napi::Status::GenericFailure,
+  };
+  napi::Error::new(status, e.to_string())
+}
+
+fn to_js_candidate(c: &gitgraph::Candidate, options: &gitgraph::QueryOptions) -> Candidate {
+  Candidate {
+    path: c.path.as_ref().unwrap().to_string(),
+    locations: c.locations.iter().map(|loc| loc.start).collect(),
+    weight: c.weight as f64,
+    normalized_weight: c.normalized_weight as f64,
+    via: c.via.as_ref().map(|p| p.to_string()),
+    explaining_commits: options
+      .explain_cutoff
+      .map(|_| c.explaining_commits.iter().map(|id| id.to_string()).collect()),
+    linking_commits: c.linking_commits().iter().map(|id| id.to_string()).collect(),
+    calibrated_score: c.calibrated_score.map(|score| score as f64),
+    attributions: options.attribute_locations.then(|| {
+      c.attributions
+        .iter()
+        .map(|a| Attribution {
+          line_start: a.range.start,
+          line_end: a.range.end,
+          commit_sha: a.commit.to_string(),
+          commit_color_seed: a.color_seed,
+        })
+        .collect()
+    }),
+    commit_id: c.commit.to_string(),
+    author_name: c.commit_meta.as_ref().map(|meta| meta.author_name.to_string()),
+    author_email: c.commit_meta.as_ref().map(|meta| meta.author_email.to_string()),
+    committed_at: c.commit_meta.as_ref().map(|meta| meta.committed_at * 1000),
+    summary: c.commit_meta.as_ref().map(|meta| meta.summary.to_string()),
+    partial: c.partial,
+  }
+}
+
+fn to_js_candidates(candidates: Vec<gitgraph::Candidate>, options: &gitgraph::QueryOptions) -> Vec<Candidate> {
+  candidates.iter().map(|c| to_js_candidate(c, options)).collect()
+}
+
+/// What a `CandidateEvent` reports.
+#[napi(string_enum)]
+pub enum CandidateEventKind {
+  /// One candidate's locations resolved; see `candidate`.
+  Located,
+  /// The query finished; `candidates` is the final ranking. Always the last event.
+  Ranked,
+}
+
+/// An event of `findSimilarFilesStream`.
+#[napi(object)]
+pub struct CandidateEvent {
+  pub kind: CandidateEventKind,
+  /// Set on `Located`. Its `weight` isn't scaled by touched lines yet and `normalizedWeight` is 0.
+  pub candidate: Option<Candidate>,
+  /// Set on `Ranked`, sorted like `findSimilarFiles` returns them.
+  pub candidates: Option<Vec<Candidate>>,
+}
+
+/// One file of `openFiles`: the opened file, or why it couldn't be opened.
+#[napi(object, object_from_js = false)]
+pub struct OpenedFile {
+  pub path: String,
+  pub file: Option<GitFile>,
+  /// Set instead of `file`, with the message `openFile` would have thrown.
+  pub error: Option<String>,
+}
+
+/// Results of `findSimilarFilesWithStatus`, saying whether they're the full answer.
+#[napi(object)]
+pub struct SimilarFiles {
+  pub candidates: Vec<Candidate>,
+  /// False when commits near the line weren't cached yet, a candidate's blame timed out, or
+  /// `maxCandidates` cut off weaker files. The first two clear up as the cache warms, so
+  /// querying again later can give more.
+  pub complete: bool,
+  /// Why the results are incomplete, for display or logs. Unset when `complete`.
+  pub reason: Option<String>,
+}
+
+/// Results of `findSimilarFilesForSymbol`, labeled with the symbol they're for.
+#[napi(object)]
+pub struct SymbolCandidates {
+  /// The `name` the query was made with.
+  pub name: String,
+  pub candidates: Vec<Candidate>,
+}
+
+/// Lines a change added or modified in a file, for `relatedFilesForHunks`.
+#[napi(object)]
+pub struct ChangedHunk {
+  pub path: String,
+  /// First line, 1-based, as of the revision the change is in.
+  pub start_line: u32,
+  /// Last line, inclusive.
+  pub end_line: u32,
+}
+
+/// How many rows `pruneCache` removed.
+#[napi(object)]
+pub struct PrunedCache {
+  pub commits: u32,
+  pub paths: u32,
+}
+
+/// Whether a `GitFile` is usable yet.
+#[napi(string_enum)]
+pub enum FileState {
+  /// The blame is still running; queries wait for it or fail with `NotReady`.
+  Loading,
+  Ready,
+}
+
+/// A file opened for queries. Lines, like a query's `lineno`, are numbered from 1 as git blame
+/// counts them in the committed file: each ends at a `\n`, so a `\r\n` ending counts once and a
+/// lone `\r` doesn't end a line, whatever line endings the checkout has. One past the last line,
+/// the empty line an editor shows after a trailing newline, queries the last one; other lines
+/// outside the file fail with `LineOutOfRange`.
 #[napi]
 pub struct GitFile {
   graph: gitgraph::LocalGitGraph,
-  blame: Arc<LazyBlame>,
+  path: BString,
+  /// The revision the file was opened at, `None` to follow HEAD across `refresh()`.
+  revision: Option<gix::ObjectId>,
+  /// The blame, with the graph's `refreshes()` when it was taken.
+  blame: std::sync::Mutex<(usize, Arc<LazyBlame>)>,
+  /// Cancels the queries in flight on the file, replaced on every `cancel()`.
+  cancellation: std::sync::Mutex<Arc<gitgraph::Cancellation>>,
+}
+
+impl GitFile {
+  fn new(graph: gitgraph::LocalGitGraph, path: BString, revision: Option<gix::ObjectId>, blame: Arc<LazyBlame>) -> Self {
+    let refreshes = graph.refreshes();
+    GitFile {
+      graph,
+      path,
+      revision,
+      blame: std::sync::Mutex::new((refreshes, blame)),
+      cancellation: Default::default(),
+    }
+  }
+
+  /// `options` for the graph, for a query the next `cancel()` aborts.
+  fn query_options(&self, options: Option<QueryOptions>) -> gitgraph::QueryOptions {
+    gitgraph::QueryOptions {
+      cancellation: Some(self.cancellation.lock().unwrap().clone()),
+      ..options.map(Into::into).unwrap_or_default()
+    }
+  }
+
+  /// The blame as last taken, possibly before a refresh.
+  fn last_blame(&self) -> Arc<LazyBlame> {
+    self.blame.lock().unwrap().1.clone()
+  }
+
+  /// The blame, unless the file follows HEAD and the graph was refreshed since it was taken.
+  fn fresh_blame(&self, refreshes: usize) -> Option<Arc<LazyBlame>> {
+    let (taken, blame) = &*self.blame.lock().unwrap();
+    (self.revision.is_some() || *taken == refreshes).then(|| blame.clone())
+  }
+
+  /// The blame, taken again at the current HEAD if `fresh_blame` has none.
+  async fn blame(&self) -> napi::Result<Arc<LazyBlame>> {
+    let refreshes = self.graph.refreshes();
+    if let Some(blame) = self.fresh_blame(refreshes) {
+      return Ok(blame);
+    }
+    let blame = self
+      .graph
+      .blame(self.path.as_bstr(), None)
+      .await
+      .map_err(to_napi_error)?;
+    *self.blame.lock().unwrap() = (refreshes, blame.clone());
+    Ok(blame)
+  }
+
+  /// Waits for `blame` unless `options` turned waiting off.
+  async fn ensure_ready(&self, blame: &LazyBlame, options: &Option<QueryOptions>) -> napi::Result<()> {
+    let wait = options.as_ref().and_then(|o| o.wait).unwrap_or(true);
+    self
+      .graph
+      .ensure_ready(blame, wait)
+      .await
+      .map_err(to_napi_error)
+  }
 }
 
 #[napi]
 impl GitFile {
+  #[napi(getter)]
+  pub fn state(&self) -> FileState {
+    if self.last_blame().is_ready() {
+      FileState::Ready
+    } else {
+      FileState::Loading
+    }
+  }
+
+  /// Resolves once the file's blame has loaded.
   #[napi]
-  pub async fn find_similar_files(&self, lineno: u32) -> napi::Result<Vec<Candidate>> {
+  pub async fn ready(&self) -> napi::Result<()> {
+    let blame = self.blame().await?;
+    blame.wait_for_ready().await;
+    Ok(())
+  }
+
+  /// Waits for the file's blame to load, for at most `timeoutMs` if given. Resolves to whether it
+  /// is ready, so a UI can keep "find related" disabled until this gives true instead of querying
+  /// a half-loaded file.
+  #[napi]
+  pub async fn wait_ready(&self, timeout_ms: Option<u32>) -> napi::Result<bool> {
+    let blame = self.blame().await?;
+    match timeout_ms {
+      Some(ms) => Ok(
+        tokio::time::timeout(Duration::from_millis(ms as u64), blame.wait_for_ready())
+          .await
+          .is_ok(),
+      ),
+      None => {
+        blame.wait_for_ready().await;
+        Ok(true)
+      }
+    }
+  }
+
+  /// Returns the per-line commit attribution of the file. Waits for blame to finish, for at most
+  /// `timeoutMs` if given, so a partial blame is never returned.
+  #[napi]
+  pub async fn get_blame(&self, timeout_ms: Option<u32>) -> napi::Result<Vec<BlameLine>> {
+    let blame = self.blame().await?;
+    match timeout_ms {
+      Some(ms) => {
+        tokio::time::timeout(Duration::from_millis(ms as u64), blame.wait_for_ready())
+          .await
+          .map_err(|_| napi::Error::from_reason(format!("blame did not finish within {}ms", ms)))?;
+      }
+      None => blame.wait_for_ready().await,
+    }
+    if let Some(reason) = blame.error() {
+      return Err(to_napi_error(GraphError::BlameFailed {
+        path: blame.file_path.clone(),
+        reason,
+      }));
+    }
+
+    Ok(
+      blame
+        .lines()
+        .into_iter()
+        .map(|entry| BlameLine {
+          line_start: entry.range_in_blamed_file.start,
+          line_end: entry.range_in_blamed_file.end,
+          commit_id: entry.commit_id.to_string(),
+          original_path: entry.original_path.to_string(),
+          uncommitted: entry.is_uncommitted(),
+        })
+        .collect(),
+    )
+  }
+
+  /// A hash of what a query at `lineno` starts from, as 16 hex digits: the commits around the
+  /// line and which of them are cached, plus the graph's tuning. Cheap next to a query; when it
+  /// matches one stored with earlier results, those results still hold for the same options.
+  /// Waits for the blame.
+  #[napi]
+  pub async fn neighborhood_signature(&self, lineno: u32) -> napi::Result<String> {
+    let blame = self.blame().await?;
+    blame.wait_for_ready().await;
+    self
+      .graph
+      .neighborhood_signature(&blame, lineno as usize)
+      .map(|signature| format!("{:016x}", signature))
+      .map_err(to_napi_error)
+  }
+
+  /// Files related to line `lineno`, 1-based. Waits for the blame first unless `options.wait` is
+  /// false.
+  #[napi]
+  pub async fn find_similar_files(&self, lineno: u32, options: Option<QueryOptions>) -> napi::Result<Vec<Candidate>> {
+    let blame = self.blame().await?;
+    self.ensure_ready(&blame, &options).await?;
+    let options = self.query_options(options);
     let related_files = self
       .graph
-      .related_files(&self.blame, lineno as usize)
+      .related_files(&blame, lineno as usize, &options)
       .await
-      .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+      .map_err(to_napi_error)?;
+
+    Ok(to_js_candidates(related_files, &options))
+  }
+
+  /// Like `findSimilarFiles`, also telling whether the results are complete, so a UI can show
+  /// that they may change once the cache has warmed up.
+  #[napi]
+  pub async fn find_similar_files_with_status(
+    &self,
+    lineno: u32,
+    options: Option<QueryOptions>,
+  ) -> napi::Result<SimilarFiles> {
+    let blame = self.blame().await?;
+    self.ensure_ready(&blame, &options).await?;
+    let options = self.query_options(options);
+    let result = self
+      .graph
+      .query_related_files(&blame, lineno as usize, &options)
+      .await
+      .map_err(to_napi_error)?;
+
+    let reason = result.incomplete_reason();
+    Ok(SimilarFiles {
+      candidates: to_js_candidates(result.candidates, &options),
+      complete: reason.is_none(),
+      reason,
+    })
+  }
+
+  /// Aborts the queries on this file still running, like a `findSimilarFiles` for a line the
+  /// cursor has since left; they fail with `Cancelled`. Later queries run as usual.
+  #[napi]
+  pub fn cancel(&self) {
+    let cancelled = std::mem::take(&mut *self.cancellation.lock().unwrap());
+    cancelled.cancel();
+  }
+
+  /// `findSimilarFiles` reaching `hops` relations out: 2 also brings in the files related to the
+  /// top results, with `via` naming the result they came through. Each hop weighs the graph's
+  /// `expansionDecay` times the one before, which halves it unless the graph was opened with
+  /// another decay. Same as `options.depth = hops`; already found paths aren't repeated and
+  /// `maxCandidates` still caps the results.
+  #[napi]
+  pub async fn find_similar_files_expanded(
+    &self,
+    lineno: u32,
+    hops: u32,
+    options: Option<QueryOptions>,
+  ) -> napi::Result<Vec<Candidate>> {
+    let mut options = options.unwrap_or_default();
+    options.depth = Some(hops);
+    self.find_similar_files(lineno, Some(options)).await
+  }
+
+  /// The results of `findSimilarFiles` grouped by their directory's first `depth` components,
+  /// strongest first.
+  #[napi]
+  pub async fn find_related_dirs(
+    &self,
+    lineno: u32,
+    depth: u32,
+    options: Option<QueryOptions>,
+  ) -> napi::Result<Vec<RelatedDir>> {
+    let blame = self.blame().await?;
+    self.ensure_ready(&blame, &options).await?;
+    let options = self.query_options(options);
+    let related_files = self
+      .graph
+      .related_files(&blame, lineno as usize, &options)
+      .await
+      .map_err(to_napi_error)?;
 
     Ok(
-      related_files
+      gitgraph::related_dirs(&related_files, depth as usize)
         .into_iter()
-        .map(|c| Candidate {
-          path: c.path.as_ref().unwrap().to_string(),
-          locations: c.locations.iter().map(|loc| loc.start).collect(),
-          weight: c.weight as f64,
+        .map(|d| RelatedDir {
+          dir: d.dir.to_string(),
+          file_count: d.file_count as u32,
+          total_weight: d.total_weight as f64,
         })
         .collect(),
     )
   }
+
+  /// `findSimilarFiles` as a blocking call, waiting for the blame, for embedders that can't
+  /// await. It blocks the calling thread, so keep it off the main thread of an app.
+  #[napi]
+  pub fn find_similar_files_sync(&self, lineno: u32, options: Option<QueryOptions>) -> napi::Result<Vec<Candidate>> {
+    let options = self.query_options(options);
+    let runtime = napi::bindgen_prelude::within_runtime_if_available(tokio::runtime::Handle::current);
+    let refreshes = self.graph.refreshes();
+    let blame = match self.fresh_blame(refreshes) {
+      Some(blame) => blame,
+      None => {
+        let blame = self
+          .graph
+          .blame_blocking(&runtime, self.path.as_bstr(), None)
+          .map_err(to_napi_error)?;
+        *self.blame.lock().unwrap() = (refreshes, blame.clone());
+        blame
+      }
+    };
+    let related_files = self
+      .graph
+      .find_similar_files_blocking(&runtime, &blame, lineno as usize, &options)
+      .map_err(to_napi_error)?;
+
+    Ok(to_js_candidates(related_files, &options))
+  }
+
+  /// Like `findSimilarFiles` for a selection of lines, `startLine` to `endLine` inclusive, in one
+  /// pass: each blame chunk in or near the selection counts once, unlike querying line by line.
+  #[napi]
+  pub async fn find_similar_files_range(
+    &self,
+    start_line: u32,
+    end_line: u32,
+    options: Option<QueryOptions>,
+  ) -> napi::Result<Vec<Candidate>> {
+    let blame = self.blame().await?;
+    self.ensure_ready(&blame, &options).await?;
+    let options = self.query_options(options);
+    let lines = start_line.min(end_line) as usize..=start_line.max(end_line) as usize;
+    let related_files = self
+      .graph
+      .related_files_in_range(&blame, lines, &options)
+      .await
+      .map_err(to_napi_error)?;
+
+    Ok(to_js_candidates(related_files, &options))
+  }
+
+  /// `findSimilarFilesRange` over a symbol's lines, `startLine` to `endLine` inclusive, as a
+  /// language server or other tool found them. The results come back labeled with `name`, which
+  /// is only echoed, so symbol-oriented UIs can match them up.
+  #[napi]
+  pub async fn find_similar_files_for_symbol(
+    &self,
+    name: String,
+    start_line: u32,
+    end_line: u32,
+    options: Option<QueryOptions>,
+  ) -> napi::Result<SymbolCandidates> {
+    let blame = self.blame().await?;
+    self.ensure_ready(&blame, &options).await?;
+    let options = self.query_options(options);
+    let symbol = self
+      .graph
+      .related_files_for_symbol(&blame, name, start_line as usize, end_line as usize, &options)
+      .await
+      .map_err(to_napi_error)?;
+
+    Ok(SymbolCandidates {
+      name: symbol.name,
+      candidates: to_js_candidates(symbol.candidates, &options),
+    })
+  }
+
+  /// Like `findSimilarFiles`, but reports each candidate to `onEvent` as a `Located` event as soon
+  /// as its locations resolve, in completion order, so a UI can render results early. A final
+  /// `Ranked` event carries the sorted list. Files found through `depth` expansion only show up
+  /// in the ranking.
+  #[napi(ts_args_type = "lineno: number, options: QueryOptions | undefined | null, onEvent: (event: CandidateEvent) => void")]
+  pub async fn find_similar_files_stream(
+    &self,
+    lineno: u32,
+    options: Option<QueryOptions>,
+    on_event: ThreadsafeFunction<CandidateEvent, ErrorStrategy::Fatal>,
+  ) -> napi::Result<()> {
+    let blame = self.blame().await?;
+    self.ensure_ready(&blame, &options).await?;
+    let options = self.query_options(options);
+    let lineno = lineno as usize;
+    let result = self
+      .graph
+      .stream_related_lines(&blame, lineno..=lineno, &options, &mut |candidate| {
+        let event = CandidateEvent {
+          kind: CandidateEventKind::Located,
+          candidate: Some(to_js_candidate(candidate, &options)),
+          candidates: None,
+        };
+        on_event.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+      })
+      .await
+      .map_err(to_napi_error)?;
+
+    let event = CandidateEvent {
+      kind: CandidateEventKind::Ranked,
+      candidate: None,
+      candidates: Some(to_js_candidates(result.candidates, &options)),
+    };
+    on_event.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+    Ok(())
+  }
+
+  /// Like `findSimilarFiles`. If some commits near the line weren't cached yet, so the result is
+  /// partial, `onUpdate` is called once they are; query again then for the fuller result.
+  #[napi(ts_args_type = "lineno: number, options: QueryOptions | undefined | null, onUpdate: () => void")]
+  pub async fn find_similar_files_with_updates(
+    &self,
+    lineno: u32,
+    options: Option<QueryOptions>,
+    on_update: ThreadsafeFunction<(), ErrorStrategy::Fatal>,
+  ) -> napi::Result<Vec<Candidate>> {
+    let blame = self.blame().await?;
+    self.ensure_ready(&blame, &options).await?;
+    let options = self.query_options(options);
+    let result = self
+      .graph
+      .query_related_files(&blame, lineno as usize, &options)
+      .await
+      .map_err(to_napi_error)?;
+
+    if !result.uncached_commits.is_empty() {
+      self
+        .graph
+        .watch_commits(&result.uncached_commits, move || {
+          on_update.call((), ThreadsafeFunctionCallMode::NonBlocking);
+        })
+        .map_err(to_napi_error)?;
+    }
+    Ok(to_js_candidates(result.candidates, &options))
+  }
+}
+
+/// Results of `traceSimilarFiles`, with the trace of how the query spent its time.
+#[cfg(feature = "trace")]
+#[napi(object)]
+pub struct TracedCandidates {
+  pub candidates: Vec<Candidate>,
+  /// Chrome `trace_event` JSON; load it in `chrome://tracing` or Perfetto.
+  pub trace: String,
+}
+
+#[cfg(feature = "trace")]
+#[napi]
+impl GitFile {
+  /// Runs `findSimilarFiles` while timing its phases: collecting commits from the cache, locating
+  /// each candidate (waiting for a slot, then blaming it) and ranking. Only in builds with the
+  /// `trace` feature.
+  #[napi]
+  pub async fn trace_similar_files(&self, lineno: u32, options: Option<QueryOptions>) -> napi::Result<TracedCandidates> {
+    let blame = self.blame().await?;
+    self.ensure_ready(&blame, &options).await?;
+    let trace = Arc::new(trace::QueryTrace::new());
+    let options = gitgraph::QueryOptions {
+      trace: Some(trace.clone()),
+      ..self.query_options(options)
+    };
+    let related_files = self
+      .graph
+      .related_files(&blame, lineno as usize, &options)
+      .await
+      .map_err(to_napi_error)?;
+
+    Ok(TracedCandidates {
+      candidates: to_js_candidates(related_files, &options),
+      trace: trace.to_chrome_json(),
+    })
+  }
 }
 
 #[napi]
@@ -53,24 +1000,325 @@ pub struct LocalGitGraph {
 
 #[napi]
 impl LocalGitGraph {
+  /// `repo` is a work tree, its `.git` directory or a bare repository. Throws if it isn't a git
+  /// repository or the cache can't be set up.
   #[napi(constructor)]
-  pub fn new(repo: String) -> Self {
-    LocalGitGraph {
-      inner: gitgraph::LocalGitGraph::new(&repo).unwrap(),
+  pub fn new(repo: String, config: Option<GraphConfig>) -> napi::Result<Self> {
+    let config = config.map(Into::into).unwrap_or_default();
+    let inner = gitgraph::LocalGitGraph::new(&repo, config).map_err(to_napi_error)?;
+    Ok(LocalGitGraph { inner })
+  }
+
+  /// Like the constructor, for a git directory kept apart from its checkout at `worktree`.
+  #[napi(factory)]
+  pub fn with_worktree(git_dir: String, worktree: String, config: Option<GraphConfig>) -> napi::Result<Self> {
+    let config = config.map(Into::into).unwrap_or_default();
+    let inner = gitgraph::LocalGitGraph::with_worktree(&git_dir, &worktree, config).map_err(to_napi_error)?;
+    Ok(LocalGitGraph { inner })
+  }
+
+  /// Stops accepting new work, waits up to `timeoutMs` (default 5000) for background blame and
+  /// caching to finish, then closes the cache. Throws if work is still running at the deadline.
+  #[napi]
+  pub async fn shutdown(&self, timeout_ms: Option<u32>) -> napi::Result<()> {
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(5000) as u64);
+    self
+      .inner
+      .shutdown(timeout)
+      .await
+      .map_err(to_napi_error)
+  }
+
+  /// Closes the graph at once, for when an app is done with a repository: running blames are
+  /// aborted, the cache is closed, and every later call on the graph or its files throws an error
+  /// starting with `Closed`. `shutdown` is the gentler way, letting background work finish.
+  #[napi]
+  pub fn close(&self) -> napi::Result<()> {
+    self.inner.close().map_err(to_napi_error)
+  }
+
+  /// Tells the graph the repository changed, e.g. after a commit or a checkout. Blames of files
+  /// whose content changed at the new HEAD are dropped, unless they were made at a given
+  /// revision, and open files that follow HEAD (opened without a revision) blame again on their
+  /// next query, reusing the blame if the file didn't change.
+  #[napi]
+  pub fn refresh(&self) {
+    self.inner.refresh();
+  }
+
+  /// Cache hit rates and query timings, for diagnosing performance.
+  #[napi]
+  pub fn stats(&self) -> GraphStats {
+    let stats = self.inner.stats();
+    GraphStats {
+      blame_cache_hits: stats.blame_cache_hits as u32,
+      blame_cache_misses: stats.blame_cache_misses as u32,
+      commit_cache_hits: stats.commit_cache_hits as u32,
+      commit_cache_misses: stats.commit_cache_misses as u32,
+      commits_diffed: stats.commits_diffed as u32,
+      find_similar_files_calls: stats.queries as u32,
+      average_latency_ms: stats.average_query_latency.as_secs_f64() * 1000.0,
     }
   }
 
+  /// Resolves a revision spec (branch, tag, `HEAD~3`, `v1.2^`, ...) to the full hex sha of the
+  /// commit it names.
   #[napi]
-  pub async fn open_file(&self, path: String) -> napi::Result<GitFile> {
+  pub async fn resolve_revision(&self, rev: String) -> napi::Result<String> {
+    self
+      .inner
+      .resolve_revision(&rev)
+      .map(|id| id.to_string())
+      .map_err(to_napi_error)
+  }
+
+  /// Caches up to `maxCommits` (default all) commits reachable from HEAD up front so later
+  /// queries don't wait for them, calling `onProgress` as commits finish. Resolves
+  /// to the number of commits that weren't cached yet.
+  #[napi(ts_args_type = "maxCommits?: number | undefined | null, onProgress?: (progress: PrebuildProgress) => void")]
+  pub async fn prebuild_cache(
+    &self,
+    max_commits: Option<u32>,
+    on_progress: Option<ThreadsafeFunction<PrebuildProgress, ErrorStrategy::Fatal>>,
+  ) -> napi::Result<u32> {
+    let cached = self
+      .inner
+      .prebuild_cache(max_commits.map(|n| n as usize), |done, total| {
+        if let Some(on_progress) = &on_progress {
+          let progress = PrebuildProgress {
+            done: done as u32,
+            total: total as u32,
+          };
+          on_progress.call(progress, ThreadsafeFunctionCallMode::NonBlocking);
+        }
+      })
+      .await
+      .map_err(to_napi_error)?;
+    Ok(cached as u32)
+  }
+
+  /// Shrinks the cache for long-running use: drops cached commits no branch, tag or HEAD reaches
+  /// anymore, or with `keepRecentCommits` all but that many of the newest they reach, along with
+  /// paths only those commits knew, then compacts the cache file. Caching pauses while it runs.
+  #[napi]
+  pub async fn prune_cache(&self, keep_recent_commits: Option<u32>) -> napi::Result<PrunedCache> {
+    let graph = self.inner.clone();
+    let pruned = tokio::task::spawn_blocking(move || graph.prune_cache(keep_recent_commits.map(|n| n as usize)))
+      .await
+      .map_err(|e| napi::Error::from_reason(e.to_string()))?
+      .map_err(to_napi_error)?;
+    Ok(PrunedCache {
+      commits: pruned.commits as u32,
+      paths: pruned.paths as u32,
+    })
+  }
+
+  /// Every path the graph has seen so far, with its current name and whether it's still in HEAD.
+  #[napi]
+  pub async fn list_paths(&self) -> napi::Result<Vec<PathInfo>> {
+    let paths = self.inner.list_paths().map_err(to_napi_error)?;
+    Ok(
+      paths
+        .into_iter()
+        .map(|info| PathInfo {
+          id: info.id,
+          path: info.path.to_string(),
+          current_path: info.current_path.to_string(),
+          exists: info.exists,
+        })
+        .collect(),
+    )
+  }
+
+  /// How the cache resolves path `id`, for debugging candidates missing from results.
+  #[napi]
+  pub fn debug_resolve_path(&self, id: u32) -> napi::Result<ResolvedPath> {
+    let resolved = self.inner.debug_resolve_path(id).map_err(to_napi_error)?;
+    Ok(ResolvedPath {
+      id,
+      path: resolved.path.map(|p| p.to_string()),
+      current_id: resolved.current_id,
+      current_path: resolved.current_path.map(|p| p.to_string()),
+    })
+  }
+
+  /// How many paths the cache has interned, for debugging.
+  #[napi]
+  pub fn debug_intern_stats(&self) -> napi::Result<InternStats> {
+    let paths = self.inner.interned_path_count().map_err(to_napi_error)?;
+    Ok(InternStats { paths: paths as u32 })
+  }
+
+  /// The files the commit `sha` (or any revision like `HEAD~2`) changed, under their current names.
+  #[napi]
+  pub async fn commit_files(&self, sha: String) -> napi::Result<Vec<String>> {
+    let graph = self.inner.clone();
+    let paths = tokio::task::spawn_blocking(move || graph.commit_files(&sha))
+      .await
+      .map_err(|e| napi::Error::from_reason(e.to_string()))?
+      .map_err(to_napi_error)?;
+    Ok(paths.into_iter().map(|path| path.to_string()).collect())
+  }
+
+  /// Returns what `commitId` changed in the related file at `path`, or null if it didn't touch it.
+  #[napi]
+  pub async fn candidate_diff(&self, path: String, commit_id: String) -> napi::Result<Option<FileDiff>> {
+    let commit = gix::ObjectId::from_hex(commit_id.as_bytes()).map_err(|e| napi::Error::from_reason(e.to_string()))?;
+    let graph = self.inner.clone();
+    let diff = tokio::task::spawn_blocking(move || graph.candidate_diff(path.as_str().into(), commit))
+      .await
+      .map_err(|e| napi::Error::from_reason(e.to_string()))?
+      .map_err(to_napi_error)?;
+
+    Ok(diff.map(|diff| FileDiff {
+      old_path: diff.old_path.map(|p| p.to_string()),
+      new_path: diff.new_path.to_string(),
+      hunks: diff
+        .hunks
+        .into_iter()
+        .map(|h| DiffHunk {
+          old_start: h.old_start,
+          old_lines: h.old_lines,
+          new_start: h.new_start,
+          new_lines: h.new_lines,
+          lines: h.lines.into_iter().map(|l| l.to_string()).collect(),
+        })
+        .collect(),
+    }))
+  }
+
+  /// `openFile` as a blocking call. The blame keeps loading in the background after it returns.
+  #[napi]
+  pub fn open_file_sync(&self, path: String, revision: Option<String>) -> napi::Result<GitFile> {
     let path: BString = path.into();
+    let revision = revision
+      .map(|spec| self.inner.resolve_revision(&spec))
+      .transpose()
+      .map_err(to_napi_error)?;
+    let runtime = napi::bindgen_prelude::within_runtime_if_available(tokio::runtime::Handle::current);
     let blame = self
       .inner
-      .blame(path.as_bstr())
+      .blame_blocking(&runtime, path.as_bstr(), revision)
+      .map_err(to_napi_error)?;
+    Ok(GitFile::new(self.inner.clone(), blame.file_path.clone(), revision, blame))
+  }
+
+  /// Opens `path` for queries as of `revision` (any rev spec git understands), or HEAD. Resolves
+  /// without waiting for blame, so the file starts out `Loading`; see `ready()`. `path` is
+  /// relative to the repository root, like `./src/a.ts`, or absolute inside its work tree; one
+  /// leaving the repository throws `PathOutsideRepo`.
+  #[napi]
+  pub async fn open_file(&self, path: String, revision: Option<String>) -> napi::Result<GitFile> {
+    let path: BString = path.into();
+    let revision = revision
+      .map(|spec| self.inner.resolve_revision(&spec))
+      .transpose()
+      .map_err(to_napi_error)?;
+    let blame = self
+      .inner
+      .blame(path.as_bstr(), revision)
       .await
-      .map_err(|e| napi::Error::from_reason(e.to_string()))?;
-    Ok(GitFile {
-      graph: self.inner.clone(),
-      blame,
-    })
+      .map_err(to_napi_error)?;
+    Ok(GitFile::new(self.inner.clone(), blame.file_path.clone(), revision, blame))
+  }
+
+  /// Hints that `paths` are likely to be opened soon: starts blaming them at HEAD in the
+  /// background and resolves right away, so a later `openFile` finds the blame under way or
+  /// done. Files that can't be blamed are skipped; at most `blameCacheCapacity` are started.
+  #[napi]
+  pub async fn prefetch(&self, paths: Vec<String>) -> napi::Result<()> {
+    self
+      .inner
+      .prefetch(paths.into_iter().map(Into::into).collect())
+      .map_err(to_napi_error)
+  }
+
+  /// Related files for `lineno` of `path` without opening it, as of `revision` or HEAD. Unless
+  /// the file is open already, only `blameWindowLines` lines on either side of `lineno` are
+  /// blamed, far less work on a long file, and the query looks no further than those. Waits for
+  /// that blame.
+  #[napi]
+  pub async fn find_similar_files_at(
+    &self,
+    path: String,
+    lineno: u32,
+    revision: Option<String>,
+    options: Option<QueryOptions>,
+  ) -> napi::Result<Vec<Candidate>> {
+    let path: BString = path.into();
+    let revision = revision
+      .map(|spec| self.inner.resolve_revision(&spec))
+      .transpose()
+      .map_err(to_napi_error)?;
+    let options = options.map(Into::into).unwrap_or_default();
+    let related = self
+      .inner
+      .related_files_at(path.as_bstr(), revision, lineno as usize, &options)
+      .await
+      .map_err(to_napi_error)?;
+    Ok(to_js_candidates(related.candidates, &options))
+  }
+
+  /// Files related to a change as a whole, like a pull request's hunks, as of `revision` (the
+  /// commit the change is in) or HEAD. Each hunk is queried like `findSimilarFilesRange` on its
+  /// file; a file several hunks lead to has their weights summed, so the ranking is across the
+  /// whole change. Files the hunks are in are left out. Waits for the blames.
+  #[napi]
+  pub async fn related_files_for_hunks(
+    &self,
+    hunks: Vec<ChangedHunk>,
+    revision: Option<String>,
+    options: Option<QueryOptions>,
+  ) -> napi::Result<Vec<Candidate>> {
+    let revision = revision
+      .map(|spec| self.inner.resolve_revision(&spec))
+      .transpose()
+      .map_err(to_napi_error)?;
+    let hunks: Vec<(BString, std::ops::RangeInclusive<usize>)> = hunks
+      .into_iter()
+      .map(|hunk| {
+        let lines = hunk.start_line.min(hunk.end_line) as usize..=hunk.start_line.max(hunk.end_line) as usize;
+        (hunk.path.into(), lines)
+      })
+      .collect();
+    let options = options.map(Into::into).unwrap_or_default();
+    let related = self
+      .inner
+      .related_files_for_hunks(&hunks, revision, &options)
+      .await
+      .map_err(to_napi_error)?;
+    Ok(to_js_candidates(related.candidates, &options))
+  }
+
+  /// `openFile` for several files at once, like an editor's open tabs, all at `revision` or
+  /// HEAD. Their blames start together and files already open share theirs. A path that can't
+  /// be opened gets an `error` in its entry rather than failing the batch; the results are in
+  /// the order of `paths`.
+  #[napi]
+  pub async fn open_files(&self, paths: Vec<String>, revision: Option<String>) -> napi::Result<Vec<OpenedFile>> {
+    let paths: Vec<BString> = paths.into_iter().map(Into::into).collect();
+    let revision = revision
+      .map(|spec| self.inner.resolve_revision(&spec))
+      .transpose()
+      .map_err(to_napi_error)?;
+    let blames = self.inner.blame_all(&paths, revision).await.map_err(to_napi_error)?;
+    Ok(
+      paths
+        .into_iter()
+        .zip(blames)
+        .map(|(path, blame)| match blame {
+          Ok(blame) => OpenedFile {
+            path: path.to_string(),
+            file: Some(GitFile::new(self.inner.clone(), blame.file_path.clone(), revision, blame)),
+            error: None,
+          },
+          Err(e) => OpenedFile {
+            path: path.to_string(),
+            file: None,
+            error: Some(to_napi_error(e).reason),
+          },
+        })
+        .collect(),
+    )
   }
 }
@@ -0,0 +1,307 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Mutex, MutexGuard};
+
+use gix::bstr::{BStr, BString};
+use gix::ObjectId;
+
+use crate::cache::{Cache, CachedBlame, CachedCommit, CachedCommitMeta, PrunedCache};
+
+struct PathEntry {
+  path: BString,
+  renamed_to: Option<u32>,
+}
+
+#[derive(Default)]
+struct State {
+  /// Indexed by path id - 1, so ids start at 1 like sqlite rowids. `None` once pruned.
+  paths: Vec<Option<PathEntry>>,
+  path_ids: HashMap<BString, u32>,
+  commits: HashMap<ObjectId, CachedCommit>,
+  changed_lines: HashMap<(ObjectId, u32), Vec<Range<u32>>>,
+  commit_meta: HashMap<ObjectId, CachedCommitMeta>,
+  blames: HashMap<u32, (ObjectId, CachedBlame)>,
+}
+
+impl State {
+  fn intern(&mut self, path: &BStr) -> u32 {
+    if let Some(id) = self.path_ids.get(path) {
+      return *id;
+    }
+    self.paths.push(Some(PathEntry {
+      path: path.to_owned(),
+      renamed_to: None,
+    }));
+    let id = self.paths.len() as u32;
+    self.path_ids.insert(path.to_owned(), id);
+    id
+  }
+
+  fn entry(&self, path_id: u32) -> Option<&PathEntry> {
+    self.paths.get((path_id as usize).checked_sub(1)?)?.as_ref()
+  }
+}
+
+/// A `Cache` that lives only as long as the process, for tests and one-off queries that don't
+/// need sqlite. Renames resolve exactly like in `SqliteCache`.
+pub(crate) struct MemoryCache {
+  /// `None` once the cache is closed.
+  state: Mutex<Option<State>>,
+}
+
+impl MemoryCache {
+  pub(crate) fn new() -> Self {
+    MemoryCache {
+      state: Mutex::new(Some(State::default())),
+    }
+  }
+
+  fn state(&self) -> anyhow::Result<MutexGuard<'_, Option<State>>> {
+    let state = self.state.lock().unwrap();
+    anyhow::ensure!(state.is_some(), "the cache has been closed");
+    Ok(state)
+  }
+}
+
+impl Cache for MemoryCache {
+  fn cache_path(&self, path: &BStr) -> anyhow::Result<u32> {
+    let mut state = self.state()?;
+    Ok(state.as_mut().unwrap().intern(path))
+  }
+
+  fn cache_paths(&self, paths: &[&BStr]) -> anyhow::Result<Vec<u32>> {
+    let mut state = self.state()?;
+    let state = state.as_mut().unwrap();
+    Ok(paths.iter().map(|path| state.intern(path)).collect())
+  }
+
+  fn path_id(&self, path: &BStr) -> anyhow::Result<Option<u32>> {
+    let state = self.state()?;
+    Ok(state.as_ref().unwrap().path_ids.get(path).copied())
+  }
+
+  fn cache_rename(&self, old_path: &BStr, new_path: u32) -> anyhow::Result<()> {
+    let mut state = self.state()?;
+    let state = state.as_mut().unwrap();
+    let old_id = state.intern(old_path);
+
+    // same as SqliteCache: never close a loop in the chain
+    let mut visited = HashSet::new();
+    let mut next = Some(new_path);
+    while let Some(id) = next {
+      if id == old_id || !visited.insert(id) {
+        return Ok(());
+      }
+      next = state.entry(id).and_then(|entry| entry.renamed_to);
+    }
+
+    if let Some(entry) = &mut state.paths[old_id as usize - 1] {
+      entry.renamed_to = Some(new_path);
+    }
+    Ok(())
+  }
+
+  fn resolve_path(&self, path_id: u32) -> anyhow::Result<Option<BString>> {
+    let state = self.state()?;
+    let state = state.as_ref().unwrap();
+    let mut visited = HashSet::new();
+    let mut current = path_id;
+    while let Some(entry) = state.entry(current) {
+      match entry.renamed_to {
+        Some(next) if visited.insert(current) => current = next,
+        _ => return Ok(Some(entry.path.clone())),
+      }
+    }
+    Ok(None)
+  }
+
+  fn resolve_path_id(&self, path_id: u32) -> anyhow::Result<u32> {
+    let state = self.state()?;
+    let state = state.as_ref().unwrap();
+    let mut visited = HashSet::new();
+    let mut current = path_id;
+    while visited.insert(current) {
+      match state.entry(current).and_then(|entry| entry.renamed_to) {
+        Some(next) => current = next,
+        None => break,
+      }
+    }
+    Ok(current)
+  }
+
+  fn path(&self, path_id: u32) -> anyhow::Result<Option<BString>> {
+    let state = self.state()?;
+    Ok(state.as_ref().unwrap().entry(path_id).map(|entry| entry.path.clone()))
+  }
+
+  fn paths(&self) -> anyhow::Result<Vec<(u32, BString)>> {
+    let state = self.state()?;
+    let paths = &state.as_ref().unwrap().paths;
+    Ok(
+      paths
+        .iter()
+        .enumerate()
+        .filter_map(|(index, entry)| Some((index as u32 + 1, entry.as_ref()?.path.clone())))
+        .collect(),
+    )
+  }
+
+  fn cached_commit(&self, id: &ObjectId) -> anyhow::Result<Option<CachedCommit>> {
+    let state = self.state()?;
+    Ok(state.as_ref().unwrap().commits.get(id).cloned())
+  }
+
+  fn update_cached_commit(&self, id: &ObjectId, commit: CachedCommit) -> anyhow::Result<()> {
+    let mut state = self.state()?;
+    state.as_mut().unwrap().commits.entry(*id).or_insert(commit);
+    Ok(())
+  }
+
+  fn is_commit_cached(&self, id: &ObjectId) -> anyhow::Result<bool> {
+    let state = self.state()?;
+    Ok(state.as_ref().unwrap().commits.contains_key(id))
+  }
+
+  fn cached_commits(&self, ids: &[ObjectId]) -> anyhow::Result<HashSet<ObjectId>> {
+    let state = self.state()?;
+    let commits = &state.as_ref().unwrap().commits;
+    Ok(ids.iter().filter(|id| commits.contains_key(*id)).copied().collect())
+  }
+
+  fn update_changed_lines(&self, id: &ObjectId, path_id: u32, lines: &[Range<u32>]) -> anyhow::Result<()> {
+    let mut state = self.state()?;
+    state
+      .as_mut()
+      .unwrap()
+      .changed_lines
+      .entry((*id, path_id))
+      .or_insert_with(|| lines.to_vec());
+    Ok(())
+  }
+
+  fn changed_lines(&self, id: &ObjectId, path_id: u32) -> anyhow::Result<Option<Vec<Range<u32>>>> {
+    let state = self.state()?;
+    Ok(state.as_ref().unwrap().changed_lines.get(&(*id, path_id)).cloned())
+  }
+
+  fn update_commit_meta(&self, id: &ObjectId, meta: &CachedCommitMeta) -> anyhow::Result<()> {
+    let mut state = self.state()?;
+    state.as_mut().unwrap().commit_meta.entry(*id).or_insert_with(|| meta.clone());
+    Ok(())
+  }
+
+  fn commit_meta(&self, id: &ObjectId) -> anyhow::Result<Option<CachedCommitMeta>> {
+    let state = self.state()?;
+    Ok(state.as_ref().unwrap().commit_meta.get(id).cloned())
+  }
+
+  fn commit_metas(&self) -> anyhow::Result<Vec<(ObjectId, CachedCommitMeta)>> {
+    let state = self.state()?;
+    let metas = &state.as_ref().unwrap().commit_meta;
+    Ok(metas.iter().map(|(id, meta)| (*id, meta.clone())).collect())
+  }
+
+  fn update_blame(&self, path_id: u32, id: &ObjectId, blame: &CachedBlame) -> anyhow::Result<()> {
+    let mut state = self.state()?;
+    state.as_mut().unwrap().blames.insert(path_id, (*id, blame.clone()));
+    Ok(())
+  }
+
+  fn blame(&self, path_id: u32, id: &ObjectId) -> anyhow::Result<Option<CachedBlame>> {
+    let state = self.state()?;
+    let stored = state.as_ref().unwrap().blames.get(&path_id);
+    Ok(stored.filter(|(blamed_at, _)| blamed_at == id).map(|(_, blame)| blame.clone()))
+  }
+
+  fn prune(&self, keep: &HashSet<ObjectId>) -> anyhow::Result<PrunedCache> {
+    let mut state = self.state()?;
+    let state = state.as_mut().unwrap();
+    let cached = state.commits.len();
+    state.commits.retain(|id, _| keep.contains(id));
+    state.changed_lines.retain(|(id, _), _| keep.contains(id));
+    state.commit_meta.retain(|id, _| keep.contains(id));
+
+    let mut referenced: HashSet<u32> = state.commits.values().flat_map(|commit| commit.changed_paths.iter().copied()).collect();
+    referenced.extend(state.changed_lines.keys().map(|(_, path_id)| *path_id));
+    referenced.extend(state.blames.keys().copied());
+    let mut pending: Vec<u32> = referenced.iter().copied().collect();
+    while let Some(path_id) = pending.pop() {
+      if let Some(next) = state.entry(path_id).and_then(|entry| entry.renamed_to) {
+        if referenced.insert(next) {
+          pending.push(next);
+        }
+      }
+    }
+
+    // ids stay positions in `paths`, so dropped ones are left as holes and never reused
+    let mut paths = 0;
+    for (index, slot) in state.paths.iter_mut().enumerate() {
+      if slot.is_some() && !referenced.contains(&(index as u32 + 1)) {
+        state.path_ids.remove(&slot.take().unwrap().path);
+        paths += 1;
+      }
+    }
+    Ok(PrunedCache {
+      commits: cached - state.commits.len(),
+      paths,
+    })
+  }
+
+  fn close(&self) -> anyhow::Result<()> {
+    self.state.lock().unwrap().take();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::sqlite::SqliteCache;
+
+  #[test]
+  fn test_renames_resolve_like_sqlite() -> anyhow::Result<()> {
+    let memory = MemoryCache::new();
+    let sqlite = SqliteCache::new()?;
+    for cache in [&memory as &dyn Cache, &sqlite] {
+      let b = cache.cache_path("b.rs".into())?;
+      let c = cache.cache_path("c.rs".into())?;
+      cache.cache_rename("a.rs".into(), b)?;
+      cache.cache_rename("b.rs".into(), c)?;
+      // would close a loop
+      let a = cache.cache_path("a.rs".into())?;
+      cache.cache_rename("c.rs".into(), a)?;
+      cache.cache_path("plain.rs".into())?;
+    }
+
+    for id in 0..=6 {
+      assert_eq!(memory.path(id)?, sqlite.path(id)?, "id {}", id);
+      assert_eq!(memory.resolve_path(id)?, sqlite.resolve_path(id)?, "id {}", id);
+      assert_eq!(memory.resolve_path_id(id)?, sqlite.resolve_path_id(id)?, "id {}", id);
+    }
+    let ids: Vec<u32> = (0..=6).collect();
+    assert_eq!(memory.resolve_paths(&ids)?, sqlite.resolve_paths(&ids)?);
+    assert_eq!(memory.paths()?, sqlite.paths()?);
+    assert_eq!(memory.resolve_path(memory.cache_path("a.rs".into())?)?, Some("c.rs".into()));
+    Ok(())
+  }
+
+  #[test]
+  fn test_commits_and_lines_round_trip() -> anyhow::Result<()> {
+    let cache = MemoryCache::new();
+    let id = ObjectId::null(gix::hash::Kind::Sha1);
+    assert!(!cache.is_commit_cached(&id)?);
+    cache.update_changed_lines(&id, 1, &[1..3, 7..8])?;
+    cache.update_cached_commit(&id, CachedCommit {
+      changed_paths: vec![1, 2],
+    })?;
+
+    assert!(cache.is_commit_cached(&id)?);
+    assert_eq!(cache.cached_commit(&id)?.unwrap().changed_paths, vec![1, 2]);
+    assert_eq!(cache.changed_lines(&id, 1)?, Some(vec![1..3, 7..8]));
+    assert_eq!(cache.changed_lines(&id, 2)?, None);
+
+    cache.close()?;
+    assert!(cache.is_commit_cached(&id).is_err());
+    Ok(())
+  }
+}
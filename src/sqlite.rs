@@ -1,135 +1,1010 @@
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
 
 use gix::bstr::{BStr, BString};
 use gix::ObjectId;
 use integer_encoding::{VarIntReader, VarIntWriter};
 use rusqlite::OptionalExtension;
 
-use crate::cache::{Cache, CachedCommit};
+use crate::blame::BlameEntry;
+use crate::cache::{Cache, CachedBlame, CachedCommit, CachedCommitMeta, PrunedCache};
 
 pub(crate) struct SqliteCache {
-  conn: Mutex<rusqlite::Connection>,
+  /// `None` once the cache is closed.
+  conn: Mutex<Option<rusqlite::Connection>>,
+}
+
+/// A locked, still open connection.
+struct Conn<'a>(MutexGuard<'a, Option<rusqlite::Connection>>);
+
+impl Deref for Conn<'_> {
+  type Target = rusqlite::Connection;
+
+  fn deref(&self) -> &rusqlite::Connection {
+    self.0.as_ref().unwrap()
+  }
 }
 
 impl SqliteCache {
   pub(crate) fn new() -> anyhow::Result<Self> {
-    let conn = rusqlite::Connection::open_in_memory()?;
+    Self::with_connection(rusqlite::Connection::open_in_memory()?)
+  }
+
+  /// A cache kept in the database file at `path`, created if missing, so it outlives the
+  /// process. Fails if the file was made for a repository hashing objects with another kind
+  /// than `object_hash`, whose ids would never match.
+  pub(crate) fn open(path: &Path, object_hash: gix::hash::Kind) -> anyhow::Result<Self> {
+    let cache = Self::with_connection(rusqlite::Connection::open(path)?)?;
+    cache.check_object_hash(object_hash)?;
+    Ok(cache)
+  }
+
+  fn with_connection(conn: rusqlite::Connection) -> anyhow::Result<Self> {
+    // WAL only syncs at checkpoints, and with it NORMAL can lose the last writes on power loss
+    // but never corrupts the file; fine for a cache. In-memory databases ignore both.
+    conn.query_row("PRAGMA journal_mode=WAL", (), |_| Ok(()))?;
+    conn.execute("PRAGMA synchronous=NORMAL", ())?;
     let cache = SqliteCache {
-      conn: Mutex::new(conn),
+      conn: Mutex::new(Some(conn)),
     };
     cache.create_tables()?;
     Ok(cache)
   }
 
-  fn create_tables(&self) -> rusqlite::Result<()> {
+  /// Records `object_hash` in a fresh cache, or checks it against the one recorded.
+  fn check_object_hash(&self, object_hash: gix::hash::Kind) -> anyhow::Result<()> {
+    let conn = self.conn()?;
+    conn.execute(
+      "INSERT INTO meta(key, value) VALUES ('object_hash', ?) ON CONFLICT(key) DO NOTHING",
+      [object_hash.to_string()],
+    )?;
+    let recorded: String = conn.query_row("SELECT value FROM meta WHERE key = 'object_hash'", (), |row| row.get(0))?;
+    anyhow::ensure!(
+      recorded == object_hash.to_string(),
+      "the cache was built for a repository using {} object ids, this one uses {}",
+      recorded,
+      object_hash
+    );
+    Ok(())
+  }
+
+  fn conn(&self) -> anyhow::Result<Conn<'_>> {
     let conn = self.conn.lock().unwrap();
+    anyhow::ensure!(conn.is_some(), "the cache has been closed");
+    Ok(Conn(conn))
+  }
+
+  fn create_tables(&self) -> anyhow::Result<()> {
+    let conn = self.conn()?;
+    conn.execute("CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL)", ())?;
     conn.execute(
-      "CREATE TABLE paths (id INTEGER PRIMARY KEY, path BLOB NOT NULL, renamed_to INTEGER)",
+      "CREATE TABLE IF NOT EXISTS paths (id INTEGER PRIMARY KEY, path BLOB NOT NULL, renamed_to INTEGER)",
       (),
     )?;
-    conn.execute("CREATE UNIQUE INDEX paths_by_path ON paths(path)", ())?;
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS paths_by_path ON paths(path)", ())?;
 
+    // ids are stored as their raw digest, 20 or 32 bytes by hash kind, without any prefix
+    conn.execute(
+      "CREATE TABLE IF NOT EXISTS commits (sha BLOB PRIMARY KEY, changes BLOB)",
+      (),
+    )?;
+    conn.execute(
+      "CREATE TABLE IF NOT EXISTS commit_hunks (sha BLOB NOT NULL, path INTEGER NOT NULL, lines BLOB, PRIMARY KEY(sha, path))",
+      (),
+    )?;
+    // nullable, and a commit may have no row at all if it was cached before this table existed
+    conn.execute(
+      "CREATE TABLE IF NOT EXISTS commit_meta (sha BLOB PRIMARY KEY, committed_at INTEGER, author_name BLOB, author_email BLOB, summary BLOB)",
+      (),
+    )?;
+    // one blame per path, of the commit it was last stored at
     conn.execute(
-      "CREATE TABLE commits (sha BLOB PRIMARY KEY, changes BLOB)",
+      "CREATE TABLE IF NOT EXISTS blames (path INTEGER PRIMARY KEY, sha BLOB NOT NULL, options INTEGER NOT NULL, entries BLOB NOT NULL)",
       (),
     )?;
     Ok(())
   }
 }
 
+/// How many ids go into one `IN (...)` query, well below sqlite's limit on bound parameters.
+const PARAMETER_BATCH: usize = 500;
+
+/// Leads every `changes` blob written since path ids are delta-encoded, followed by
+/// `CHANGES_VERSION`. Blobs from before are bare varints of the ids, and since ids start at 1
+/// they never begin with a zero byte.
+const CHANGES_MARKER: u8 = 0;
+const CHANGES_VERSION: u8 = 1;
+
+/// Sorted path ids as the marker, the version, the first id and then the gaps between
+/// consecutive ids, all varints. Ids of a commit often sit close together, so the gaps mostly
+/// fit in a byte.
+fn encode_changed_paths(changed_paths: &[u32]) -> anyhow::Result<Vec<u8>> {
+  debug_assert!(changed_paths.windows(2).all(|pair| pair[0] < pair[1]));
+  let mut serialized = vec![CHANGES_MARKER, CHANGES_VERSION];
+  let mut previous = 0;
+  for &path_id in changed_paths {
+    serialized.write_varint(path_id - previous)?;
+    previous = path_id;
+  }
+  Ok(serialized)
+}
+
+fn decode_changed_paths(serialized: &[u8]) -> Vec<u32> {
+  let mut changed_paths = Vec::new();
+  match serialized {
+    [CHANGES_MARKER, CHANGES_VERSION, deltas @ ..] => {
+      let mut cursor = std::io::Cursor::new(deltas);
+      let mut previous = 0;
+      while let Ok(delta) = cursor.read_varint::<u32>() {
+        previous += delta;
+        changed_paths.push(previous);
+      }
+    }
+    _ => {
+      let mut cursor = std::io::Cursor::new(serialized);
+      while let Ok(path_id) = cursor.read_varint() {
+        changed_paths.push(path_id);
+      }
+    }
+  }
+  changed_paths
+}
+
+/// Leads every `entries` blob of the `blames` table.
+const BLAME_VERSION: u8 = 1;
+
+/// A blame's chunks as the version, the distinct commits and original paths each as its length
+/// and bytes, then per chunk its start and length in the blamed file, the same in the original
+/// file, and the indices of its commit and path, all varints. A file's chunks share a handful of
+/// commits and usually one path, so those are written once.
+fn encode_blame(entries: &[BlameEntry]) -> anyhow::Result<Vec<u8>> {
+  let mut commits: Vec<&ObjectId> = Vec::new();
+  let mut paths: Vec<&BString> = Vec::new();
+  let mut indices = Vec::with_capacity(entries.len());
+  for entry in entries {
+    let commit = match commits.iter().position(|commit| **commit == entry.commit_id) {
+      Some(index) => index,
+      None => {
+        commits.push(&entry.commit_id);
+        commits.len() - 1
+      }
+    };
+    let path = match paths.iter().position(|path| **path == entry.original_path) {
+      Some(index) => index,
+      None => {
+        paths.push(&entry.original_path);
+        paths.len() - 1
+      }
+    };
+    indices.push((commit, path));
+  }
+
+  let mut serialized = vec![BLAME_VERSION];
+  serialized.write_varint(commits.len())?;
+  for commit in commits {
+    serialized.write_varint(commit.as_slice().len())?;
+    serialized.extend_from_slice(commit.as_slice());
+  }
+  serialized.write_varint(paths.len())?;
+  for path in paths {
+    serialized.write_varint(path.len())?;
+    serialized.extend_from_slice(path);
+  }
+  for (entry, (commit, path)) in entries.iter().zip(indices) {
+    let blamed = &entry.range_in_blamed_file;
+    let original = &entry.range_in_original_file;
+    serialized.write_varint(blamed.start)?;
+    serialized.write_varint(blamed.end - blamed.start)?;
+    serialized.write_varint(original.start)?;
+    serialized.write_varint(original.end - original.start)?;
+    serialized.write_varint(commit)?;
+    serialized.write_varint(path)?;
+  }
+  Ok(serialized)
+}
+
+fn decode_blame(serialized: &[u8]) -> anyhow::Result<Vec<BlameEntry>> {
+  let [BLAME_VERSION, rest @ ..] = serialized else {
+    anyhow::bail!("unknown blame format");
+  };
+  let mut cursor = std::io::Cursor::new(rest);
+  let read_bytes = |cursor: &mut std::io::Cursor<&[u8]>| -> anyhow::Result<Vec<u8>> {
+    let len: usize = cursor.read_varint()?;
+    let mut bytes = vec![0; len];
+    std::io::Read::read_exact(cursor, &mut bytes)?;
+    Ok(bytes)
+  };
+  let commit_count: usize = cursor.read_varint()?;
+  let mut commits = Vec::with_capacity(commit_count);
+  for _ in 0..commit_count {
+    commits.push(ObjectId::try_from(read_bytes(&mut cursor)?.as_slice())?);
+  }
+  let path_count: usize = cursor.read_varint()?;
+  let mut paths = Vec::with_capacity(path_count);
+  for _ in 0..path_count {
+    paths.push(BString::new(read_bytes(&mut cursor)?));
+  }
+
+  let mut entries = Vec::new();
+  while (cursor.position() as usize) < rest.len() {
+    let blamed_start: u32 = cursor.read_varint()?;
+    let blamed_len: u32 = cursor.read_varint()?;
+    let original_start: u32 = cursor.read_varint()?;
+    let original_len: u32 = cursor.read_varint()?;
+    let commit: usize = cursor.read_varint()?;
+    let path: usize = cursor.read_varint()?;
+    entries.push(BlameEntry {
+      range_in_blamed_file: blamed_start..blamed_start + blamed_len,
+      range_in_original_file: original_start..original_start + original_len,
+      commit_id: *commits.get(commit).ok_or_else(|| anyhow::anyhow!("no commit {} in a stored blame", commit))?,
+      original_path: paths.get(path).ok_or_else(|| anyhow::anyhow!("no path {} in a stored blame", path))?.clone(),
+    });
+  }
+  Ok(entries)
+}
+
 impl Cache for SqliteCache {
   fn cache_path(&self, path: &BStr) -> anyhow::Result<u32> {
     let path: &[u8] = path.into();
-    let conn = self.conn.lock().unwrap();
-    let changed = conn.execute(
-      "INSERT OR IGNORE INTO paths (path) VALUES (?)",
-      rusqlite::params![path],
-    )?;
+    let conn = self.conn()?;
+    let changed = conn
+      .prepare_cached("INSERT OR IGNORE INTO paths (path) VALUES (?)")?
+      .execute(rusqlite::params![path])?;
 
     if changed > 0 {
       Ok(conn.last_insert_rowid() as u32)
     } else {
-      let existing_id = conn.query_row(
-        "SELECT id FROM paths WHERE path = ?",
-        rusqlite::params![path],
-        |row| row.get(0),
-      )?;
+      let existing_id = conn
+        .prepare_cached("SELECT id FROM paths WHERE path = ?")?
+        .query_row(rusqlite::params![path], |row| row.get(0))?;
       Ok(existing_id)
     }
   }
 
+  fn path_id(&self, path: &BStr) -> anyhow::Result<Option<u32>> {
+    let path: &[u8] = path.into();
+    Ok(
+      self
+        .conn()?
+        .prepare_cached("SELECT id FROM paths WHERE path = ?")?
+        .query_row(rusqlite::params![path], |row| row.get(0))
+        .optional()?,
+    )
+  }
+
+  fn cache_paths(&self, paths: &[&BStr]) -> anyhow::Result<Vec<u32>> {
+    let conn = self.conn()?;
+    // the lock already keeps anyone else off the connection while the transaction runs
+    let tx = conn.unchecked_transaction()?;
+    let mut ids = Vec::with_capacity(paths.len());
+    {
+      let mut insert = tx.prepare_cached("INSERT OR IGNORE INTO paths (path) VALUES (?)")?;
+      let mut select = tx.prepare_cached("SELECT id FROM paths WHERE path = ?")?;
+      for path in paths {
+        let path: &[u8] = path.as_ref();
+        let id = if insert.execute(rusqlite::params![path])? > 0 {
+          tx.last_insert_rowid() as u32
+        } else {
+          select.query_row(rusqlite::params![path], |row| row.get(0))?
+        };
+        ids.push(id);
+      }
+    }
+    tx.commit()?;
+    Ok(ids)
+  }
+
   fn cache_rename(&self, old_path: &BStr, new_path: u32) -> anyhow::Result<()> {
-    // TODO: come up with a efficient way to store this
+    let old_path: &[u8] = old_path.into();
+    let conn = self.conn()?;
+    conn.execute(
+      "INSERT OR IGNORE INTO paths (path) VALUES (?)",
+      rusqlite::params![old_path],
+    )?;
+    let old_id: u32 = conn.query_row(
+      "SELECT id FROM paths WHERE path = ?",
+      rusqlite::params![old_path],
+      |row| row.get(0),
+    )?;
+
+    // a file renamed back and forth would otherwise close a loop in the chain; keep whichever
+    // direction we saw first rather than making resolve_path spin forever
+    let mut visited = HashSet::new();
+    let mut next = Some(new_path);
+    while let Some(id) = next {
+      if id == old_id || !visited.insert(id) {
+        return Ok(());
+      }
+      next = conn
+        .query_row(
+          "SELECT renamed_to FROM paths WHERE id = ?",
+          rusqlite::params![id],
+          |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+    }
+
+    conn.execute(
+      "UPDATE paths SET renamed_to = ? WHERE id = ?",
+      rusqlite::params![new_path, old_id],
+    )?;
     Ok(())
   }
 
   fn resolve_path(&self, path_id: u32) -> anyhow::Result<Option<BString>> {
-    let conn = self.conn.lock().unwrap();
-    let row = conn
+    let conn = self.conn()?;
+    let mut stmt = conn.prepare_cached("SELECT path, renamed_to FROM paths WHERE id = ?")?;
+    // cache_rename never closes a loop, but a damaged cache file might have one; stop where it
+    // comes back around like resolve_paths does
+    let mut visited = HashSet::new();
+    let mut current = path_id;
+    loop {
+      let row: Option<(Vec<u8>, Option<u32>)> = stmt
+        .query_row(rusqlite::params![current], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+      match row {
+        Some((_, Some(next))) if visited.insert(current) => current = next,
+        Some((path, _)) => return Ok(Some(BString::new(path))),
+        None => return Ok(None),
+      }
+    }
+  }
+
+  fn resolve_paths(&self, path_ids: &[u32]) -> anyhow::Result<HashMap<u32, BString>> {
+    let conn = self.conn()?;
+    let mut rows: HashMap<u32, (Vec<u8>, Option<u32>)> = HashMap::new();
+    let mut queried: HashSet<u32> = HashSet::new();
+    let mut wanted: Vec<u32> = path_ids.iter().copied().filter(|id| queried.insert(*id)).collect();
+    // fetch the ids, then whatever they were renamed to, until the chains end
+    while !wanted.is_empty() {
+      for batch in wanted.chunks(PARAMETER_BATCH) {
+        let placeholders = vec!["?"; batch.len()].join(", ");
+        let mut stmt = conn.prepare(&format!(
+          "SELECT id, path, renamed_to FROM paths WHERE id IN ({})",
+          placeholders
+        ))?;
+        let mut query = stmt.query(rusqlite::params_from_iter(batch))?;
+        while let Some(row) = query.next()? {
+          rows.insert(row.get(0)?, (row.get(1)?, row.get(2)?));
+        }
+      }
+      wanted = rows
+        .values()
+        .filter_map(|(_, renamed_to)| *renamed_to)
+        .filter(|id| queried.insert(*id))
+        .collect();
+    }
+
+    let mut paths = HashMap::new();
+    for &path_id in path_ids {
+      let mut visited = HashSet::new();
+      let mut current = path_id;
+      while let Some((path, renamed_to)) = rows.get(&current) {
+        match renamed_to {
+          Some(next) if visited.insert(current) => current = *next,
+          _ => {
+            paths.insert(path_id, BString::from(path.as_slice()));
+            break;
+          }
+        }
+      }
+    }
+    Ok(paths)
+  }
+
+  fn resolve_path_id(&self, path_id: u32) -> anyhow::Result<u32> {
+    let conn = self.conn()?;
+    let mut visited = HashSet::new();
+    let mut current = path_id;
+    while visited.insert(current) {
+      let renamed_to: Option<u32> = conn
+        .query_row(
+          "SELECT renamed_to FROM paths WHERE id = ?",
+          rusqlite::params![current],
+          |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+      match renamed_to {
+        Some(next) => current = next,
+        None => break,
+      }
+    }
+    Ok(current)
+  }
+
+  fn path(&self, path_id: u32) -> anyhow::Result<Option<BString>> {
+    let conn = self.conn()?;
+    let path: Option<Vec<u8>> = conn
       .query_row(
-        "SELECT path, renamed_to FROM paths WHERE id = ?",
+        "SELECT path FROM paths WHERE id = ?",
         rusqlite::params![path_id],
-        |row| {
-          let path: Vec<u8> = row.get(0)?;
-          let renamed_to: Option<u32> = row.get(1)?;
-          Ok((path, renamed_to))
-        },
+        |row| row.get(0),
       )
       .optional()?;
+    Ok(path.map(BString::new))
+  }
 
-    match row {
-      Some((path, renamed_to)) => {
-        if let Some(renamed_to) = renamed_to {
-          self.resolve_path(renamed_to)
-        } else {
-          Ok(Some(BString::new(path)))
-        }
-      }
-      None => Ok(None),
-    }
+  fn paths(&self) -> anyhow::Result<Vec<(u32, BString)>> {
+    let conn = self.conn()?;
+    let mut stmt = conn.prepare("SELECT id, path FROM paths ORDER BY id")?;
+    let rows = stmt.query_map((), |row| {
+      let path: Vec<u8> = row.get(1)?;
+      Ok((row.get(0)?, BString::new(path)))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
   }
 
   fn cached_commit(&self, id: &ObjectId) -> anyhow::Result<Option<CachedCommit>> {
-    let conn = self.conn.lock().unwrap();
-    let row: Option<Vec<u8>> = conn.query_row(
-      "SELECT changes FROM commits WHERE sha = ?",
-      rusqlite::params![id.as_slice()],
-      |row| Ok(row.get(0)?),
-    ).optional()?;
+    let conn = self.conn()?;
+    let row: Option<Vec<u8>> = conn
+      .prepare_cached("SELECT changes FROM commits WHERE sha = ?")?
+      .query_row(rusqlite::params![id.as_slice()], |row| row.get(0))
+      .optional()?;
 
-    Ok(row.map(|serialized_changes| {
-      let mut changed_paths = Vec::new();
-      let mut cursor = std::io::Cursor::new(serialized_changes);
-      while let Ok(p) = cursor.read_varint() {
-        changed_paths.push(p);
-      }
-      CachedCommit {
-        changed_paths,
-      }
+    Ok(row.map(|serialized_changes| CachedCommit {
+      changed_paths: decode_changed_paths(&serialized_changes),
     }))
   }
 
   fn update_cached_commit(&self, id: &ObjectId, commit: CachedCommit) -> anyhow::Result<()> {
-    let mut serialized_bitmap = Vec::new();
-    for p in commit.changed_paths.iter() {
-      serialized_bitmap.write_varint(*p)?;
+    let serialized_changes = encode_changed_paths(&commit.changed_paths)?;
+    let conn = self.conn()?;
+    conn
+      .prepare_cached("INSERT INTO commits(sha, changes) VALUES (?, ?) ON CONFLICT(sha) DO NOTHING;")?
+      .execute(rusqlite::params![id.as_slice(), &serialized_changes])?;
+    Ok(())
+  }
+
+  fn is_commit_cached(&self, id: &ObjectId) -> anyhow::Result<bool> {
+    let conn = self.conn()?;
+    let row: Option<()> = conn
+      .prepare_cached("SELECT 1 FROM commits WHERE sha = ?")?
+      .query_row(rusqlite::params![id.as_slice()], |_| Ok(()))
+      .optional()?;
+    Ok(row.is_some())
+  }
+
+  fn cached_commits(&self, ids: &[ObjectId]) -> anyhow::Result<HashSet<ObjectId>> {
+    let conn = self.conn()?;
+    let mut cached = HashSet::new();
+    for batch in ids.chunks(PARAMETER_BATCH) {
+      let placeholders = vec!["?"; batch.len()].join(", ");
+      let mut stmt = conn.prepare_cached(&format!("SELECT sha FROM commits WHERE sha IN ({})", placeholders))?;
+      let mut rows = stmt.query(rusqlite::params_from_iter(batch.iter().map(|id| id.as_slice())))?;
+      while let Some(row) = rows.next()? {
+        let sha: Vec<u8> = row.get(0)?;
+        cached.insert(ObjectId::try_from(sha.as_slice())?);
+      }
     }
+    Ok(cached)
+  }
 
-    let conn = self.conn.lock().unwrap();
+  fn update_changed_lines(&self, id: &ObjectId, path_id: u32, lines: &[Range<u32>]) -> anyhow::Result<()> {
+    let mut serialized_lines = Vec::new();
+    for range in lines {
+      serialized_lines.write_varint(range.start)?;
+      serialized_lines.write_varint(range.end - range.start)?;
+    }
+
+    let conn = self.conn()?;
     conn.execute(
-      "INSERT INTO commits(sha, changes) VALUES (?, ?) ON CONFLICT(sha) DO NOTHING;",
-      rusqlite::params![id.as_slice(), &serialized_bitmap],
+      "INSERT INTO commit_hunks(sha, path, lines) VALUES (?, ?, ?) ON CONFLICT(sha, path) DO NOTHING;",
+      rusqlite::params![id.as_slice(), path_id, &serialized_lines],
     )?;
     Ok(())
   }
 
-  fn is_commit_cached(&self, id: &ObjectId) -> anyhow::Result<bool> {
-    let conn = self.conn.lock().unwrap();
-    let row: Option<()> = conn.query_row(
-      "SELECT 1 FROM commits WHERE sha = ?",
-      rusqlite::params![id.as_slice()],
-      |_| Ok(()),
-    ).optional()?;
-    Ok(row.is_some())
+  fn changed_lines(&self, id: &ObjectId, path_id: u32) -> anyhow::Result<Option<Vec<Range<u32>>>> {
+    let conn = self.conn()?;
+    let row: Option<Vec<u8>> = conn
+      .query_row(
+        "SELECT lines FROM commit_hunks WHERE sha = ? AND path = ?",
+        rusqlite::params![id.as_slice(), path_id],
+        |row| row.get(0),
+      )
+      .optional()?;
+
+    Ok(row.map(|serialized_lines| {
+      let mut lines = Vec::new();
+      let mut cursor = std::io::Cursor::new(serialized_lines);
+      while let (Ok(start), Ok(len)) = (cursor.read_varint::<u32>(), cursor.read_varint::<u32>()) {
+        lines.push(start..start + len);
+      }
+      lines
+    }))
+  }
+
+  fn update_commit_meta(&self, id: &ObjectId, meta: &CachedCommitMeta) -> anyhow::Result<()> {
+    let conn = self.conn()?;
+    conn
+      .prepare_cached(
+        "INSERT INTO commit_meta(sha, committed_at, author_name, author_email, summary) VALUES (?, ?, ?, ?, ?) ON CONFLICT(sha) DO NOTHING;",
+      )?
+      .execute(rusqlite::params![
+        id.as_slice(),
+        meta.committed_at,
+        meta.author_name.as_slice(),
+        meta.author_email.as_slice(),
+        meta.summary.as_slice()
+      ])?;
+    Ok(())
+  }
+
+  fn commit_meta(&self, id: &ObjectId) -> anyhow::Result<Option<CachedCommitMeta>> {
+    let conn = self.conn()?;
+    type Row = (Option<i64>, Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>);
+    let row: Option<Row> = conn
+      .prepare_cached("SELECT committed_at, author_name, author_email, summary FROM commit_meta WHERE sha = ?")?
+      .query_row(rusqlite::params![id.as_slice()], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+      })
+      .optional()?;
+
+    // a row missing any column counts as no metadata
+    Ok(match row {
+      Some((Some(committed_at), Some(author_name), Some(author_email), Some(summary))) => Some(CachedCommitMeta {
+        author_name: author_name.into(),
+        author_email: author_email.into(),
+        committed_at,
+        summary: summary.into(),
+      }),
+      _ => None,
+    })
+  }
+
+  fn commit_metas(&self) -> anyhow::Result<Vec<(ObjectId, CachedCommitMeta)>> {
+    let conn = self.conn()?;
+    let mut stmt = conn.prepare(
+      "SELECT sha, committed_at, author_name, author_email, summary FROM commit_meta WHERE committed_at IS NOT NULL AND author_name IS NOT NULL AND author_email IS NOT NULL AND summary IS NOT NULL",
+    )?;
+    let rows = stmt.query_map((), |row| {
+      Ok((
+        row.get::<_, Vec<u8>>(0)?,
+        CachedCommitMeta {
+          committed_at: row.get(1)?,
+          author_name: row.get::<_, Vec<u8>>(2)?.into(),
+          author_email: row.get::<_, Vec<u8>>(3)?.into(),
+          summary: row.get::<_, Vec<u8>>(4)?.into(),
+        },
+      ))
+    })?;
+    let mut metas = Vec::new();
+    for row in rows {
+      let (sha, meta) = row?;
+      metas.push((ObjectId::try_from(sha.as_slice())?, meta));
+    }
+    Ok(metas)
+  }
+
+  fn update_blame(&self, path_id: u32, id: &ObjectId, blame: &CachedBlame) -> anyhow::Result<()> {
+    let serialized_entries = encode_blame(&blame.entries)?;
+    let conn = self.conn()?;
+    conn
+      .prepare_cached("INSERT OR REPLACE INTO blames(path, sha, options, entries) VALUES (?, ?, ?, ?)")?
+      .execute(rusqlite::params![path_id, id.as_slice(), blame.options as i64, &serialized_entries])?;
+    Ok(())
+  }
+
+  fn blame(&self, path_id: u32, id: &ObjectId) -> anyhow::Result<Option<CachedBlame>> {
+    let conn = self.conn()?;
+    let row: Option<(i64, Vec<u8>)> = conn
+      .prepare_cached("SELECT options, entries FROM blames WHERE path = ? AND sha = ?")?
+      .query_row(rusqlite::params![path_id, id.as_slice()], |row| Ok((row.get(0)?, row.get(1)?)))
+      .optional()?;
+    let Some((options, serialized_entries)) = row else {
+      return Ok(None);
+    };
+    Ok(Some(CachedBlame {
+      options: options as u64,
+      entries: decode_blame(&serialized_entries)?,
+    }))
+  }
+
+  fn prune(&self, keep: &HashSet<ObjectId>) -> anyhow::Result<PrunedCache> {
+    let conn = self.conn()?;
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("CREATE TEMP TABLE kept_commits (sha BLOB PRIMARY KEY)", ())?;
+    {
+      let mut insert = tx.prepare("INSERT OR IGNORE INTO kept_commits (sha) VALUES (?)")?;
+      for id in keep {
+        insert.execute(rusqlite::params![id.as_slice()])?;
+      }
+    }
+    let commits = tx.execute("DELETE FROM commits WHERE sha NOT IN (SELECT sha FROM kept_commits)", ())?;
+    tx.execute("DELETE FROM commit_hunks WHERE sha NOT IN (SELECT sha FROM kept_commits)", ())?;
+    tx.execute("DELETE FROM commit_meta WHERE sha NOT IN (SELECT sha FROM kept_commits)", ())?;
+    tx.execute("DROP TABLE kept_commits", ())?;
+
+    let mut referenced: HashSet<u32> = HashSet::new();
+    {
+      let mut stmt = tx.prepare("SELECT changes FROM commits")?;
+      let mut rows = stmt.query(())?;
+      while let Some(row) = rows.next()? {
+        let changes: Option<Vec<u8>> = row.get(0)?;
+        referenced.extend(decode_changed_paths(&changes.unwrap_or_default()));
+      }
+      for table in ["commit_hunks", "blames"] {
+        let mut stmt = tx.prepare(&format!("SELECT path FROM {}", table))?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+          referenced.insert(row.get(0)?);
+        }
+      }
+    }
+    // and where those were renamed to, which is how they resolve
+    let mut renamed_to: HashMap<u32, u32> = HashMap::new();
+    let mut all_ids: Vec<u32> = Vec::new();
+    {
+      let mut stmt = tx.prepare("SELECT id, renamed_to FROM paths")?;
+      let mut rows = stmt.query(())?;
+      while let Some(row) = rows.next()? {
+        let id: u32 = row.get(0)?;
+        if let Some(next) = row.get::<_, Option<u32>>(1)? {
+          renamed_to.insert(id, next);
+        }
+        all_ids.push(id);
+      }
+    }
+    let mut pending: Vec<u32> = referenced.iter().copied().collect();
+    while let Some(id) = pending.pop() {
+      if let Some(&next) = renamed_to.get(&id) {
+        if referenced.insert(next) {
+          pending.push(next);
+        }
+      }
+    }
+    let unreferenced: Vec<u32> = all_ids.into_iter().filter(|id| !referenced.contains(id)).collect();
+    for batch in unreferenced.chunks(PARAMETER_BATCH) {
+      let placeholders = vec!["?"; batch.len()].join(", ");
+      tx.execute(
+        &format!("DELETE FROM paths WHERE id IN ({})", placeholders),
+        rusqlite::params_from_iter(batch),
+      )?;
+    }
+    tx.commit()?;
+
+    // outside the transaction, which VACUUM can't run in
+    conn.execute("VACUUM", ())?;
+    Ok(PrunedCache {
+      commits,
+      paths: unreferenced.len(),
+    })
+  }
+
+  fn close(&self) -> anyhow::Result<()> {
+    if let Some(conn) = self.conn.lock().unwrap().take() {
+      conn.close().map_err(|(_, e)| e)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_path_id_looks_up_without_interning() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let a = cache.cache_path("a.rs".into())?;
+    assert_eq!(cache.path_id("a.rs".into())?, Some(a));
+    assert_eq!(cache.path_id("b.rs".into())?, None);
+    assert_eq!(cache.paths()?, vec![(a, "a.rs".into())]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_rename_chain_resolves_to_latest_path() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let b = cache.cache_path("b.rs".into())?;
+    let c = cache.cache_path("c.rs".into())?;
+    cache.cache_rename("a.rs".into(), b)?;
+    cache.cache_rename("b.rs".into(), c)?;
+
+    let a = cache.cache_path("a.rs".into())?;
+    assert_eq!(cache.resolve_path(a)?, Some("c.rs".into()));
+    assert_eq!(cache.resolve_path(b)?, Some("c.rs".into()));
+    assert_eq!(cache.resolve_path(c)?, Some("c.rs".into()));
+    Ok(())
+  }
+
+  #[test]
+  fn test_resolve_paths_matches_resolve_path() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let c = cache.cache_path("c.rs".into())?;
+    let b = cache.cache_path("b.rs".into())?;
+    cache.cache_rename("a.rs".into(), b)?;
+    cache.cache_rename("b.rs".into(), c)?;
+    let a = cache.cache_path("a.rs".into())?;
+    let plain = cache.cache_path("plain.rs".into())?;
+    let missing = 999;
+
+    let ids = [a, b, c, plain, missing, a];
+    let bulk = cache.resolve_paths(&ids)?;
+    for id in ids {
+      assert_eq!(bulk.get(&id).cloned(), cache.resolve_path(id)?, "id {}", id);
+    }
+    assert_eq!(bulk[&a], "c.rs");
+    assert_eq!(bulk.len(), 4);
+    Ok(())
+  }
+
+  #[test]
+  fn test_cache_paths_matches_cache_path() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let existing = cache.cache_path("existing.rs".into())?;
+    let paths: Vec<&BStr> = vec!["new.rs".into(), "existing.rs".into(), "other.rs".into(), "new.rs".into()];
+    let ids = cache.cache_paths(&paths)?;
+
+    assert_eq!(ids[1], existing);
+    assert_eq!(ids[0], ids[3]);
+    for (path, id) in paths.iter().zip(&ids) {
+      assert_eq!(cache.cache_path(path)?, *id);
+    }
+    assert_eq!(cache.paths()?.len(), 3);
+    Ok(())
+  }
+
+  #[test]
+  fn test_on_disk_cache_uses_wal_and_persists() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("cache.db");
+    let id = ObjectId::null(gix::hash::Kind::Sha1);
+    {
+      let cache = SqliteCache::open(&path, gix::hash::Kind::Sha1)?;
+      let journal_mode: String = cache.conn()?.query_row("PRAGMA journal_mode", (), |row| row.get(0))?;
+      assert_eq!(journal_mode, "wal");
+      let path_id = cache.cache_path("a.rs".into())?;
+      cache.update_cached_commit(&id, CachedCommit {
+        changed_paths: vec![path_id],
+      })?;
+      cache.close()?;
+    }
+
+    let cache = SqliteCache::open(&path, gix::hash::Kind::Sha1)?;
+    assert_eq!(cache.cached_commit(&id)?.unwrap().changed_paths, vec![cache.cache_path("a.rs".into())?]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_object_ids_round_trip_in_full() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let id = ObjectId::from_hex(b"0123456789abcdef0123456789abcdef01234567")?;
+    cache.update_cached_commit(&id, CachedCommit { changed_paths: vec![1] })?;
+    let stored: usize = cache.conn()?.query_row("SELECT length(sha) FROM commits", (), |row| row.get(0))?;
+    assert_eq!(stored, id.kind().len_in_bytes());
+    assert_eq!(cache.cached_commits(&[id])?, HashSet::from([id]));
+    Ok(())
+  }
+
+  #[test]
+  fn test_commit_meta_round_trips_and_partial_rows_read_as_none() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let id = ObjectId::from_hex(b"0123456789abcdef0123456789abcdef01234567")?;
+    assert_eq!(cache.commit_meta(&id)?, None);
+    let meta = CachedCommitMeta {
+      author_name: "Ann".into(),
+      author_email: "ann@example.com".into(),
+      committed_at: 1_700_000_000,
+      summary: "fix the parser".into(),
+    };
+    cache.update_commit_meta(&id, &meta)?;
+    assert_eq!(cache.commit_meta(&id)?, Some(meta));
+
+    let partial = ObjectId::from_hex(b"89abcdef0123456789abcdef0123456789abcdef")?;
+    cache.conn()?.execute(
+      "INSERT INTO commit_meta(sha, committed_at) VALUES (?, 1700000000)",
+      rusqlite::params![partial.as_slice()],
+    )?;
+    assert_eq!(cache.commit_meta(&partial)?, None);
+    Ok(())
+  }
+
+  #[test]
+  fn test_cache_file_rejects_other_hash_kind() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("cache.db");
+    SqliteCache::open(&path, gix::hash::Kind::Sha1)?.close()?;
+    SqliteCache::open(&path, gix::hash::Kind::Sha1)?.close()?;
+
+    // as if a SHA-256 repository had made it
+    let cache = SqliteCache::open(&path, gix::hash::Kind::Sha1)?;
+    cache.conn()?.execute("UPDATE meta SET value = 'SHA256' WHERE key = 'object_hash'", ())?;
+    cache.close()?;
+    let Err(err) = SqliteCache::open(&path, gix::hash::Kind::Sha1) else {
+      panic!("opened a cache made for another hash kind");
+    };
+    assert!(err.to_string().contains("SHA256 object ids, this one uses SHA1"), "{}", err);
+    Ok(())
+  }
+
+  /// Caching commits into a file with WAL against sqlite's default rollback journal, which
+  /// syncs on every write. Only reports the timings, which depend on the disk; run it by hand
+  /// with `cargo test -- --ignored --nocapture`.
+  #[test]
+  #[ignore]
+  fn bench_wal_against_rollback_journal() -> anyhow::Result<()> {
+    const COMMITS: u32 = 500;
+    let dir = tempfile::tempdir()?;
+    let mut timings = Vec::new();
+    for wal in [false, true] {
+      let cache = SqliteCache::open(&dir.path().join(format!("cache-{}.db", wal)), gix::hash::Kind::Sha1)?;
+      if !wal {
+        let conn = cache.conn()?;
+        conn.query_row("PRAGMA journal_mode=DELETE", (), |_| Ok(()))?;
+        conn.execute("PRAGMA synchronous=FULL", ())?;
+      }
+
+      let start = std::time::Instant::now();
+      for i in 0..COMMITS {
+        let mut sha = [0u8; 20];
+        sha[..4].copy_from_slice(&i.to_be_bytes());
+        let id = ObjectId::from_bytes_or_panic(&sha);
+        let paths: Vec<BString> = (0..10).map(|p| format!("dir/{}/file{}.rs", i % 7, p).into()).collect();
+        let paths: Vec<&BStr> = paths.iter().map(|p| p.as_ref()).collect();
+        let changed_paths = cache.cache_paths(&paths)?;
+        assert!(!cache.is_commit_cached(&id)?);
+        cache.update_cached_commit(&id, CachedCommit { changed_paths })?;
+      }
+      timings.push(start.elapsed());
+    }
+    println!("{} commits: rollback journal {:?}, WAL {:?}", COMMITS, timings[0], timings[1]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_changed_paths_encoding() -> anyhow::Result<()> {
+    let changed_paths: Vec<u32> = (0..100).map(|i| 20_000 + i * 3).collect();
+    let encoded = encode_changed_paths(&changed_paths)?;
+    assert_eq!(decode_changed_paths(&encoded), changed_paths);
+    assert_eq!(decode_changed_paths(&encode_changed_paths(&[])?), Vec::<u32>::new());
+
+    // plain varints as written before the format was versioned
+    let mut legacy = Vec::new();
+    for path_id in &changed_paths {
+      legacy.write_varint(*path_id)?;
+    }
+    assert_eq!(decode_changed_paths(&legacy), changed_paths);
+    assert_eq!(decode_changed_paths(&[]), Vec::<u32>::new());
+    assert!(encoded.len() * 2 < legacy.len(), "{} vs {}", encoded.len(), legacy.len());
+    Ok(())
+  }
+
+  #[test]
+  fn test_cached_commits_matches_is_commit_cached() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    // more than one batch of parameters
+    let ids: Vec<ObjectId> = (0..1200u32)
+      .map(|i| {
+        let mut sha = [0u8; 20];
+        sha[..4].copy_from_slice(&i.to_be_bytes());
+        ObjectId::from_bytes_or_panic(&sha)
+      })
+      .collect();
+    for id in ids.iter().step_by(3) {
+      cache.update_cached_commit(id, CachedCommit {
+        changed_paths: vec![],
+      })?;
+    }
+
+    let cached = cache.cached_commits(&ids)?;
+    assert_eq!(cached.len(), 400);
+    for id in &ids {
+      assert_eq!(cached.contains(id), cache.is_commit_cached(id)?);
+    }
+    assert!(cache.cached_commits(&[])?.is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn test_rename_cycle_is_not_recorded() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let a = cache.cache_path("a.rs".into())?;
+    let b = cache.cache_path("b.rs".into())?;
+    cache.cache_rename("a.rs".into(), b)?;
+    cache.cache_rename("b.rs".into(), a)?;
+
+    assert_eq!(cache.resolve_path(a)?, Some("b.rs".into()));
+    assert_eq!(cache.resolve_path(b)?, Some("b.rs".into()));
+    Ok(())
+  }
+
+  #[test]
+  fn test_resolving_a_rename_cycle_terminates() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let a = cache.cache_path("a.rs".into())?;
+    let b = cache.cache_path("b.rs".into())?;
+    // written behind cache_rename's back, as a damaged cache file could have it
+    let conn = cache.conn()?;
+    conn.execute("UPDATE paths SET renamed_to = ? WHERE id = ?", rusqlite::params![b, a])?;
+    conn.execute("UPDATE paths SET renamed_to = ? WHERE id = ?", rusqlite::params![a, b])?;
+    drop(conn);
+
+    assert_eq!(cache.resolve_path(a)?, Some("a.rs".into()));
+    assert_eq!(cache.resolve_path(b)?, Some("b.rs".into()));
+    assert_eq!(cache.resolve_paths(&[a, b])?, HashMap::from([(a, "a.rs".into()), (b, "b.rs".into())]));
+    assert_eq!(cache.resolve_path_id(a)?, a);
+    Ok(())
+  }
+
+  #[test]
+  fn test_stored_blame_round_trips_and_is_replaced_at_another_commit() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let path_id = cache.cache_path("src.rs".into())?;
+    let first = ObjectId::from_hex(b"0123456789abcdef0123456789abcdef01234567")?;
+    let second = ObjectId::from_hex(b"89abcdef0123456789abcdef0123456789abcdef")?;
+    let entry = |lines: Range<u32>, commit: ObjectId, path: &str| BlameEntry {
+      range_in_blamed_file: lines.clone(),
+      range_in_original_file: lines.start + 2..lines.end + 2,
+      commit_id: commit,
+      original_path: path.into(),
+    };
+    let blame = CachedBlame {
+      options: u64::MAX,
+      entries: vec![
+        entry(1..3, first, "old.rs"),
+        entry(3..4, second, "src.rs"),
+        entry(4..9, first, "old.rs"),
+      ],
+    };
+    assert_eq!(cache.blame(path_id, &first)?, None);
+    cache.update_blame(path_id, &first, &blame)?;
+    assert_eq!(cache.blame(path_id, &first)?, Some(blame.clone()));
+    assert_eq!(cache.blame(path_id, &second)?, None);
+
+    let empty = CachedBlame {
+      options: 0,
+      entries: vec![],
+    };
+    cache.update_blame(path_id, &second, &empty)?;
+    assert_eq!(cache.blame(path_id, &second)?, Some(empty));
+    assert_eq!(cache.blame(path_id, &first)?, None);
+    Ok(())
+  }
+
+  #[test]
+  fn test_prune_keeps_paths_kept_commits_and_blames_refer_to() -> anyhow::Result<()> {
+    let cache = SqliteCache::new()?;
+    let kept = ObjectId::from_hex(b"0123456789abcdef0123456789abcdef01234567")?;
+    let dropped = ObjectId::from_hex(b"89abcdef0123456789abcdef0123456789abcdef")?;
+    let b = cache.cache_path("b.rs".into())?;
+    cache.cache_rename("a.rs".into(), b)?;
+    let a = cache.cache_path("a.rs".into())?;
+    let blamed = cache.cache_path("blamed.rs".into())?;
+    let gone = cache.cache_path("gone.rs".into())?;
+    let hunks_only = cache.cache_path("hunks.rs".into())?;
+    cache.update_cached_commit(&kept, CachedCommit { changed_paths: vec![a] })?;
+    cache.update_cached_commit(&dropped, CachedCommit {
+      changed_paths: vec![a, gone],
+    })?;
+    cache.update_changed_lines(&dropped, hunks_only, &[1..3, 5..6])?;
+    let meta = CachedCommitMeta {
+      author_name: "Ann".into(),
+      author_email: "ann@example.com".into(),
+      committed_at: 1_700_000_000,
+      summary: "drop me".into(),
+    };
+    cache.update_commit_meta(&dropped, &meta)?;
+    let blame = CachedBlame {
+      options: 0,
+      entries: vec![],
+    };
+    cache.update_blame(blamed, &dropped, &blame)?;
+
+    let pruned = cache.prune(&HashSet::from([kept]))?;
+    assert_eq!(pruned, PrunedCache { commits: 1, paths: 2 });
+    assert_eq!(cache.cached_commits(&[kept, dropped])?, HashSet::from([kept]));
+    assert_eq!(cache.changed_lines(&dropped, hunks_only)?, None);
+    assert_eq!(cache.commit_meta(&dropped)?, None);
+    assert_eq!(cache.blame(blamed, &dropped)?, Some(blame));
+    assert_eq!(cache.resolve_path(a)?, Some("b.rs".into()));
+    let paths: Vec<BString> = cache.paths()?.into_iter().map(|(_, path)| path).collect();
+    assert_eq!(paths, vec![BString::from("b.rs"), "a.rs".into(), "blamed.rs".into()]);
+    assert_eq!(cache.prune(&HashSet::from([kept]))?, PrunedCache::default());
+    Ok(())
   }
 }
@@ -0,0 +1,94 @@
+use std::path::Path;
+use std::process::Command;
+
+use gix::ObjectId;
+
+use crate::config::{CacheBackend, GraphConfig};
+use crate::gitgraph::LocalGitGraph;
+
+/// A throwaway git repository in a temp dir, driven through the `git` binary like the blame
+/// backend is.
+pub(crate) struct TestRepo {
+  dir: tempfile::TempDir,
+}
+
+impl TestRepo {
+  pub(crate) fn new() -> Self {
+    Self::with_object_format("sha1")
+  }
+
+  /// A repository hashing objects with `format`, `sha1` or `sha256`.
+  pub(crate) fn with_object_format(format: &str) -> Self {
+    let repo = TestRepo {
+      dir: tempfile::tempdir().unwrap(),
+    };
+    repo.git(&["init", "-q", "-b", "main", &format!("--object-format={}", format)]);
+    repo.git(&["config", "user.name", "Test"]);
+    repo.git(&["config", "user.email", "test@example.com"]);
+    repo.git(&["config", "commit.gpgsign", "false"]);
+    repo
+  }
+
+  pub(crate) fn path(&self) -> &Path {
+    self.dir.path()
+  }
+
+  pub(crate) fn write(&self, path: &str, contents: impl AsRef<[u8]>) {
+    let path = self.path().join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+  }
+
+  /// Runs git in the repository, panicking on failure, and returns trimmed stdout.
+  pub(crate) fn git(&self, args: &[&str]) -> String {
+    let output = Command::new("git")
+      .arg("-C")
+      .arg(self.path())
+      .args(args)
+      .output()
+      .unwrap();
+    assert!(
+      output.status.success(),
+      "git {:?} failed: {}",
+      args,
+      String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+  }
+
+  /// Stages everything and commits it, returning the new commit id.
+  pub(crate) fn commit(&self, message: &str) -> ObjectId {
+    self.git(&["add", "-A"]);
+    self.git(&["commit", "-q", "--allow-empty", "-m", message]);
+    ObjectId::from_hex(self.git(&["rev-parse", "HEAD"]).as_bytes()).unwrap()
+  }
+
+  /// Like `commit`, with author and committer dated `timestamp` seconds since the epoch.
+  pub(crate) fn commit_at(&self, message: &str, timestamp: i64) -> ObjectId {
+    self.git(&["add", "-A"]);
+    let date = format!("@{} +0000", timestamp);
+    let output = Command::new("git")
+      .arg("-C")
+      .arg(self.path())
+      .args(["commit", "-q", "--allow-empty", "-m", message])
+      .env("GIT_AUTHOR_DATE", &date)
+      .env("GIT_COMMITTER_DATE", &date)
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "git commit failed: {}", String::from_utf8_lossy(&output.stderr));
+    ObjectId::from_hex(self.git(&["rev-parse", "HEAD"]).as_bytes()).unwrap()
+  }
+
+  pub(crate) fn graph(&self) -> LocalGitGraph {
+    self.graph_with(GraphConfig::default())
+  }
+
+  /// A graph over the repository, cached in memory so graph tests don't depend on sqlite.
+  pub(crate) fn graph_with(&self, config: GraphConfig) -> LocalGitGraph {
+    let config = GraphConfig {
+      cache_backend: CacheBackend::Memory,
+      ..config
+    };
+    LocalGitGraph::new(self.path().to_str().unwrap(), config).unwrap()
+  }
+}
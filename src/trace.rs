@@ -0,0 +1,131 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Timing spans recorded during one query, exported in the Chrome `trace_event` format that
+/// `chrome://tracing` and Perfetto load.
+#[derive(Debug)]
+pub(crate) struct QueryTrace {
+  origin: Instant,
+  spans: Mutex<Vec<Span>>,
+  next_lane: AtomicU32,
+}
+
+#[derive(Debug)]
+struct Span {
+  category: &'static str,
+  name: String,
+  lane: u32,
+  start: Duration,
+  duration: Duration,
+}
+
+/// Records its span when dropped.
+pub(crate) struct SpanGuard {
+  trace: Arc<QueryTrace>,
+  category: &'static str,
+  name: String,
+  lane: u32,
+  start: Instant,
+}
+
+impl Drop for SpanGuard {
+  fn drop(&mut self) {
+    let span = Span {
+      category: self.category,
+      name: std::mem::take(&mut self.name),
+      lane: self.lane,
+      start: self.start - self.trace.origin,
+      duration: self.start.elapsed(),
+    };
+    self.trace.spans.lock().unwrap().push(span);
+  }
+}
+
+impl QueryTrace {
+  pub(crate) fn new() -> Self {
+    QueryTrace {
+      origin: Instant::now(),
+      spans: Mutex::new(Vec::new()),
+      next_lane: AtomicU32::new(1),
+    }
+  }
+
+  /// A fresh lane for work running alongside the query, like one candidate's resolution, so its
+  /// spans don't overlap the query's own on lane 0.
+  pub(crate) fn lane(&self) -> u32 {
+    self.next_lane.fetch_add(1, Ordering::Relaxed)
+  }
+
+  /// Starts a span on `lane` that lasts until the guard is dropped.
+  pub(crate) fn span(self: &Arc<Self>, category: &'static str, name: impl Into<String>, lane: u32) -> SpanGuard {
+    SpanGuard {
+      trace: self.clone(),
+      category,
+      name: name.into(),
+      lane,
+      start: Instant::now(),
+    }
+  }
+
+  /// The finished spans as a trace file, lanes mapped to threads. Spans still open are left out.
+  pub(crate) fn to_chrome_json(&self) -> String {
+    let spans = self.spans.lock().unwrap();
+    let mut json = String::from("{\"traceEvents\":[");
+    for (i, span) in spans.iter().enumerate() {
+      if i > 0 {
+        json.push(',');
+      }
+      let _ = write!(
+        json,
+        "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":1,\"tid\":{}}}",
+        escape(&span.name),
+        span.category,
+        span.start.as_secs_f64() * 1e6,
+        span.duration.as_secs_f64() * 1e6,
+        span.lane,
+      );
+    }
+    json.push_str("],\"displayTimeUnit\":\"ms\"}");
+    json
+  }
+}
+
+/// `s` as the inside of a JSON string.
+fn escape(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      c if (c as u32) < 0x20 => {
+        let _ = write!(escaped, "\\u{:04x}", c as u32);
+      }
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_spans_export_as_complete_events() {
+    let trace = Arc::new(QueryTrace::new());
+    {
+      let _outer = trace.span("query", "rank", 0);
+      let lane = trace.lane();
+      let _inner = trace.span("locate", "locate \"odd\\name\".rs\n", lane);
+    }
+    let json: serde_json::Value = serde_json::from_str(&trace.to_chrome_json()).unwrap();
+    let events = json["traceEvents"].as_array().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["name"], "locate \"odd\\name\".rs\n");
+    assert_eq!(events[0]["tid"], 1);
+    assert_eq!(events[1]["name"], "rank");
+    assert!(events.iter().all(|e| e["ph"] == "X" && e["dur"].as_f64().unwrap() >= 0.0));
+  }
+}